@@ -0,0 +1,59 @@
+//! A step of a scripted tutorial: put a keyboard in front of the user, wait for them to point it at
+//! a text field, then type into it.
+
+use color_eyre::eyre::Result;
+use stardust_xr_fusion::client::{Client, FrameInfo, RootHandler};
+use stardust_xr_manifold::{
+	ConnectionEvent, Emitter, EmitterConfig, Keyboard, KeymapConfig, Placement, RESOURCE_DIR,
+};
+use std::time::Duration;
+use xkbcommon::xkb;
+
+struct Tutorial {
+	keyboard: Emitter<Keyboard>,
+}
+impl RootHandler for Tutorial {
+	fn frame(&mut self, info: FrameInfo) {
+		self.keyboard.frame(info);
+	}
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+	color_eyre::install()?;
+	let (client, event_loop) = Client::connect_with_async_loop().await?;
+	client.set_base_prefixes(&[RESOURCE_DIR]);
+
+	let config = EmitterConfig::default();
+	let keyboard = Emitter::new(client.get_root(), &config, Placement::default(), |parent| {
+		Keyboard::create(parent, &config)
+	})?;
+	let handler = keyboard.contained.clone();
+	let keymap = KeymapConfig {
+		layout: "us".into(),
+		..Default::default()
+	}
+	.compile()?;
+	handler
+		.lock()
+		.set_keymap_string(&keymap.get_as_string(xkb::KEYMAP_FORMAT_TEXT_V1))?;
+	let mut connections = handler.lock().subscribe();
+	let _tutorial = client.wrap_root(Tutorial { keyboard })?;
+
+	println!("Point the keyboard at a text field");
+	while let Ok(event) = connections.recv().await {
+		let ConnectionEvent::Connected(uid) = event else {
+			continue;
+		};
+		let mut keyboard = handler.lock();
+		keyboard.set_focus(Some(uid));
+		keyboard.type_text("hello from the tutorial!\n");
+		break;
+	}
+
+	// give the messenger a chance to flush the keys
+	tokio::time::sleep(Duration::from_millis(100)).await;
+	client.stop_loop();
+	event_loop.await??;
+	Ok(())
+}
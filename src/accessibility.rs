@@ -0,0 +1,341 @@
+//! Sticky, slow and bounce keys, filtering keys before they reach the remapping like the desktop's
+//! AccessX does, configured by [`AccessibilityConfig`].
+
+use crate::{binding::EVDEV_OFFSET, config::AccessibilityConfig, keymap::Xkb, remap::KeyEvent};
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::time::{Duration, Instant};
+use xkbcommon::xkb::{self, Keymap, State};
+
+/// What the filter is holding on to, for showing on the keyboard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterState {
+	/// A key is down but hasn't been held long enough for slow keys yet.
+	Pending,
+	/// A modifier was tapped and stays down for the next key.
+	Latched,
+	/// A modifier was tapped twice and stays down until it's tapped again.
+	Locked,
+}
+
+/// Where a modifier is at with sticky keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Sticky {
+	/// Held down, `used` once another key was pressed along with it.
+	Held {
+		used: bool,
+	},
+	Latched,
+	/// Pressed again while latched, locks when released.
+	Locking,
+	Locked,
+	/// Pressed again while locked, comes up when released.
+	Unlocking,
+}
+
+#[derive(Default)]
+pub struct KeyFilter {
+	keymap: Xkb<Option<Keymap>>,
+	sticky_keys: bool,
+	slow_keys: Option<Duration>,
+	bounce_keys: Option<Duration>,
+	/// Keys that were let through and are still down.
+	down: FxHashSet<u32>,
+	/// Keys down for less than `slow_keys`, and since when.
+	slow: FxHashMap<u32, Instant>,
+	/// Keys whose press was thrown away, so their release is too.
+	dropped: FxHashSet<u32>,
+	/// When each key was last released, for bounce keys.
+	released: FxHashMap<u32, Instant>,
+	sticky: FxHashMap<u32, Sticky>,
+}
+impl KeyFilter {
+	pub fn new(config: &AccessibilityConfig) -> Self {
+		KeyFilter {
+			sticky_keys: config.sticky_keys,
+			slow_keys: config.slow_keys.map(Duration::from_secs_f64),
+			bounce_keys: config.bounce_keys.map(Duration::from_secs_f64),
+			..Default::default()
+		}
+	}
+
+	/// Use this keymap to tell which keys are modifiers for sticky keys.
+	pub(crate) fn set_keymap(&mut self, keymap: Keymap) {
+		self.keymap = Xkb::new(Some(keymap));
+	}
+
+	/// Feed a key through the filters, returning the key events to pass on in order.
+	pub fn key(&mut self, scancode: u32, pressed: bool, now: Instant) -> Vec<KeyEvent> {
+		let mut events = Vec::new();
+		if pressed {
+			if self.down.contains(&scancode)
+				|| self.slow.contains_key(&scancode)
+				|| self.dropped.contains(&scancode)
+			{
+				// auto-repeat from the desktop
+				return events;
+			}
+			if self
+				.bounce_keys
+				.zip(self.released.get(&scancode))
+				.is_some_and(|(bounce, released)| now < *released + bounce)
+			{
+				self.dropped.insert(scancode);
+			} else if self.slow_keys.is_some() {
+				self.slow.insert(scancode, now);
+			} else {
+				self.down.insert(scancode);
+				self.sticky(scancode, true, &mut events);
+			}
+		} else if self.dropped.remove(&scancode) || self.slow.remove(&scancode).is_some() {
+			// never got pressed as far as anyone else knows
+		} else {
+			if self.bounce_keys.is_some() {
+				self.released.insert(scancode, now);
+			}
+			self.down.remove(&scancode);
+			self.sticky(scancode, false, &mut events);
+		}
+		events
+	}
+
+	/// Let through the keys that have now been held long enough for slow keys.
+	pub fn tick(&mut self, now: Instant) -> Vec<KeyEvent> {
+		let mut events = Vec::new();
+		let Some(slow_keys) = self.slow_keys else {
+			return events;
+		};
+		let mut accepted: Vec<(u32, Instant)> = self
+			.slow
+			.iter()
+			.filter(|(_, since)| now >= **since + slow_keys)
+			.map(|(key, since)| (*key, *since))
+			.collect();
+		accepted.sort_by_key(|(_, since)| *since);
+		for (key, _) in accepted {
+			self.slow.remove(&key);
+			self.down.insert(key);
+			self.sticky(key, true, &mut events);
+		}
+		events
+	}
+
+	/// Forget everything that's held, when whatever comes after the filter releases it all anyway.
+	pub fn reset(&mut self) {
+		self.down.clear();
+		self.slow.clear();
+		self.dropped.clear();
+		self.sticky.clear();
+	}
+
+	/// Release every key that was let through and is still down, latched or locked.
+	pub fn release_all(&mut self) -> Vec<KeyEvent> {
+		let mut keys: Vec<u32> = self
+			.down
+			.iter()
+			.chain(self.sticky.keys())
+			.copied()
+			.collect();
+		keys.sort_unstable();
+		keys.dedup();
+		self.reset();
+		keys.into_iter().map(|key| (key, false)).collect()
+	}
+
+	/// The most important thing being held on to, if anything.
+	pub fn state(&self) -> Option<FilterState> {
+		if !self.slow.is_empty() {
+			Some(FilterState::Pending)
+		} else if self
+			.sticky
+			.values()
+			.any(|sticky| *sticky == Sticky::Latched)
+		{
+			Some(FilterState::Latched)
+		} else if self
+			.sticky
+			.values()
+			.any(|sticky| matches!(sticky, Sticky::Locking | Sticky::Locked | Sticky::Unlocking))
+		{
+			Some(FilterState::Locked)
+		} else {
+			None
+		}
+	}
+
+	fn sticky(&mut self, scancode: u32, pressed: bool, events: &mut Vec<KeyEvent>) {
+		if !self.sticky_keys || !self.is_modifier(scancode) {
+			events.push((scancode, pressed));
+			if pressed {
+				for sticky in self.sticky.values_mut() {
+					if let Sticky::Held { used } = sticky {
+						*used = true;
+					}
+				}
+			} else {
+				self.sticky.retain(|key, sticky| {
+					let latched = *sticky == Sticky::Latched;
+					if latched {
+						events.push((*key, false));
+					}
+					!latched
+				});
+			}
+			return;
+		}
+
+		let sticky = self.sticky.remove(&scancode);
+		let next = match (sticky, pressed) {
+			(Some(Sticky::Latched), true) => Some(Sticky::Locking),
+			(Some(Sticky::Locked), true) => Some(Sticky::Unlocking),
+			(_, true) => {
+				events.push((scancode, true));
+				Some(Sticky::Held { used: false })
+			}
+			(Some(Sticky::Held { used: false }), false) => Some(Sticky::Latched),
+			(Some(Sticky::Locking), false) => Some(Sticky::Locked),
+			(Some(Sticky::Latched | Sticky::Locked), false) => sticky,
+			_ => {
+				events.push((scancode, false));
+				None
+			}
+		};
+		if let Some(next) = next {
+			self.sticky.insert(scancode, next);
+		}
+	}
+
+	/// Whether pressing the key sets a modifier in the keymap, leaving out lock keys like Caps Lock.
+	fn is_modifier(&self, scancode: u32) -> bool {
+		let Some(keymap) = &*self.keymap else {
+			return false;
+		};
+		let mut state = State::new(keymap);
+		let changed = state.update_key(scancode + EVDEV_OFFSET, xkb::KeyDirection::Down);
+		changed & xkb::STATE_MODS_DEPRESSED != 0 && changed & xkb::STATE_MODS_LOCKED == 0
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::keymap::tests::us_keymap;
+	use input_event_codes::{KEY_A, KEY_LEFTSHIFT};
+
+	fn filter(config: AccessibilityConfig) -> KeyFilter {
+		let mut filter = KeyFilter::new(&config);
+		filter.set_keymap(us_keymap());
+		filter
+	}
+	fn sticky_keys() -> KeyFilter {
+		filter(AccessibilityConfig {
+			sticky_keys: true,
+			..Default::default()
+		})
+	}
+	/// Press and release a key, returning everything that came out.
+	fn tap(filter: &mut KeyFilter, key: u32, now: Instant) -> Vec<KeyEvent> {
+		let mut events = filter.key(key, true, now);
+		events.extend(filter.key(key, false, now));
+		events
+	}
+
+	#[test]
+	fn tapped_modifiers_latch_for_the_next_key() {
+		let mut filter = sticky_keys();
+		let now = Instant::now();
+		assert_eq!(
+			tap(&mut filter, KEY_LEFTSHIFT!(), now),
+			[(KEY_LEFTSHIFT!(), true)]
+		);
+		assert_eq!(filter.state(), Some(FilterState::Latched));
+		assert_eq!(
+			tap(&mut filter, KEY_A!(), now),
+			[
+				(KEY_A!(), true),
+				(KEY_A!(), false),
+				(KEY_LEFTSHIFT!(), false)
+			]
+		);
+		assert_eq!(filter.state(), None);
+	}
+
+	#[test]
+	fn tapping_twice_locks_until_tapped_again() {
+		let mut filter = sticky_keys();
+		let now = Instant::now();
+		tap(&mut filter, KEY_LEFTSHIFT!(), now);
+		assert_eq!(tap(&mut filter, KEY_LEFTSHIFT!(), now), []);
+		assert_eq!(filter.state(), Some(FilterState::Locked));
+		assert_eq!(
+			tap(&mut filter, KEY_A!(), now),
+			[(KEY_A!(), true), (KEY_A!(), false)]
+		);
+		assert_eq!(
+			tap(&mut filter, KEY_LEFTSHIFT!(), now),
+			[(KEY_LEFTSHIFT!(), false)]
+		);
+		assert_eq!(filter.state(), None);
+	}
+
+	#[test]
+	fn modifiers_used_while_held_come_up_normally() {
+		let mut filter = sticky_keys();
+		let now = Instant::now();
+		filter.key(KEY_LEFTSHIFT!(), true, now);
+		tap(&mut filter, KEY_A!(), now);
+		assert_eq!(
+			filter.key(KEY_LEFTSHIFT!(), false, now),
+			[(KEY_LEFTSHIFT!(), false)]
+		);
+		assert_eq!(filter.state(), None);
+	}
+
+	#[test]
+	fn slow_keys_wait_before_pressing() {
+		let slow = Duration::from_millis(300);
+		let mut filter = filter(AccessibilityConfig {
+			slow_keys: Some(slow.as_secs_f64()),
+			..Default::default()
+		});
+		let now = Instant::now();
+		// let go too soon, never pressed
+		assert_eq!(tap(&mut filter, KEY_A!(), now), []);
+		assert_eq!(filter.key(KEY_A!(), true, now), []);
+		assert_eq!(filter.state(), Some(FilterState::Pending));
+		assert_eq!(filter.tick(now + slow / 2), []);
+		assert_eq!(filter.tick(now + slow), [(KEY_A!(), true)]);
+		assert_eq!(filter.key(KEY_A!(), false, now + slow), [(KEY_A!(), false)]);
+	}
+
+	#[test]
+	fn bounce_keys_ignore_quick_presses_again() {
+		let bounce = Duration::from_millis(300);
+		let mut filter = filter(AccessibilityConfig {
+			bounce_keys: Some(bounce.as_secs_f64()),
+			..Default::default()
+		});
+		let now = Instant::now();
+		assert_eq!(
+			tap(&mut filter, KEY_A!(), now),
+			[(KEY_A!(), true), (KEY_A!(), false)]
+		);
+		assert_eq!(tap(&mut filter, KEY_A!(), now + bounce / 2), []);
+		assert_eq!(
+			tap(&mut filter, KEY_A!(), now + bounce),
+			[(KEY_A!(), true), (KEY_A!(), false)]
+		);
+	}
+
+	#[test]
+	fn release_all_lets_go_of_held_and_latched_keys() {
+		let mut filter = sticky_keys();
+		let now = Instant::now();
+		tap(&mut filter, KEY_LEFTSHIFT!(), now);
+		filter.key(KEY_A!(), true, now);
+		assert_eq!(
+			filter.release_all(),
+			[(KEY_A!(), false), (KEY_LEFTSHIFT!(), false)]
+		);
+		assert_eq!(filter.state(), None);
+	}
+}
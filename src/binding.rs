@@ -0,0 +1,171 @@
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+use winit::event::ModifiersState;
+use xkbcommon::xkb::{self, Keycode, Keymap, Keysym};
+
+/// Offset between evdev scancodes (what winit and the receivers use) and xkb keycodes.
+pub const EVDEV_OFFSET: Keycode = 8;
+
+/// The base keysym of a key, ignoring any modifiers.
+pub fn keysym_for_scancode(keymap: &Keymap, scancode: u32) -> Keysym {
+	keymap
+		.key_get_syms_by_level(scancode + EVDEV_OFFSET, 0, 0)
+		.first()
+		.copied()
+		.unwrap_or(xkb::KEY_NoSymbol)
+}
+
+/// Look up a keysym by its xkb name. With `base_level` set, case is ignored and the lowercase keysym
+/// wins, which is what [`keysym_for_scancode`] returns for letters.
+pub fn parse_keysym(name: &str, base_level: bool) -> Option<Keysym> {
+	let flags = if base_level {
+		xkb::KEYSYM_CASE_INSENSITIVE
+	} else {
+		xkb::KEYSYM_NO_FLAGS
+	};
+	let keysym = xkb::keysym_from_name(name, flags);
+	(keysym != xkb::KEY_NoSymbol).then_some(keysym)
+}
+
+/// Whether the keysym belongs to a modifier key, which can't finish a binding.
+pub fn is_modifier(keysym: Keysym) -> bool {
+	(xkb::KEY_Shift_L..=xkb::KEY_Hyper_R).contains(&keysym)
+		|| keysym == xkb::KEY_ISO_Level3_Shift
+		|| keysym == xkb::KEY_ISO_Level5_Shift
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Modifiers {
+	pub ctrl: bool,
+	pub shift: bool,
+	pub alt: bool,
+	pub logo: bool,
+}
+impl From<ModifiersState> for Modifiers {
+	fn from(state: ModifiersState) -> Self {
+		Modifiers {
+			ctrl: state.ctrl(),
+			shift: state.shift(),
+			alt: state.alt(),
+			logo: state.logo(),
+		}
+	}
+}
+
+/// A key chord such as `ctrl+Escape`, written as `+` separated modifiers followed by an xkb keysym name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct KeyBinding {
+	pub modifiers: Modifiers,
+	pub keysym: Keysym,
+}
+impl KeyBinding {
+	pub fn matches(&self, modifiers: Modifiers, keysym: Keysym) -> bool {
+		self.modifiers == modifiers && self.keysym == keysym
+	}
+}
+impl FromStr for KeyBinding {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let mut parts: Vec<&str> = s.split('+').map(str::trim).collect();
+		let key = parts.pop().filter(|k| !k.is_empty());
+		let Some(key) = key else {
+			return Err(format!("key binding \"{s}\" has no key"));
+		};
+
+		let mut modifiers = Modifiers::default();
+		for modifier in parts {
+			let flag = match modifier.to_lowercase().as_str() {
+				"ctrl" | "control" => &mut modifiers.ctrl,
+				"shift" => &mut modifiers.shift,
+				"alt" => &mut modifiers.alt,
+				"super" | "logo" | "meta" => &mut modifiers.logo,
+				_ => return Err(format!("unknown modifier \"{modifier}\" in \"{s}\"")),
+			};
+			*flag = true;
+		}
+
+		let Some(keysym) = parse_keysym(key, true) else {
+			return Err(format!("unknown key \"{key}\" in \"{s}\""));
+		};
+		Ok(KeyBinding { modifiers, keysym })
+	}
+}
+impl fmt::Display for KeyBinding {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let modifiers = [
+			(self.modifiers.ctrl, "ctrl"),
+			(self.modifiers.shift, "shift"),
+			(self.modifiers.alt, "alt"),
+			(self.modifiers.logo, "super"),
+		];
+		for (_, name) in modifiers.iter().filter(|(active, _)| *active) {
+			write!(f, "{name}+")?;
+		}
+		write!(f, "{}", xkb::keysym_get_name(self.keysym))
+	}
+}
+impl TryFrom<String> for KeyBinding {
+	type Error = String;
+
+	fn try_from(value: String) -> Result<Self, Self::Error> {
+		value.parse()
+	}
+}
+impl From<KeyBinding> for String {
+	fn from(binding: KeyBinding) -> Self {
+		binding.to_string()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parses_modifiers_and_key() {
+		let binding: KeyBinding = "Ctrl + alt+Escape".parse().unwrap();
+		assert_eq!(
+			binding.modifiers,
+			Modifiers {
+				ctrl: true,
+				alt: true,
+				..Default::default()
+			}
+		);
+		assert_eq!(binding.keysym, xkb::KEY_Escape);
+		assert!(binding.matches(binding.modifiers, xkb::KEY_Escape));
+		assert!(!binding.matches(Modifiers::default(), xkb::KEY_Escape));
+	}
+
+	#[test]
+	fn letters_match_the_base_level() {
+		let binding: KeyBinding = "super+Q".parse().unwrap();
+		assert!(binding.modifiers.logo);
+		assert_eq!(binding.keysym, xkb::KEY_q);
+	}
+
+	#[test]
+	fn rejects_bad_bindings() {
+		for binding in ["", "ctrl+", "hyper+a", "ctrl+NotAKey"] {
+			assert!(binding.parse::<KeyBinding>().is_err(), "{binding:?}");
+		}
+	}
+
+	#[test]
+	fn round_trips_through_strings() {
+		for binding in ["ctrl+shift+alt+super+F12", "Pause", "alt+space"] {
+			let parsed: KeyBinding = binding.parse().unwrap();
+			assert_eq!(parsed.to_string(), binding);
+			assert_eq!(String::from(parsed).parse::<KeyBinding>(), Ok(parsed));
+		}
+	}
+
+	#[test]
+	fn modifier_keys_cant_finish_a_binding() {
+		assert!(is_modifier(xkb::KEY_Control_L));
+		assert!(is_modifier(xkb::KEY_ISO_Level3_Shift));
+		assert!(!is_modifier(xkb::KEY_a));
+	}
+}
@@ -0,0 +1,122 @@
+//! How the input window reaches the emitters without ever taking their locks on its own thread.
+//! It queues commands on a bounded channel, and a task on the Stardust side applies them in order
+//! to whichever emitters exist by then. Commands sent while disconnected from the server are
+//! dropped.
+
+use crate::{
+	input::InputEvent,
+	keyboard::Keyboard,
+	manifold::Manifold,
+	mouse::Mouse,
+	remap::KeyRepeat,
+	script::{self, ScriptStep},
+};
+use parking_lot::Mutex;
+use std::{sync::Arc, time::Instant};
+use tokio::sync::{mpsc, watch};
+use xkbcommon::xkb::{self, Keymap};
+
+/// How many commands can wait before the input window has to wait for the Stardust side.
+const CAPACITY: usize = 1024;
+
+pub enum Command {
+	/// Input along with when it reached the input window.
+	Input(InputEvent, Instant),
+	/// A keymap in xkb's text format. It gets compiled again on the Stardust side so the keyboard
+	/// doesn't share xkb objects with the input window's thread.
+	SetKeymap(String),
+	SetRepeat(KeyRepeat),
+	ToggleMouseKeys,
+	CycleDwellMode,
+	/// Let go of these keys on the keyboard and then play the macro.
+	PlayMacro {
+		release: Vec<u32>,
+		steps: Vec<ScriptStep>,
+	},
+}
+
+#[derive(Clone)]
+pub struct CommandSender(mpsc::Sender<Command>);
+impl CommandSender {
+	/// Queue a command, blocking if the Stardust side is a whole channel's worth behind. Commands
+	/// sent once the Stardust side is gone are dropped.
+	pub fn send(&self, command: Command) {
+		let _ = self.0.blocking_send(command);
+	}
+	pub fn set_keymap(&self, keymap: &Keymap) {
+		self.send(Command::SetKeymap(
+			keymap.get_as_string(xkb::KEYMAP_FORMAT_TEXT_V1),
+		));
+	}
+}
+
+/// Start applying commands to the emitters of whichever manifold `manifold` holds at the time,
+/// until every sender is dropped.
+pub fn spawn(manifold: watch::Receiver<Option<Arc<Mutex<Manifold>>>>) -> CommandSender {
+	let (sender, mut receiver) = mpsc::channel(CAPACITY);
+	tokio::task::spawn(async move {
+		while let Some(command) = receiver.recv().await {
+			let manifold = manifold.borrow().clone();
+			let (keyboard, mouse) = match manifold {
+				Some(manifold) => {
+					let manifold = manifold.lock();
+					(manifold.keyboard(), manifold.mouse())
+				}
+				None => (None, None),
+			};
+			// everything that piled up meanwhile goes to the same emitters
+			let mut next = Some(command);
+			while let Some(command) = next {
+				apply(command, keyboard.as_ref(), mouse.as_ref());
+				next = receiver.try_recv().ok();
+			}
+		}
+	});
+	CommandSender(sender)
+}
+
+fn apply(command: Command, keyboard: Option<&Keyboard>, mouse: Option<&Mouse>) {
+	match command {
+		Command::Input(event, received) => event.send_received_at(keyboard, mouse, received),
+		Command::SetKeymap(text) => {
+			let Some(keyboard) = keyboard else {
+				return;
+			};
+			if let Err(e) = keyboard.lock().set_keymap_string(&text) {
+				eprintln!("Keeping the old keymap, the input window's didn't compile: {e}");
+			}
+		}
+		Command::SetRepeat(repeat) => {
+			if let Some(keyboard) = keyboard {
+				keyboard.lock().set_repeat(Some(repeat));
+			}
+		}
+		Command::ToggleMouseKeys => {
+			if let Some(mouse) = mouse {
+				let mut mouse = mouse.lock();
+				let enabled = mouse.mouse_keys_enabled();
+				mouse.set_mouse_keys_enabled(!enabled);
+			}
+		}
+		Command::CycleDwellMode => {
+			if let Some(mouse) = mouse {
+				let mut mouse = mouse.lock();
+				let mode = mouse.dwell_mode().next();
+				mouse.set_dwell_mode(mode);
+			}
+		}
+		Command::PlayMacro { release, steps } => {
+			for key in release {
+				InputEvent::Key {
+					key,
+					pressed: false,
+				}
+				.send(keyboard, None);
+			}
+			let (keyboard, mouse) = (keyboard.cloned(), mouse.cloned());
+			tokio::task::spawn(async move {
+				script::play(&steps, keyboard.as_ref(), mouse.as_ref()).await;
+			});
+		}
+	}
+}
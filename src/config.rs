@@ -0,0 +1,874 @@
+use crate::{
+	binding::{parse_keysym, KeyBinding},
+	dwell::DwellMode,
+	emitter::Emittable,
+	remap::KeyRepeat,
+	script::ScriptStep,
+};
+use color::{rgba, Rgba};
+use color_eyre::eyre::{ensure, eyre, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use std::{
+	collections::BTreeMap,
+	fs,
+	io::{ErrorKind, Write},
+	path::{Path, PathBuf},
+	time::{Duration, SystemTime},
+};
+use xkbcommon::xkb::{self, Keymap, KEYMAP_COMPILE_NO_FLAGS};
+
+/// Everything that can be set in `$XDG_CONFIG_HOME/manifold/config.toml`.
+///
+/// Every field is optional, and unknown fields are rejected so typos don't go unnoticed.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+	pub window: WindowConfig,
+	pub grab: GrabConfig,
+	pub keyboard: EmitterConfig,
+	pub mouse: EmitterConfig,
+	pub keymap: KeymapConfig,
+	pub remap: RemapConfig,
+	pub repeat: RepeatConfig,
+	pub accessibility: AccessibilityConfig,
+	pub mouse_keys: MouseKeysConfig,
+	pub dwell: DwellConfig,
+	pub mouse_emulation: MouseEmulationConfig,
+	/// Pointer motion is summed up and sent once per Stardust frame. Setting this also sends it this
+	/// many times a second in between, for receivers that want it smoother than the frame rate.
+	pub motion_rate: Option<f64>,
+	/// Set the desktop's Caps, Num and Scroll Lock to match the focused receiver while input is
+	/// grabbed. This goes through XKB on X11, so it locks them on the desktop too.
+	pub mirror_leds: bool,
+	/// Chord that starts and stops recording a macro in the input window, recording is off if unset.
+	pub record_macro: Option<KeyBinding>,
+	pub macros: Vec<MacroConfig>,
+}
+impl Config {
+	pub fn path() -> Option<PathBuf> {
+		dirs::config_dir().map(|dir| dir.join("manifold").join("config.toml"))
+	}
+
+	/// Load and validate a config file, falling back to the defaults if it doesn't exist.
+	pub fn load_from(path: &Path) -> Result<Self> {
+		let contents = match fs::read_to_string(path) {
+			Ok(contents) => contents,
+			Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Config::default()),
+			Err(e) => return Err(e).wrap_err_with(|| format!("Couldn't read {}", path.display())),
+		};
+		let config: Config = toml::from_str(&contents)
+			.wrap_err_with(|| format!("Invalid config in {}", path.display()))?;
+		config
+			.validate()
+			.wrap_err_with(|| format!("Invalid config in {}", path.display()))?;
+		Ok(config)
+	}
+
+	pub fn validate(&self) -> Result<()> {
+		let [width, height] = self.window.size;
+		ensure!(
+			width > 0 && height > 0,
+			"window.size must be at least 1x1, got {width}x{height}"
+		);
+		ensure!(
+			self.window.cursor_radius.saturating_mul(2) <= width.min(height),
+			"window.cursor_radius must fit inside the window, got {}",
+			self.window.cursor_radius
+		);
+		if let Some(idle_timeout) = self.grab.idle_timeout {
+			ensure_seconds("grab.idle_timeout", idle_timeout)?;
+		}
+		if let Some(motion_rate) = self.motion_rate {
+			ensure_rate("motion_rate", motion_rate, "times")?;
+		}
+		self.keyboard.validate("keyboard")?;
+		self.mouse.validate("mouse")?;
+		self.remap.validate()?;
+		self.repeat.validate()?;
+		self.accessibility.validate()?;
+		self.mouse_keys.validate()?;
+		self.dwell.validate()?;
+		self.mouse_emulation.validate()?;
+		self.keymap.validate()?;
+		let bindings = self.bindings();
+		for (i, (name, binding)) in bindings.iter().enumerate() {
+			if let Some((other, _)) = bindings[..i].iter().find(|(_, other)| other == binding) {
+				return Err(eyre!("{name} is already used for {other}"));
+			}
+		}
+		for (i, macro_config) in self.macros.iter().enumerate() {
+			for step in &macro_config.steps {
+				step.validate()
+					.wrap_err_with(|| format!("Invalid step in macros[{i}]"))?;
+			}
+		}
+		Ok(())
+	}
+
+	/// Every chord the input window handles itself, named after its field.
+	fn bindings(&self) -> Vec<(String, KeyBinding)> {
+		let mut bindings = vec![
+			("grab.release".to_string(), self.grab.release),
+			("grab.hotkey".to_string(), self.grab.hotkey),
+		];
+		let optional = [
+			("keymap.cycle_layout", self.keymap.cycle_layout),
+			("mouse_keys.toggle", self.mouse_keys.toggle),
+			("dwell.cycle_mode", self.dwell.cycle_mode),
+			("record_macro", self.record_macro),
+		];
+		bindings.extend(
+			optional
+				.into_iter()
+				.filter_map(|(name, binding)| Some((name.to_string(), binding?))),
+		);
+		bindings.extend(
+			self.macros
+				.iter()
+				.enumerate()
+				.map(|(i, macro_config)| (format!("macros[{i}].trigger"), macro_config.trigger)),
+		);
+		bindings
+	}
+
+	/// Add a macro to the end of the config file at `path`, leaving the rest of the file alone.
+	pub fn append_macro(path: &Path, macro_config: &MacroConfig) -> Result<()> {
+		let steps: Vec<String> = macro_config
+			.steps
+			.iter()
+			.map(|step| Ok(format!("\t{},\n", toml::Value::try_from(step)?)))
+			.collect::<Result<_>>()?;
+		let section = format!(
+			"\n[[macros]]\ntrigger = \"{}\"\nsteps = [\n{}]\n",
+			macro_config.trigger,
+			steps.concat()
+		);
+		if let Some(dir) = path.parent() {
+			fs::create_dir_all(dir)?;
+		}
+		fs::OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(path)
+			.and_then(|mut file| file.write_all(section.as_bytes()))
+			.wrap_err_with(|| format!("Couldn't save the macro to {}", path.display()))
+	}
+
+	/// Poll the config file at `path` for changes, calling `reload` with each new valid config.
+	/// Invalid configs are reported and otherwise ignored so a typo doesn't take manifold down.
+	pub async fn watch(path: PathBuf, mut reload: impl FnMut(Config)) {
+		let modified_time = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+		let mut last_modified: Option<SystemTime> = modified_time(&path);
+		let mut interval = tokio::time::interval(Duration::from_secs(1));
+		loop {
+			interval.tick().await;
+			let modified = modified_time(&path);
+			if modified == last_modified {
+				continue;
+			}
+			last_modified = modified;
+			match Config::load_from(&path) {
+				Ok(config) => reload(config),
+				Err(e) => eprintln!("Not reloading config: {e:?}"),
+			}
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WindowConfig {
+	/// Logical size of the input window in pixels.
+	pub size: [u32; 2],
+	/// Half the width of the square drawn at the cursor while grabbed.
+	pub cursor_radius: u32,
+}
+impl Default for WindowConfig {
+	fn default() -> Self {
+		WindowConfig {
+			size: [512, 512],
+			cursor_radius: 8,
+		}
+	}
+}
+
+/// What makes the input window take the cursor and keyboard.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum GrabTrigger {
+	/// Left click inside the input window.
+	#[default]
+	Click,
+	/// Press `grab.hotkey` while the input window is focused.
+	Hotkey,
+	/// Focus the input window.
+	Focus,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GrabConfig {
+	/// Chord that releases the grab, it's never sent to receivers.
+	pub release: KeyBinding,
+	pub trigger: GrabTrigger,
+	/// Chord that grabs input when `trigger = "hotkey"`.
+	pub hotkey: KeyBinding,
+	pub ungrab_on_focus_loss: bool,
+	/// Seconds without any input before the grab is released, never if unset.
+	pub idle_timeout: Option<f64>,
+	/// Grab the whole keyboard too, so shortcuts the desktop would take like Alt+Tab reach the receivers.
+	pub keyboard: bool,
+}
+impl GrabConfig {
+	pub fn idle_timeout(&self) -> Option<Duration> {
+		self.idle_timeout
+			.filter(|secs| *secs > 0.0)
+			.map(Duration::from_secs_f64)
+	}
+}
+impl Default for GrabConfig {
+	fn default() -> Self {
+		GrabConfig {
+			release: "ctrl+Escape".parse().unwrap(),
+			trigger: GrabTrigger::Click,
+			hotkey: "ctrl+Return".parse().unwrap(),
+			ungrab_on_focus_loss: false,
+			idle_timeout: None,
+			keyboard: true,
+		}
+	}
+}
+
+/// Settings for one emitter, the unset ones fall back to the emitter's own defaults.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EmitterConfig {
+	/// Whether to spawn this emitter at all.
+	pub enabled: bool,
+	/// sRGB RGBA color of the lines to connected receivers, each component between 0 and 1.
+	pub color: Option<[f32; 4]>,
+	/// Thickness in meters of the lines to connected receivers.
+	pub line_thickness: f32,
+	/// Size in meters of the box you grab the emitter by.
+	pub size: Option<[f32; 3]>,
+	/// Where the lines to connected receivers start, relative to the emitter's center.
+	pub emit_point: Option<[f32; 3]>,
+	/// How far away in meters you can be to start grabbing the emitter.
+	pub max_distance: f32,
+}
+impl EmitterConfig {
+	pub fn color<E: Emittable>(&self) -> Rgba<f32> {
+		match self.color {
+			Some([r, g, b, a]) => rgba!(r, g, b, a),
+			None => E::COLOR,
+		}
+	}
+	pub fn size<E: Emittable>(&self) -> [f32; 3] {
+		self.size.unwrap_or(E::SIZE)
+	}
+	pub fn emit_point<E: Emittable>(&self) -> [f32; 3] {
+		self.emit_point.unwrap_or(E::EMIT_POINT)
+	}
+
+	/// Whether going from this config to `other` can only be done by recreating the emitter.
+	pub fn needs_rebuild(&self, other: &EmitterConfig) -> bool {
+		self.enabled != other.enabled
+			|| self.size != other.size
+			|| self.emit_point != other.emit_point
+			|| self.max_distance != other.max_distance
+	}
+
+	fn validate(&self, name: &str) -> Result<()> {
+		if let Some(color) = self.color {
+			ensure!(
+				color.iter().all(|c| (0.0..=1.0).contains(c)),
+				"{name}.color components must be between 0 and 1, got {color:?}"
+			);
+		}
+		ensure!(
+			self.line_thickness > 0.0,
+			"{name}.line_thickness must be positive, got {}",
+			self.line_thickness
+		);
+		if let Some(size) = self.size {
+			ensure!(
+				size.iter().all(|s| *s > 0.0),
+				"{name}.size must be positive on every axis, got {size:?}"
+			);
+		}
+		if let Some(emit_point) = self.emit_point {
+			ensure!(
+				emit_point.iter().all(|p| p.is_finite()),
+				"{name}.emit_point must be finite, got {emit_point:?}"
+			);
+		}
+		ensure!(
+			self.max_distance >= 0.0,
+			"{name}.max_distance can't be negative, got {}",
+			self.max_distance
+		);
+		Ok(())
+	}
+}
+impl Default for EmitterConfig {
+	fn default() -> Self {
+		EmitterConfig {
+			enabled: true,
+			color: None,
+			line_thickness: 0.005,
+			size: None,
+			emit_point: None,
+			max_distance: 0.1,
+		}
+	}
+}
+
+/// xkb RMLVO names to build the keymap from. If they're all empty the desktop's keymap is used.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeymapConfig {
+	pub rules: String,
+	pub model: String,
+	pub layout: String,
+	pub variant: String,
+	pub options: String,
+	/// Layouts to switch between with `cycle_layout`, each an xkb layout with an optional variant in
+	/// parentheses like `de(nodeadkeys)`. The first one is used in place of `layout` and `variant`.
+	pub layouts: Vec<String>,
+	/// Chord that switches the receivers to the next of `layouts` in the input window.
+	pub cycle_layout: Option<KeyBinding>,
+	/// Layouts, written like the ones in `layouts`, for the receivers with these labels to keep
+	/// whatever the others are switched to. A label is `receiver N` or the name the receiver was
+	/// given over the control socket.
+	pub receivers: BTreeMap<String, String>,
+}
+impl KeymapConfig {
+	/// Whether the config names a keymap for everyone, instead of leaving it to the desktop.
+	pub fn is_set(&self) -> bool {
+		KeymapConfig {
+			cycle_layout: None,
+			receivers: BTreeMap::new(),
+			..self.clone()
+		} != KeymapConfig::default()
+	}
+
+	fn validate(&self) -> Result<()> {
+		for layout in &self.layouts {
+			ensure!(
+				parse_layout(layout).is_some(),
+				"keymap.layouts has {layout:?}, expected a layout like \"us\" or \"de(nodeadkeys)\""
+			);
+		}
+		for (label, layout) in &self.receivers {
+			ensure!(
+				parse_layout(layout).is_some(),
+				"keymap.receivers.{label:?} is {layout:?}, expected a layout like \"us\" or \"de(nodeadkeys)\""
+			);
+		}
+		Ok(())
+	}
+
+	/// Compile a keymap from these names, xkb fills in any empty ones with its defaults.
+	pub fn compile(&self) -> Result<Keymap> {
+		self.compile_layout(0)
+	}
+
+	/// Compile the keymap for the `index`th of `layouts`, or for `layout` and `variant` if there are
+	/// none.
+	pub fn compile_layout(&self, index: usize) -> Result<Keymap> {
+		match self.layouts.get(index) {
+			Some(layout) => self.compile_named(layout),
+			None => self.compile_names(&self.layout, &self.variant),
+		}
+	}
+
+	/// Compile the keymap for a layout like `de(nodeadkeys)`, with the rest of the names from here.
+	pub fn compile_named(&self, layout: &str) -> Result<Keymap> {
+		let (layout, variant) =
+			parse_layout(layout).ok_or_else(|| eyre!("Invalid layout {layout:?}"))?;
+		self.compile_names(layout, variant)
+	}
+
+	fn compile_names(&self, layout: &str, variant: &str) -> Result<Keymap> {
+		Keymap::new_from_names(
+			&xkb::Context::new(0),
+			self.rules.as_str(),
+			self.model.as_str(),
+			layout,
+			variant,
+			(!self.options.is_empty()).then(|| self.options.clone()),
+			KEYMAP_COMPILE_NO_FLAGS,
+		)
+		.ok_or_else(|| {
+			eyre!("Couldn't compile a keymap with layout {layout:?} and variant {variant:?} from {self:?}")
+		})
+	}
+}
+
+/// Check that `seconds` is a length of time a [`Duration`] can hold.
+fn ensure_seconds(name: &str, seconds: f64) -> Result<()> {
+	ensure!(
+		Duration::try_from_secs_f64(seconds).is_ok(),
+		"{name} must be a positive number of seconds, got {seconds}"
+	);
+	Ok(())
+}
+
+/// Check that something can happen `rate` times a second, with a [`Duration`] of more than 0 between
+/// each time.
+fn ensure_rate(name: &str, rate: f64, unit: &str) -> Result<()> {
+	ensure!(
+		rate > 0.0 && Duration::try_from_secs_f64(1.0 / rate).is_ok_and(|d| !d.is_zero()),
+		"{name} must be a positive number of {unit} a second, got {rate}"
+	);
+	Ok(())
+}
+
+/// Split `de(nodeadkeys)` into its layout and variant.
+fn parse_layout(layout: &str) -> Option<(&str, &str)> {
+	match layout.split_once('(') {
+		None => Some((layout, "")),
+		Some((layout, variant)) => Some((layout, variant.strip_suffix(')')?)),
+	}
+}
+
+/// keyd style remapping applied to keys before they reach the receivers. Keys are named by the
+/// keysym they type without modifiers, and map to another keysym, `@layer` to switch to a layer
+/// while held, or a tap-hold pair:
+///
+/// ```toml
+/// [remap.keys]
+/// Caps_Lock = { tap = "Escape", hold = "Control_L" }
+/// space = { tap = "space", hold = "@nav" }
+///
+/// [remap.layers.nav]
+/// h = "Left"
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RemapConfig {
+	/// Seconds a tap-hold key has to stay down before it counts as held. Pressing another key
+	/// while it's down also makes it a hold.
+	pub tap_timeout: f64,
+	pub keys: BTreeMap<String, KeyRemap>,
+	pub layers: BTreeMap<String, BTreeMap<String, KeyRemap>>,
+	/// Keys and layers that replace the ones above for the receivers with these labels, `receiver N`
+	/// or the name the receiver was given over the control socket.
+	pub receivers: BTreeMap<String, RemapRules>,
+}
+impl RemapConfig {
+	pub fn tap_timeout(&self) -> Duration {
+		Duration::from_secs_f64(self.tap_timeout)
+	}
+
+	/// The keys and layers, without the timeout.
+	pub fn rules(&self) -> RemapRules {
+		RemapRules {
+			keys: self.keys.clone(),
+			layers: self.layers.clone(),
+		}
+	}
+
+	fn validate(&self) -> Result<()> {
+		ensure_seconds("remap.tap_timeout", self.tap_timeout)?;
+		ensure!(
+			self.tap_timeout > 0.0,
+			"remap.tap_timeout can't be 0, a tap-hold key would never tap"
+		);
+		self.rules().validate("remap")?;
+		for (label, overrides) in &self.receivers {
+			self.rules()
+				.with(overrides)
+				.validate(&format!("remap.receivers.{label:?}"))?;
+		}
+		Ok(())
+	}
+}
+impl Default for RemapConfig {
+	fn default() -> Self {
+		RemapConfig {
+			tap_timeout: 0.2,
+			keys: BTreeMap::new(),
+			layers: BTreeMap::new(),
+			receivers: BTreeMap::new(),
+		}
+	}
+}
+
+/// Keys and layers to remap, those in `remap` for every receiver or ones for a single receiver from
+/// `remap.receivers` or the control socket.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RemapRules {
+	pub keys: BTreeMap<String, KeyRemap>,
+	pub layers: BTreeMap<String, BTreeMap<String, KeyRemap>>,
+}
+impl RemapRules {
+	/// The keys and layers from `overrides` replacing the same ones here.
+	pub fn with(&self, overrides: &RemapRules) -> RemapRules {
+		let mut rules = self.clone();
+		rules.keys.extend(overrides.keys.clone());
+		rules.layers.extend(overrides.layers.clone());
+		rules
+	}
+
+	pub(crate) fn validate(&self, name: &str) -> Result<()> {
+		let layers = self.layers.values().map(|keys| ("layers", keys));
+		for (section, keys) in [("keys", &self.keys)].into_iter().chain(layers) {
+			for (from, to) in keys {
+				ensure!(
+					parse_keysym(from, true).is_some(),
+					"{name}.{section} has an unknown key \"{from}\""
+				);
+				let targets = match to {
+					KeyRemap::Key(to) => vec![to],
+					KeyRemap::TapHold { tap, hold } => {
+						ensure!(
+							!tap.starts_with('@'),
+							"{name}.{section}: {from} can only tap a key, not a layer"
+						);
+						vec![tap, hold]
+					}
+				};
+				for target in targets {
+					match target.strip_prefix('@') {
+						Some(layer) => ensure!(
+							self.layers.contains_key(layer),
+							"{name}.{section}: {from} uses the unknown layer \"{layer}\""
+						),
+						None => ensure!(
+							parse_keysym(target, false).is_some(),
+							"{name}.{section}: {from} maps to the unknown key \"{target}\""
+						),
+					}
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum KeyRemap {
+	/// Another keysym, or `@layer`.
+	Key(String),
+	/// Type `tap` when the key is tapped, act as `hold` (a keysym or `@layer`) while it's held.
+	TapHold { tap: String, hold: String },
+}
+
+/// Key repeat, done by manifold itself since receivers don't get told about repeat rates.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RepeatConfig {
+	pub enabled: bool,
+	/// Seconds a key has to be held before it starts repeating.
+	pub delay: f64,
+	/// Repeats per second once it has.
+	pub rate: f64,
+	/// Use the X server's delay and rate instead, when there is one.
+	pub from_desktop: bool,
+}
+impl RepeatConfig {
+	pub fn key_repeat(&self) -> Option<KeyRepeat> {
+		self.enabled.then(|| KeyRepeat {
+			delay: Duration::from_secs_f64(self.delay),
+			interval: Duration::from_secs_f64(1.0 / self.rate),
+		})
+	}
+
+	fn validate(&self) -> Result<()> {
+		ensure_seconds("repeat.delay", self.delay)?;
+		ensure_rate("repeat.rate", self.rate, "repeats")
+	}
+}
+impl Default for RepeatConfig {
+	fn default() -> Self {
+		RepeatConfig {
+			enabled: true,
+			delay: 0.6,
+			rate: 25.0,
+			from_desktop: true,
+		}
+	}
+}
+
+/// Keyboard filters for people who have trouble hitting keys, all off by default.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessibilityConfig {
+	/// Modifiers tapped on their own stay down for the next key, tapping one twice locks it.
+	pub sticky_keys: bool,
+	/// Seconds a key has to be held before it counts as pressed.
+	pub slow_keys: Option<f64>,
+	/// Seconds after releasing a key during which pressing it again is ignored.
+	pub bounce_keys: Option<f64>,
+}
+impl AccessibilityConfig {
+	fn validate(&self) -> Result<()> {
+		for (name, seconds) in [
+			("slow_keys", self.slow_keys),
+			("bounce_keys", self.bounce_keys),
+		] {
+			if let Some(seconds) = seconds {
+				ensure_seconds(&format!("accessibility.{name}"), seconds)?;
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Drive the mouse emitter from the numpad: the number keys around 5 move the pointer, 5 clicks, the
+/// plus key double-clicks, 0 holds the button down to drag and the dot lets go of it, while /, * and
+/// the minus key pick the left, middle or right button for the rest.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MouseKeysConfig {
+	/// Start with mouse keys on.
+	pub enabled: bool,
+	/// Chord that turns mouse keys on and off in the input window.
+	pub toggle: Option<KeyBinding>,
+	/// Pixels per second the pointer starts moving at.
+	pub speed: f64,
+	/// Pixels per second the pointer speeds up to.
+	pub max_speed: f64,
+	/// Seconds of holding a direction it takes to reach `max_speed`.
+	pub acceleration_time: f64,
+}
+impl MouseKeysConfig {
+	fn validate(&self) -> Result<()> {
+		ensure!(
+			self.speed.is_finite() && self.speed > 0.0,
+			"mouse_keys.speed must be a positive number of pixels per second, got {}",
+			self.speed
+		);
+		ensure!(
+			self.max_speed.is_finite() && self.max_speed >= self.speed,
+			"mouse_keys.max_speed must be at least mouse_keys.speed, got {}",
+			self.max_speed
+		);
+		ensure_seconds("mouse_keys.acceleration_time", self.acceleration_time)
+	}
+}
+impl Default for MouseKeysConfig {
+	fn default() -> Self {
+		MouseKeysConfig {
+			enabled: false,
+			toggle: None,
+			speed: 100.0,
+			max_speed: 1200.0,
+			acceleration_time: 1.5,
+		}
+	}
+}
+
+/// Click by holding the pointer still instead of pressing a button.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DwellConfig {
+	pub enabled: bool,
+	/// Seconds the pointer has to rest before it clicks.
+	pub time: f64,
+	/// Pixels the pointer can drift while resting.
+	pub threshold: f32,
+	/// One of `left`, `right`, `double` or `drag`.
+	pub mode: DwellMode,
+	/// Chord that switches to the next mode in the input window.
+	pub cycle_mode: Option<KeyBinding>,
+}
+impl DwellConfig {
+	fn validate(&self) -> Result<()> {
+		ensure_seconds("dwell.time", self.time)?;
+		ensure!(self.time > 0.0, "dwell.time can't be 0");
+		ensure!(
+			self.threshold.is_finite() && self.threshold >= 0.0,
+			"dwell.threshold must be a positive number of pixels, got {}",
+			self.threshold
+		);
+		Ok(())
+	}
+}
+impl Default for DwellConfig {
+	fn default() -> Self {
+		DwellConfig {
+			enabled: false,
+			time: 1.0,
+			threshold: 5.0,
+			mode: DwellMode::Left,
+			cycle_mode: None,
+		}
+	}
+}
+
+/// Stand-ins for a scroll wheel and middle button, for trackballs and mice without them.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MouseEmulationConfig {
+	/// Evdev code of the button that turns motion into scrolling while it's held, such as 274 for
+	/// the middle button. Clicking it without moving still clicks.
+	pub scroll_button: Option<u32>,
+	/// Scroll distance per pixel of motion, negative to scroll the other way.
+	pub scroll_speed: f32,
+	/// Seconds within which pressing left and right together clicks the middle button instead.
+	pub middle_chord: Option<f64>,
+}
+impl MouseEmulationConfig {
+	fn validate(&self) -> Result<()> {
+		ensure!(
+			self.scroll_speed.is_finite(),
+			"mouse_emulation.scroll_speed must be a number, got {}",
+			self.scroll_speed
+		);
+		if let Some(middle_chord) = self.middle_chord {
+			ensure_seconds("mouse_emulation.middle_chord", middle_chord)?;
+		}
+		Ok(())
+	}
+}
+impl Default for MouseEmulationConfig {
+	fn default() -> Self {
+		MouseEmulationConfig {
+			scroll_button: None,
+			scroll_speed: 1.0,
+			middle_chord: None,
+		}
+	}
+}
+
+/// A sequence of input played into the focused receiver whenever `trigger` is pressed.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MacroConfig {
+	/// Chord that plays the macro, it's never sent to receivers.
+	pub trigger: KeyBinding,
+	/// Same steps as an input script, such as `{ type = "key", key = 30, pressed = true }` or `{ delay = 0.1 }`.
+	pub steps: Vec<ScriptStep>,
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn parse(toml: &str) -> Result<Config> {
+		let config: Config = toml::from_str(toml)?;
+		config.validate()?;
+		Ok(config)
+	}
+
+	#[test]
+	fn default_is_valid() {
+		Config::default().validate().unwrap();
+		assert_eq!(parse("").unwrap(), Config::default());
+	}
+
+	#[test]
+	fn receiver_rules_replace_shared_ones() {
+		let config =
+			parse("[remap.keys]\na = \"b\"\nc = \"d\"\n[remap.layers.nav]\nh = \"Left\"").unwrap();
+		let overrides: RemapRules = toml::from_str("[keys]\na = \"x\"").unwrap();
+		let rules = config.remap.rules().with(&overrides);
+		assert_eq!(rules.keys["a"], KeyRemap::Key("x".into()));
+		assert_eq!(rules.keys["c"], KeyRemap::Key("d".into()));
+		assert!(rules.layers.contains_key("nav"));
+
+		let config = parse(
+			"[keymap.receivers]\nterminal = \"de(nodeadkeys)\"\n[remap.receivers.\"receiver 2\".keys]\na = \"x\"",
+		)
+		.unwrap();
+		assert!(!config.keymap.is_set());
+		assert_eq!(config.keymap.receivers["terminal"], "de(nodeadkeys)");
+		assert_eq!(
+			config.remap.receivers["receiver 2"].keys["a"],
+			KeyRemap::Key("x".into())
+		);
+		assert!(parse("[keymap.receivers]\nterminal = \"de(nodeadkeys\"").is_err());
+		assert!(parse("[remap.receivers.terminal.keys]\na = \"Nonsense\"").is_err());
+		assert!(parse(
+			"[remap.receivers.terminal.keys]\nspace = { tap = \"space\", hold = \"@nav\" }"
+		)
+		.is_err());
+	}
+
+	#[test]
+	fn rejects_unknown_fields() {
+		assert!(parse("[repeat]\nrat = 30.0").is_err());
+	}
+
+	#[test]
+	fn rejects_durations_out_of_range() {
+		for toml in [
+			"repeat.delay = 1e30",
+			"repeat.delay = -0.1",
+			"repeat.rate = 1e-300",
+			"repeat.rate = 0.0",
+			"repeat.rate = inf",
+			"motion_rate = 1e-300",
+			"motion_rate = -60.0",
+			"grab.idle_timeout = nan",
+			"remap.tap_timeout = 0.0",
+			"remap.tap_timeout = 1e300",
+			"accessibility.slow_keys = -1.0",
+			"accessibility.bounce_keys = 1e30",
+			"mouse_keys.acceleration_time = inf",
+			"dwell.time = 1e30",
+			"mouse_emulation.middle_chord = -0.05",
+			"[[macros]]\ntrigger = \"ctrl+m\"\nsteps = [{ delay = 1e30 }]",
+		] {
+			assert!(parse(toml).is_err(), "{toml} should be invalid");
+		}
+	}
+
+	#[test]
+	fn valid_durations_convert() {
+		let config =
+			parse("repeat.delay = 0.0\nrepeat.rate = 1000.0\nmotion_rate = 240.0").unwrap();
+		let repeat = config.repeat.key_repeat().unwrap();
+		assert_eq!(repeat.delay, Duration::ZERO);
+		assert_eq!(repeat.interval, Duration::from_millis(1));
+		assert_eq!(config.remap.tap_timeout(), Duration::from_millis(200));
+	}
+
+	#[test]
+	fn rejects_duplicate_bindings() {
+		assert!(parse("keymap.cycle_layout = \"ctrl+Escape\"").is_err());
+		assert!(parse("mouse_keys.toggle = \"ctrl+Escape\"").is_err());
+		assert!(parse("[[macros]]\ntrigger = \"ctrl+Escape\"\nsteps = []").is_err());
+		assert!(parse("keymap.cycle_layout = \"ctrl+Return\"").is_err());
+		assert!(parse("record_macro = \"ctrl+m\"\nmouse_keys.toggle = \"ctrl+m\"").is_err());
+		assert!(parse("dwell.cycle_mode = \"ctrl+d\"\n[grab]\nhotkey = \"ctrl+d\"").is_err());
+		assert!(parse(
+			"[[macros]]\ntrigger = \"ctrl+m\"\nsteps = []\n[[macros]]\ntrigger = \"ctrl+m\"\nsteps = []"
+		)
+		.is_err());
+		assert!(
+			parse("record_macro = \"ctrl+m\"\n[[macros]]\ntrigger = \"ctrl+m\"\nsteps = []")
+				.is_err()
+		);
+		assert!(
+			parse("keymap.cycle_layout = \"ctrl+space\"\nmouse_keys.toggle = \"ctrl+k\"").is_ok()
+		);
+	}
+
+	#[test]
+	fn checks_remap_targets() {
+		assert!(parse("[remap.keys]\nCaps_Lock = \"Escape\"").is_ok());
+		assert!(parse("[remap.keys]\nCaps_Lock = \"Nonsense\"").is_err());
+		assert!(parse("[remap.keys]\nspace = { tap = \"space\", hold = \"@nav\" }").is_err());
+		assert!(parse(
+			"[remap.keys]\nspace = { tap = \"space\", hold = \"@nav\" }\n[remap.layers.nav]\nh = \"Left\""
+		)
+		.is_ok());
+		assert!(parse(
+			"[remap.keys]\nspace = { tap = \"@nav\", hold = \"space\" }\n[remap.layers.nav]"
+		)
+		.is_err());
+	}
+
+	#[test]
+	fn checks_layouts() {
+		assert!(parse("keymap.layouts = [\"us\", \"de(nodeadkeys)\"]").is_ok());
+		assert!(parse("keymap.layouts = [\"de(nodeadkeys\"]").is_err());
+		assert_eq!(parse_layout("de(nodeadkeys)"), Some(("de", "nodeadkeys")));
+		assert_eq!(parse_layout("us"), Some(("us", "")));
+	}
+}
@@ -0,0 +1,589 @@
+//! A Unix socket other programs can drive manifold through, with one JSON object per line each way:
+//!
+//! ```text
+//! > {"request": "receivers_info"}
+//! < {"response": "receivers_info", "keyboard": {"receivers": ["4"], "names": {"4": "receiver 1"}, "focus": null}, "mouse": null}
+//! > {"request": "set_name", "emitter": "keyboard", "uid": "4", "name": "terminal"}
+//! < {"response": "ok"}
+//! > {"request": "focus", "emitter": "keyboard", "uid": "4"}
+//! < {"response": "ok"}
+//! > {"request": "inject", "event": {"type": "key", "key": 30, "pressed": true}}
+//! < {"response": "ok"}
+//! > {"request": "subscribe"}
+//! < {"response": "ok"}
+//! < {"response": "connection", "emitter": "keyboard", "uid": "5", "connected": true}
+//! > {"request": "latency"}
+//! < {"response": "latency", "key": {"count": 12, "mean_micros": 180, "max_micros": 410, "buckets": [...]}, ...}
+//! ```
+//!
+//! Receiver UIDs are made up anew each time a receiver connects. The config gives receivers their
+//! own layouts and remapping by label instead (`keymap.receivers` and `remap.receivers`), which is
+//! `receiver N` until `set_name` names it. `set_keymap` and `set_remap` override those for a single
+//! connection, until the receiver is named or the config changes.
+//!
+//! Every request gets exactly one response. After a `subscribe` request, connection events are
+//! interleaved with the responses until the client disconnects.
+
+use crate::{
+	config::RemapRules,
+	emitter::ConnectionEvent,
+	input::InputEvent,
+	input_window::InputWindowEvent,
+	latency::{LatencySnapshot, LATENCY},
+	manifold::Manifold,
+	EmitterKind,
+};
+use color_eyre::eyre::{bail, Result, WrapErr};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, env, fs, path::PathBuf, sync::Arc};
+use tokio::{
+	io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+	net::{UnixListener, UnixStream},
+	sync::broadcast::{self, error::RecvError},
+	task::JoinSet,
+};
+use winit::event_loop::EventLoopProxy;
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "request", rename_all = "snake_case")]
+pub enum Request {
+	/// Which emitters exist and which receivers are connected to them.
+	ReceiversInfo,
+	/// Only send an emitter's input to one receiver, or to all of them again if `uid` is missing.
+	Focus {
+		emitter: EmitterKind,
+		#[serde(default)]
+		uid: Option<String>,
+	},
+	/// Label the line to a receiver with `name` instead of its number, `receiver N`. Receivers are
+	/// only ever named this way, and the name goes with the number, which the next receiver to get it
+	/// is labelled with too, including after manifold reconnects to the server.
+	SetName {
+		emitter: EmitterKind,
+		uid: String,
+		name: String,
+	},
+	Grab,
+	Ungrab,
+	/// Send an input event as if it came from the desktop.
+	Inject {
+		event: InputEvent,
+	},
+	/// Read the keymap again, picking up `setxkbmap` changes when the config doesn't set one.
+	ReloadKeymap,
+	/// Give a keyboard receiver a layout of its own, like `de(nodeadkeys)`, or the shared keymap
+	/// again if `layout` is missing.
+	SetKeymap {
+		uid: String,
+		#[serde(default)]
+		layout: Option<String>,
+	},
+	/// Remap a keyboard receiver's keys with these keys and layers on top of `remap`, or with only
+	/// `remap` again if `rules` is missing.
+	SetRemap {
+		uid: String,
+		#[serde(default)]
+		rules: Option<RemapRules>,
+	},
+	/// Start receiving `connection` events on this socket.
+	Subscribe,
+	/// How long input has taken to reach the receivers, optionally starting the counts over after.
+	Latency {
+		#[serde(default)]
+		reset: bool,
+	},
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "response", rename_all = "snake_case")]
+pub enum Response {
+	Ok,
+	Error {
+		message: String,
+	},
+	/// `None` for emitters that are disabled.
+	ReceiversInfo {
+		keyboard: Option<EmitterInfo>,
+		mouse: Option<EmitterInfo>,
+	},
+	Connection {
+		emitter: EmitterKind,
+		uid: String,
+		connected: bool,
+	},
+	Latency(LatencySnapshot),
+}
+impl Response {
+	fn error(message: impl Into<String>) -> Self {
+		Response::Error {
+			message: message.into(),
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct EmitterInfo {
+	/// UIDs of the connected receivers.
+	pub receivers: Vec<String>,
+	/// What their lines are labelled, by UID.
+	pub names: BTreeMap<String, String>,
+	pub focus: Option<String>,
+}
+
+/// `$XDG_RUNTIME_DIR/manifold.sock`, or the same name in the temp dir without a runtime dir.
+pub fn default_path() -> PathBuf {
+	dirs::runtime_dir()
+		.unwrap_or_else(env::temp_dir)
+		.join("manifold.sock")
+}
+
+/// Removes the socket file once nothing is listening on it anymore.
+struct Listener {
+	listener: UnixListener,
+	path: PathBuf,
+}
+impl Listener {
+	/// Listen on `path`, replacing the socket file a manifold that didn't shut down cleanly left behind.
+	async fn bind(path: PathBuf) -> Result<Self> {
+		if UnixStream::connect(&path).await.is_ok() {
+			bail!(
+				"Another manifold is already listening on {}",
+				path.display()
+			);
+		}
+		let _ = fs::remove_file(&path);
+		Ok(Listener {
+			listener: UnixListener::bind(&path)
+				.wrap_err_with(|| format!("Couldn't listen on {}", path.display()))?,
+			path,
+		})
+	}
+}
+impl Drop for Listener {
+	fn drop(&mut self) {
+		let _ = fs::remove_file(&self.path);
+	}
+}
+
+/// Accept clients on `path` until the task is dropped, which disconnects the clients too so none of
+/// them outlive `manifold`. `window` is `None` when there's no input window, in which case grabbing
+/// isn't possible.
+pub async fn serve(
+	path: PathBuf,
+	manifold: Arc<Mutex<Manifold>>,
+	window: Option<EventLoopProxy<InputWindowEvent>>,
+) -> Result<()> {
+	let listener = Listener::bind(path).await?;
+	let control = Arc::new(Control {
+		manifold,
+		window: window.map(Mutex::new),
+	});
+	let mut clients = JoinSet::new();
+	loop {
+		tokio::select! {
+			accepted = listener.listener.accept() => {
+				let (stream, _) = accepted?;
+				let control = control.clone();
+				clients.spawn(async move {
+					if let Err(e) = control.handle_client(stream).await {
+						eprintln!("Control client went away: {e}");
+					}
+				});
+			}
+			// forget about clients that are done
+			Some(_) = clients.join_next() => (),
+		}
+	}
+}
+
+/// Send one request to the socket at `path` and print the responses, forever if it was a `subscribe`.
+pub async fn send(path: PathBuf, request: &str) -> Result<()> {
+	let parsed: Request = serde_json::from_str(request).wrap_err("Invalid request")?;
+	let stream = UnixStream::connect(&path)
+		.await
+		.wrap_err_with(|| format!("Couldn't connect to {}", path.display()))?;
+	let (reader, mut writer) = stream.into_split();
+	writer
+		.write_all(format!("{}\n", request.trim()).as_bytes())
+		.await?;
+
+	let mut lines = BufReader::new(reader).lines();
+	while let Some(line) = lines.next_line().await? {
+		println!("{line}");
+		if parsed != Request::Subscribe {
+			break;
+		}
+	}
+	Ok(())
+}
+
+struct Control {
+	manifold: Arc<Mutex<Manifold>>,
+	window: Option<Mutex<EventLoopProxy<InputWindowEvent>>>,
+}
+impl Control {
+	async fn handle_client(&self, stream: UnixStream) -> Result<()> {
+		let (reader, mut writer) = stream.into_split();
+		let mut lines = BufReader::new(reader).lines();
+		let mut subscription = Subscription::default();
+		loop {
+			let response = tokio::select! {
+				line = lines.next_line() => {
+					let Some(line) = line? else {
+						return Ok(());
+					};
+					if line.trim().is_empty() {
+						continue;
+					}
+					match serde_json::from_str(&line) {
+						Ok(request) => self.handle(request, &mut subscription),
+						Err(e) => Response::error(format!("Invalid request: {e}")),
+					}
+				}
+				response = subscription.next(&self.manifold) => response,
+			};
+			let mut response = serde_json::to_string(&response)?;
+			response.push('\n');
+			writer.write_all(response.as_bytes()).await?;
+		}
+	}
+
+	fn handle(&self, request: Request, subscription: &mut Subscription) -> Response {
+		let (keyboard, mouse) = {
+			let manifold = self.manifold.lock();
+			(manifold.keyboard(), manifold.mouse())
+		};
+		match request {
+			Request::ReceiversInfo => Response::ReceiversInfo {
+				keyboard: keyboard.map(|keyboard| {
+					let keyboard = keyboard.lock();
+					EmitterInfo {
+						receivers: keyboard.connected_receivers(),
+						names: keyboard.receiver_names(),
+						focus: keyboard.focus().map(str::to_owned),
+					}
+				}),
+				mouse: mouse.map(|mouse| {
+					let mouse = mouse.lock();
+					EmitterInfo {
+						receivers: mouse.connected_receivers(),
+						names: mouse.receiver_names(),
+						focus: mouse.focus().map(str::to_owned),
+					}
+				}),
+			},
+			Request::Focus { emitter, uid } => {
+				let focused = match emitter {
+					EmitterKind::Keyboard => keyboard.map(|k| k.lock().set_focus(uid.clone())),
+					EmitterKind::Mouse => mouse.map(|m| m.lock().set_focus(uid.clone())),
+				};
+				match focused {
+					None => Response::error(format!("The {emitter} emitter is disabled")),
+					Some(false) => Response::error(format!(
+						"No receiver {} is connected to the {emitter}",
+						uid.unwrap_or_default()
+					)),
+					Some(true) => Response::Ok,
+				}
+			}
+			Request::SetName { emitter, uid, name } => {
+				let named = match emitter {
+					EmitterKind::Keyboard => {
+						keyboard.map(|k| k.lock().set_receiver_name(&uid, &name))
+					}
+					EmitterKind::Mouse => mouse.map(|m| m.lock().set_receiver_name(&uid, &name)),
+				};
+				match named {
+					None => Response::error(format!("The {emitter} emitter is disabled")),
+					Some(false) => {
+						Response::error(format!("No receiver {uid} is connected to the {emitter}"))
+					}
+					Some(true) => Response::Ok,
+				}
+			}
+			Request::Grab => self.send_to_window(InputWindowEvent::SetGrab(true)),
+			Request::Ungrab => self.send_to_window(InputWindowEvent::SetGrab(false)),
+			Request::Inject { event } => {
+				event.send(keyboard.as_ref(), mouse.as_ref());
+				Response::Ok
+			}
+			Request::ReloadKeymap if self.window.is_some() => {
+				self.send_to_window(InputWindowEvent::ReloadKeymap)
+			}
+			Request::ReloadKeymap => {
+				let Some(keyboard) = keyboard else {
+					return Response::error("The keyboard emitter is disabled");
+				};
+				match self.manifold.lock().config().keymap.compile() {
+					Ok(keymap) => {
+						keyboard.lock().set_keymap(keymap);
+						Response::Ok
+					}
+					Err(e) => Response::error(format!("Keeping the old keymap: {e}")),
+				}
+			}
+			Request::SetKeymap { uid, layout } => {
+				let Some(keyboard) = keyboard else {
+					return Response::error("The keyboard emitter is disabled");
+				};
+				let keymap = match layout {
+					Some(layout) => {
+						match self.manifold.lock().config().keymap.compile_named(&layout) {
+							Ok(keymap) => Some(keymap),
+							Err(e) => return Response::error(format!("{e}")),
+						}
+					}
+					None => None,
+				};
+				if keyboard.lock().set_receiver_keymap(&uid, keymap) {
+					Response::Ok
+				} else {
+					Response::error(format!("No receiver {uid} is connected to the keyboard"))
+				}
+			}
+			Request::SetRemap { uid, rules } => {
+				let Some(keyboard) = keyboard else {
+					return Response::error("The keyboard emitter is disabled");
+				};
+				if let Some(rules) = &rules {
+					if let Err(e) = rules.validate("rules") {
+						return Response::error(format!("{e}"));
+					}
+				}
+				if keyboard.lock().set_receiver_remap(&uid, rules) {
+					Response::Ok
+				} else {
+					Response::error(format!("No receiver {uid} is connected to the keyboard"))
+				}
+			}
+			Request::Subscribe => {
+				*subscription = Subscription {
+					keyboard: keyboard.map(|k| k.lock().subscribe()),
+					mouse: mouse.map(|m| m.lock().subscribe()),
+				};
+				Response::Ok
+			}
+			Request::Latency { reset } => {
+				let latency = LATENCY.snapshot();
+				if reset {
+					LATENCY.reset();
+				}
+				Response::Latency(latency)
+			}
+		}
+	}
+
+	fn send_to_window(&self, event: InputWindowEvent) -> Response {
+		let Some(window) = &self.window else {
+			return Response::error("There's no input window");
+		};
+		match window.lock().send_event(event) {
+			Ok(()) => Response::Ok,
+			Err(_) => Response::error("The input window has closed"),
+		}
+	}
+}
+
+#[derive(Default)]
+struct Subscription {
+	keyboard: Option<broadcast::Receiver<ConnectionEvent>>,
+	mouse: Option<broadcast::Receiver<ConnectionEvent>>,
+}
+impl Subscription {
+	/// The next connection event from either emitter, never resolving if nothing is subscribed.
+	async fn next(&mut self, manifold: &Mutex<Manifold>) -> Response {
+		let (emitter, event) = tokio::select! {
+			event = next_event(&mut self.keyboard, || {
+				manifold.lock().keyboard().map(|k| k.lock().subscribe())
+			}) => (EmitterKind::Keyboard, event),
+			event = next_event(&mut self.mouse, || {
+				manifold.lock().mouse().map(|m| m.lock().subscribe())
+			}) => (EmitterKind::Mouse, event),
+		};
+		let (uid, connected) = match event {
+			ConnectionEvent::Connected(uid) => (uid, true),
+			ConnectionEvent::Disconnected(uid) => (uid, false),
+		};
+		Response::Connection {
+			emitter,
+			uid,
+			connected,
+		}
+	}
+}
+
+/// Wait for the next event, following the emitter through config reloads that recreate it.
+async fn next_event(
+	receiver: &mut Option<broadcast::Receiver<ConnectionEvent>>,
+	resubscribe: impl Fn() -> Option<broadcast::Receiver<ConnectionEvent>>,
+) -> ConnectionEvent {
+	loop {
+		let Some(events) = receiver else {
+			return std::future::pending().await;
+		};
+		match events.recv().await {
+			Ok(event) => return event,
+			Err(RecvError::Lagged(_)) => continue,
+			Err(RecvError::Closed) => *receiver = resubscribe(),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::latency::{Bucket, HistogramSnapshot};
+	use std::process;
+
+	fn round_trip<T: PartialEq + std::fmt::Debug + Serialize + for<'de> Deserialize<'de>>(
+		value: T,
+	) {
+		let json = serde_json::to_string(&value).unwrap();
+		assert_eq!(serde_json::from_str::<T>(&json).unwrap(), value, "{json}");
+	}
+
+	#[test]
+	fn requests_round_trip() {
+		let rules: RemapRules = toml::from_str("[keys]\nCaps_Lock = \"Escape\"").unwrap();
+		for request in [
+			Request::ReceiversInfo,
+			Request::Focus {
+				emitter: EmitterKind::Keyboard,
+				uid: Some("4".into()),
+			},
+			Request::Focus {
+				emitter: EmitterKind::Mouse,
+				uid: None,
+			},
+			Request::SetName {
+				emitter: EmitterKind::Mouse,
+				uid: "4".into(),
+				name: "terminal".into(),
+			},
+			Request::Grab,
+			Request::Ungrab,
+			Request::Inject {
+				event: InputEvent::Key {
+					key: 30,
+					pressed: true,
+				},
+			},
+			Request::ReloadKeymap,
+			Request::SetKeymap {
+				uid: "4".into(),
+				layout: Some("de(nodeadkeys)".into()),
+			},
+			Request::SetRemap {
+				uid: "4".into(),
+				rules: Some(rules),
+			},
+			Request::Subscribe,
+			Request::Latency { reset: true },
+		] {
+			round_trip(request);
+		}
+	}
+
+	#[test]
+	fn missing_request_fields_default() {
+		let parse = |json| serde_json::from_str::<Request>(json).unwrap();
+		assert_eq!(
+			parse(r#"{"request": "focus", "emitter": "keyboard"}"#),
+			Request::Focus {
+				emitter: EmitterKind::Keyboard,
+				uid: None
+			}
+		);
+		assert_eq!(
+			parse(r#"{"request": "set_keymap", "uid": "4"}"#),
+			Request::SetKeymap {
+				uid: "4".into(),
+				layout: None
+			}
+		);
+		assert_eq!(
+			parse(r#"{"request": "set_remap", "uid": "4"}"#),
+			Request::SetRemap {
+				uid: "4".into(),
+				rules: None
+			}
+		);
+		assert_eq!(
+			parse(r#"{"request": "latency"}"#),
+			Request::Latency { reset: false }
+		);
+	}
+
+	#[test]
+	fn responses_round_trip() {
+		let histogram = HistogramSnapshot {
+			count: 2,
+			mean_micros: 150,
+			max_micros: 200,
+			buckets: vec![
+				Bucket {
+					le_micros: Some(100),
+					count: 1,
+				},
+				Bucket {
+					le_micros: None,
+					count: 1,
+				},
+			],
+		};
+		let latency = LatencySnapshot {
+			key: histogram.clone(),
+			motion: histogram.clone(),
+			button: histogram.clone(),
+			scroll: histogram,
+		};
+		let json = serde_json::to_value(Response::Latency(latency.clone())).unwrap();
+		assert_eq!(json["response"], "latency");
+		assert_eq!(json["key"]["max_micros"], 200);
+
+		for response in [
+			Response::Ok,
+			Response::error("nope"),
+			Response::ReceiversInfo {
+				keyboard: Some(EmitterInfo {
+					receivers: vec!["4".into()],
+					names: [("4".into(), "receiver 1".into())].into(),
+					focus: Some("4".into()),
+				}),
+				mouse: None,
+			},
+			Response::Connection {
+				emitter: EmitterKind::Keyboard,
+				uid: "5".into(),
+				connected: true,
+			},
+			Response::Latency(latency),
+		] {
+			round_trip(response);
+		}
+	}
+
+	#[tokio::test]
+	async fn socket_is_removed_once_nothing_listens() {
+		let path = env::temp_dir().join(format!("manifold-test-{}.sock", process::id()));
+		let listener = Listener::bind(path.clone()).await.unwrap();
+
+		let accepted = listener.listener.accept();
+		let (sent, accepted) = tokio::join!(send(path.clone(), r#"{"request": "grab"}"#), async {
+			let (stream, _) = accepted.await.unwrap();
+			let (reader, mut writer) = stream.into_split();
+			let line = BufReader::new(reader).lines().next_line().await.unwrap();
+			writer.write_all(b"{\"response\": \"ok\"}\n").await.unwrap();
+			line
+		});
+		sent.unwrap();
+		assert_eq!(accepted.as_deref(), Some(r#"{"request": "grab"}"#));
+
+		// a second manifold can't take the socket over, nor remove it
+		assert!(Listener::bind(path.clone()).await.is_err());
+		assert!(path.exists());
+		drop(listener);
+		assert!(!path.exists());
+	}
+}
@@ -0,0 +1,249 @@
+//! Dwell clicking: clicking for people who can move the pointer but not press buttons, configured by
+//! [`DwellConfig`].
+
+use crate::{config::DwellConfig, input::InputEvent};
+use serde::{Deserialize, Serialize};
+use std::{
+	fmt,
+	time::{Duration, Instant},
+};
+
+/// What happens once the pointer has rested long enough.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DwellMode {
+	#[default]
+	Left,
+	Right,
+	Double,
+	/// Press the left button on one dwell and release it on the next.
+	Drag,
+}
+impl DwellMode {
+	pub fn next(self) -> Self {
+		match self {
+			DwellMode::Left => DwellMode::Right,
+			DwellMode::Right => DwellMode::Double,
+			DwellMode::Double => DwellMode::Drag,
+			DwellMode::Drag => DwellMode::Left,
+		}
+	}
+}
+impl fmt::Display for DwellMode {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			DwellMode::Left => f.write_str("left click"),
+			DwellMode::Right => f.write_str("right click"),
+			DwellMode::Double => f.write_str("double click"),
+			DwellMode::Drag => f.write_str("drag"),
+		}
+	}
+}
+
+pub struct Dwell {
+	enabled: bool,
+	time: Duration,
+	threshold: f32,
+	mode: DwellMode,
+	/// How far the pointer has gone since it last counted as moving.
+	offset: [f32; 2],
+	/// When the pointer came to rest, if it moved since the last dwell.
+	resting_since: Option<Instant>,
+	dragging: bool,
+}
+impl Dwell {
+	pub fn new(config: &DwellConfig) -> Self {
+		Dwell {
+			enabled: config.enabled,
+			time: Duration::from_secs_f64(config.time),
+			threshold: config.threshold,
+			mode: config.mode,
+			offset: [0.0; 2],
+			resting_since: None,
+			dragging: false,
+		}
+	}
+
+	pub fn mode(&self) -> DwellMode {
+		self.mode
+	}
+	/// Switch modes, returning the button to let go of if it was dragging.
+	pub fn set_mode(&mut self, mode: DwellMode) -> Option<InputEvent> {
+		self.mode = mode;
+		self.resting_since = None;
+		self.release()
+	}
+
+	/// Count pointer motion, which restarts the wait once it goes past the threshold.
+	pub fn motion(&mut self, delta: [f32; 2], now: Instant) {
+		if !self.enabled {
+			return;
+		}
+		self.offset = [self.offset[0] + delta[0], self.offset[1] + delta[1]];
+		if self.offset[0].hypot(self.offset[1]) > self.threshold {
+			self.offset = [0.0; 2];
+			self.resting_since = Some(now);
+		}
+	}
+
+	/// A real button was used, so there's nothing to dwell for until the pointer moves again.
+	pub fn cancel(&mut self) {
+		self.resting_since = None;
+	}
+
+	/// The clicks to send if the pointer has rested for long enough.
+	pub fn tick(&mut self, now: Instant) -> Vec<InputEvent> {
+		if self
+			.resting_since
+			.is_none_or(|since| now < since + self.time)
+		{
+			return Vec::new();
+		}
+		self.resting_since = None;
+		let click = |button, pressed| InputEvent::Button { button, pressed };
+		let left = input_event_codes::BTN_LEFT!();
+		match self.mode {
+			DwellMode::Left => vec![click(left, true), click(left, false)],
+			DwellMode::Right => {
+				let right = input_event_codes::BTN_RIGHT!();
+				vec![click(right, true), click(right, false)]
+			}
+			DwellMode::Double => vec![
+				click(left, true),
+				click(left, false),
+				click(left, true),
+				click(left, false),
+			],
+			DwellMode::Drag => {
+				self.dragging = !self.dragging;
+				vec![click(left, self.dragging)]
+			}
+		}
+	}
+
+	/// When the pointer came to rest and how long it has to stay there, if it's waiting to dwell.
+	pub fn wait(&self) -> Option<(Instant, Duration)> {
+		Some((self.resting_since?, self.time))
+	}
+
+	/// Let go of the button if it's in the middle of a drag.
+	pub fn release(&mut self) -> Option<InputEvent> {
+		std::mem::take(&mut self.dragging).then_some(InputEvent::Button {
+			button: input_event_codes::BTN_LEFT!(),
+			pressed: false,
+		})
+	}
+}
+
+/// How far along a [`Dwell::wait`] is at `now`, from 0 to 1.
+pub fn wait_progress((since, time): (Instant, Duration), now: Instant) -> f32 {
+	let waited = now.saturating_duration_since(since).as_secs_f32();
+	(waited / time.as_secs_f32()).min(1.0)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use input_event_codes::{BTN_LEFT, BTN_RIGHT};
+
+	const TIME: Duration = Duration::from_secs(1);
+
+	fn dwell(mode: DwellMode) -> Dwell {
+		Dwell::new(&DwellConfig {
+			enabled: true,
+			time: TIME.as_secs_f64(),
+			threshold: 5.0,
+			mode,
+			..Default::default()
+		})
+	}
+	fn click(button: u32) -> [InputEvent; 2] {
+		[
+			InputEvent::Button {
+				button,
+				pressed: true,
+			},
+			InputEvent::Button {
+				button,
+				pressed: false,
+			},
+		]
+	}
+	/// Move the pointer past the threshold at `now` and rest until the dwell clicks.
+	fn rest(dwell: &mut Dwell, now: Instant) -> Vec<InputEvent> {
+		dwell.motion([10.0, 0.0], now);
+		dwell.tick(now + TIME)
+	}
+
+	#[test]
+	fn waits_once_moved_past_the_threshold() {
+		let mut dwell = dwell(DwellMode::Left);
+		let start = Instant::now();
+		dwell.motion([3.0, 0.0], start);
+		assert_eq!(dwell.wait(), None);
+		dwell.motion([0.0, 5.0], start);
+		assert_eq!(dwell.wait(), Some((start, TIME)));
+
+		// drifting within the threshold keeps waiting, moving past it starts over
+		let later = start + TIME / 2;
+		dwell.motion([4.0, 0.0], later);
+		assert_eq!(dwell.tick(start + TIME), click(BTN_LEFT!()));
+		dwell.motion([10.0, 0.0], start);
+		dwell.motion([10.0, 0.0], later);
+		assert_eq!(dwell.tick(start + TIME), []);
+		assert_eq!(dwell.tick(later + TIME), click(BTN_LEFT!()));
+		assert_eq!(dwell.tick(later + TIME * 2), []);
+	}
+
+	#[test]
+	fn cancelling_skips_the_click_until_the_next_motion() {
+		let mut dwell = dwell(DwellMode::Left);
+		let now = Instant::now();
+		dwell.motion([10.0, 0.0], now);
+		dwell.cancel();
+		assert_eq!(dwell.wait(), None);
+		assert_eq!(dwell.tick(now + TIME), []);
+		assert_eq!(rest(&mut dwell, now), click(BTN_LEFT!()));
+	}
+
+	#[test]
+	fn each_mode_clicks_its_own_way() {
+		let now = Instant::now();
+		assert_eq!(rest(&mut dwell(DwellMode::Right), now), click(BTN_RIGHT!()));
+		assert_eq!(
+			rest(&mut dwell(DwellMode::Double), now),
+			[click(BTN_LEFT!()), click(BTN_LEFT!())].concat()
+		);
+
+		let mut drag = dwell(DwellMode::Drag);
+		let left = |pressed| InputEvent::Button {
+			button: BTN_LEFT!(),
+			pressed,
+		};
+		assert_eq!(rest(&mut drag, now), [left(true)]);
+		assert_eq!(rest(&mut drag, now), [left(false)]);
+		assert_eq!(rest(&mut drag, now), [left(true)]);
+		// switching modes mid-drag lets go of the button
+		assert_eq!(drag.set_mode(DwellMode::Left), Some(left(false)));
+		assert_eq!(drag.release(), None);
+	}
+
+	#[test]
+	fn cycling_goes_through_every_mode() {
+		let mut mode = DwellMode::Left;
+		let mut modes = Vec::new();
+		for _ in 0..4 {
+			mode = mode.next();
+			modes.push(mode);
+		}
+		assert_eq!(
+			modes,
+			[
+				DwellMode::Right,
+				DwellMode::Double,
+				DwellMode::Drag,
+				DwellMode::Left
+			]
+		);
+	}
+}
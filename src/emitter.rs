@@ -1,68 +1,422 @@
+use crate::config::EmitterConfig;
+use color::{rgba, Rgba};
+use color_eyre::eyre::Result;
 use mint::Vector3;
+use parking_lot::Mutex;
 use stardust_xr_fusion::{
 	client::FrameInfo,
 	core::values::Transform,
-	drawable::{Model, ResourceID},
+	data::PulseSender,
+	drawable::{
+		Alignment, LinePoint, Lines, MaterialParameter, Model, ModelPart, ResourceID, Text,
+		TextStyle,
+	},
 	fields::BoxField,
+	node::NodeType,
 	spatial::Spatial,
 };
 use stardust_xr_molecules::{GrabData, Grabbable};
+use std::{
+	collections::BTreeMap,
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc,
+	},
+	time::{Duration, Instant},
+};
+
+/// Where a receiver's label sits, above its end of the line.
+const LABEL_OFFSET: [f32; 3] = [0.0, 0.01, 0.0];
+const LABEL_HEIGHT: f32 = 0.006;
+/// How much of the activity pulse fades per second.
+const PULSE_FADE: f32 = 4.0;
+/// The focused receiver's line is this much thicker, the others fade to this much of their color.
+const FOCUSED_THICKNESS: f32 = 2.0;
+const UNFOCUSED_BRIGHTNESS: f32 = 0.35;
+/// How often receiver lines are pointed at a resting emitter again, in case a receiver moved.
+const LINE_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A pulse receiver connecting to or disconnecting from an emitter, identified by its UID.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionEvent {
+	Connected(String),
+	Disconnected(String),
+}
 
 pub trait Emittable {
 	const SIZE: [f32; 3];
 	const EMIT_POINT: [f32; 3];
+	const COLOR: Rgba<f32>;
+	/// Name of the node in the model whose material gets tinted.
+	const MODEL_PART: &'static str;
 	fn model_resource() -> ResourceID;
-	fn update(&mut self, info: FrameInfo);
+	/// Called every frame, `moving` is whether the emitter is being moved.
+	fn update(&mut self, info: FrameInfo, moving: bool);
+	/// Color to tint the model with, to show what state the emitter is in.
+	fn tint(&self) -> Option<Rgba<f32>> {
+		None
+	}
+	/// Apply the parts of the config that don't need the emitter to be recreated.
+	fn configure(&mut self, config: &EmitterConfig);
+}
+
+/// Where an emitter was left relative to the client's root, kept up to date as it's moved so it
+/// can be put back in the same place when it's created again.
+#[derive(Debug, Default, Clone)]
+pub struct Placement(Arc<Mutex<Option<Transform>>>);
+impl Placement {
+	pub fn get(&self) -> Option<Transform> {
+		*self.0.lock()
+	}
+	pub fn set(&self, transform: Transform) {
+		*self.0.lock() = Some(transform);
+	}
+}
+
+/// Names given to an emitter's receivers over the control socket, by the number each receiver is
+/// labelled with until it's named. Manifold can't see what a receiver is, so these are the only names
+/// there are. A receiver gets the lowest number that's free when it connects, so one that comes back,
+/// alone or after a reconnect to the server, gets its number and name back if the receivers before it
+/// did too. Kept across reconnects like a [`Placement`].
+#[derive(Debug, Default, Clone)]
+pub struct ReceiverNames(Arc<Mutex<BTreeMap<usize, String>>>);
+impl ReceiverNames {
+	/// What the receiver with this number is labelled: the name it was given, or `receiver N`.
+	pub fn label(&self, number: usize) -> String {
+		self.0
+			.lock()
+			.get(&number)
+			.cloned()
+			.unwrap_or_else(|| format!("receiver {number}"))
+	}
+	pub fn set(&self, number: usize, name: &str) {
+		self.0.lock().insert(number, name.to_string());
+	}
+}
+
+/// The number to give a receiver that just connected, the lowest one the others don't have.
+pub(crate) fn receiver_number(taken: impl Iterator<Item = usize>) -> usize {
+	let taken: Vec<usize> = taken.collect();
+	(1..).find(|number| !taken.contains(number)).unwrap()
 }
 
 #[allow(dead_code)]
 pub struct Emitter<E: Emittable> {
+	root: Spatial,
 	field: BoxField,
 	grabbable: Grabbable,
 	model: Model,
+	model_part: ModelPart,
+	tint: Option<Rgba<f32>>,
+	placement: Placement,
+	/// Whether it was being moved last frame.
+	moving: bool,
 	pub contained: E,
 }
 impl<E: Emittable> Emitter<E> {
-	pub fn new<F>(spatial_parent: &Spatial, contain_fn: F) -> Self
+	/// Create the emitter where `placement` says it was left, or in front of `spatial_parent`'s
+	/// origin the first time.
+	pub fn new<F>(
+		spatial_parent: &Spatial,
+		config: &EmitterConfig,
+		placement: Placement,
+		contain_fn: F,
+	) -> Result<Self>
 	where
-		F: FnOnce(&Spatial) -> E,
+		F: FnOnce(&Spatial) -> Result<E>,
 	{
-		let field =
-			BoxField::create(spatial_parent, Transform::default(), Vector3::from(E::SIZE)).unwrap();
-		let grabbable = Grabbable::create(
+		let field = BoxField::create(
 			spatial_parent,
 			Transform::default(),
+			Vector3::from(config.size::<E>()),
+		)?;
+		let grabbable = Grabbable::create(
+			spatial_parent,
+			placement
+				.get()
+				.unwrap_or_else(|| Transform::from_position(config.emit_point::<E>().map(|n| -n))),
 			&field,
 			GrabData {
-				max_distance: 0.1,
+				max_distance: config.max_distance,
 				..Default::default()
 			},
-		)
-		.unwrap();
-		grabbable
-			.content_parent()
-			.set_position(None, E::EMIT_POINT.map(|n| -n))
-			.unwrap();
+		)?;
 		let model = Model::create(
 			grabbable.content_parent(),
 			Transform::default(),
 			&E::model_resource(),
-		)
-		.unwrap();
-		let contained = contain_fn(grabbable.content_parent());
-		field
-			.set_spatial_parent(grabbable.content_parent())
-			.unwrap();
-		Emitter {
+		)?;
+		let model_part = model.model_part(E::MODEL_PART)?;
+		let contained = contain_fn(grabbable.content_parent())?;
+		field.set_spatial_parent(grabbable.content_parent())?;
+		Ok(Emitter {
+			root: spatial_parent.alias(),
 			field,
 			grabbable,
 			model,
+			model_part,
+			tint: None,
+			placement,
+			moving: false,
 			contained,
-		}
+		})
 	}
 
 	pub fn frame(&mut self, info: FrameInfo) {
-		let _ = self.grabbable.update(&info);
-		self.contained.update(info);
+		if let Err(e) = self.grabbable.update(&info) {
+			eprintln!("Couldn't update grabbing the {}: {e}", E::MODEL_PART);
+		}
+		let grab = self.grabbable.grab_action();
+		let moving = grab.actor_acting()
+			|| grab.actor_stopped()
+			|| self.grabbable.linear_velocity().is_some()
+			|| self.grabbable.angular_velocity().is_some();
+		if self.moving && !moving {
+			self.save_placement();
+		}
+		self.moving = moving;
+		self.contained.update(info, moving);
+
+		let tint = self.contained.tint();
+		if tint != self.tint {
+			self.tint = tint;
+			let color = tint.unwrap_or(rgba!(1.0, 1.0, 1.0, 1.0));
+			if let Err(e) = self.model_part.set_material_parameter(
+				"color",
+				MaterialParameter::Color([color.c.r, color.c.g, color.c.b, color.a]),
+			) {
+				eprintln!("Couldn't tint the {}: {e}", E::MODEL_PART);
+			}
+		}
+	}
+
+	/// Remember where the emitter was left, once the server says.
+	fn save_placement(&self) {
+		let query = match self
+			.grabbable
+			.content_parent()
+			.get_position_rotation_scale(&self.root)
+		{
+			Ok(query) => query,
+			Err(e) => {
+				eprintln!("Couldn't find where the {} was left: {e}", E::MODEL_PART);
+				return;
+			}
+		};
+		let placement = self.placement.clone();
+		tokio::task::spawn(async move {
+			match query.await {
+				Ok((position, rotation, _)) => {
+					placement.set(Transform::from_position_rotation(position, rotation))
+				}
+				Err(e) => eprintln!("Couldn't find where the {} was left: {e}", E::MODEL_PART),
+			}
+		});
+	}
+}
+
+/// The line from an emitter to a connected receiver, labelled at the receiver's end and flashing
+/// when input goes down it.
+pub struct ReceiverLine {
+	lines: Arc<Lines>,
+	label: Text,
+	name: String,
+	/// Where the emitter end was and how the line looked when it was last drawn.
+	shown: Arc<Mutex<Option<Drawn>>>,
+	/// 1 right after input was sent to the receiver, fading to 0.
+	activity: f32,
+}
+impl ReceiverLine {
+	pub fn create(receiver: &Spatial, name: &str) -> Result<Self> {
+		Ok(ReceiverLine {
+			lines: Arc::new(Lines::create(receiver, Transform::default(), &[], false)?),
+			label: Text::create(
+				receiver,
+				Transform::from_position(LABEL_OFFSET),
+				name,
+				TextStyle {
+					character_height: LABEL_HEIGHT,
+					text_align: Alignment::Center.into(),
+					..Default::default()
+				},
+			)?,
+			name: name.to_string(),
+			shown: Arc::new(Mutex::new(None)),
+			activity: 0.0,
+		})
+	}
+
+	pub fn name(&self) -> &str {
+		&self.name
+	}
+	pub fn set_name(&mut self, name: &str) {
+		if name != self.name {
+			if let Err(e) = self.label.set_text(name) {
+				eprintln!("Couldn't relabel the line to {}: {e}", self.name);
+			}
+			self.name = name.to_string();
+		}
+	}
+
+	/// Flash the line, input was just sent down it.
+	pub fn pulse(&mut self) {
+		self.activity = 1.0;
+	}
+
+	/// Fade the activity pulse and work out how the line should look.
+	fn style(
+		&mut self,
+		color: Rgba<f32>,
+		thickness: f32,
+		focused: Option<bool>,
+		delta: f32,
+	) -> Style {
+		self.activity = (self.activity - delta * PULSE_FADE).max(0.0);
+		let (thickness, brightness) = match focused {
+			Some(true) => (thickness * FOCUSED_THICKNESS, 1.0),
+			Some(false) => (thickness, UNFOCUSED_BRIGHTNESS),
+			None => (thickness, 1.0),
+		};
+		// dimmed by focus, then brightened towards white by activity
+		let channel = |c: f32| {
+			let c = c * brightness;
+			c + (1.0 - c) * self.activity
+		};
+		let color = rgba!(
+			channel(color.c.r),
+			channel(color.c.g),
+			channel(color.c.b),
+			color.a
+		);
+		(thickness, color)
+	}
+}
+
+/// A line's thickness and color.
+type Style = (f32, Rgba<f32>);
+/// Where a line's emitter end is and its style.
+type Drawn = (Vector3<f32>, Style);
+
+/// Keeps an emitter's receiver lines pointing at it. Where the emitter is relative to the receivers
+/// is only asked while the emitter moves, for receivers that just connected, and every
+/// [`LINE_REFRESH_INTERVAL`] in case a receiver moved on its own, all at once in a single task.
+/// Lines whose style changes in between are redrawn from where the emitter was last seen.
+pub struct ReceiverLines {
+	in_flight: Arc<AtomicBool>,
+	/// Whether every line needs asking about, not just the new ones.
+	stale: bool,
+	last_query: Instant,
+}
+impl Default for ReceiverLines {
+	fn default() -> Self {
+		ReceiverLines {
+			in_flight: Arc::default(),
+			stale: true,
+			last_query: Instant::now(),
+		}
+	}
+}
+impl ReceiverLines {
+	/// Update `lines`, each with whether it goes to the receiver input is limited to, or `None` if it
+	/// isn't limited. `moving` is whether the emitter is being moved this frame.
+	pub fn update<'a>(
+		&mut self,
+		sender: &PulseSender,
+		lines: impl IntoIterator<Item = (&'a mut ReceiverLine, Option<bool>)>,
+		color: Rgba<f32>,
+		thickness: f32,
+		delta: f32,
+		moving: bool,
+	) {
+		self.stale |= moving || self.last_query.elapsed() >= LINE_REFRESH_INTERVAL;
+		let styled: Vec<_> = lines
+			.into_iter()
+			.map(|(line, focused)| {
+				let style = line.style(color, thickness, focused, delta);
+				let position = line.shown.lock().map(|(position, _)| position);
+				(line.lines.clone(), line.shown.clone(), style, position)
+			})
+			.collect();
+		let stale = self.stale;
+		let querying = styled
+			.iter()
+			.any(|(_, _, _, position)| stale || position.is_none())
+			&& !self.in_flight.swap(true, Ordering::AcqRel);
+
+		// send all the queries before waiting on any of them
+		let mut queries = Vec::new();
+		for (lines, shown, style, position) in styled {
+			match position {
+				Some(position) if !(querying && stale) => redraw(&lines, &shown, position, style),
+				_ if querying => match sender.get_position_rotation_scale(&lines) {
+					Ok(query) => queries.push((lines, shown, style, query)),
+					Err(e) => eprintln!("Couldn't find where a receiver is: {e}"),
+				},
+				_ => (),
+			}
+		}
+		if !querying {
+			return;
+		}
+		if stale {
+			self.stale = false;
+			self.last_query = Instant::now();
+		}
+		let in_flight = self.in_flight.clone();
+		tokio::task::spawn(async move {
+			for (lines, shown, style, query) in queries {
+				match query.await {
+					Ok((position, _, _)) => redraw(&lines, &shown, position, style),
+					Err(e) => eprintln!("Couldn't find where a receiver is: {e}"),
+				}
+			}
+			in_flight.store(false, Ordering::Release);
+		});
+	}
+}
+
+/// Draw a line from the emitter at `position` to its receiver, unless it already looks like that.
+fn redraw(lines: &Lines, shown: &Mutex<Option<Drawn>>, position: Vector3<f32>, style: Style) {
+	let drawn = (position, style);
+	let mut shown = shown.lock();
+	if *shown == Some(drawn) {
+		return;
+	}
+	// kept even if drawing fails, so the same error isn't logged every frame
+	*shown = Some(drawn);
+	let (thickness, color) = style;
+	let updated = lines.update_points(&[
+		LinePoint {
+			point: Vector3::from([0.0; 3]),
+			thickness,
+			color,
+		},
+		LinePoint {
+			point: position,
+			thickness,
+			color,
+		},
+	]);
+	if let Err(e) = updated {
+		eprintln!("Couldn't redraw the line to a receiver: {e}");
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn receivers_get_the_lowest_free_number_and_its_name() {
+		assert_eq!(receiver_number([].into_iter()), 1);
+		assert_eq!(receiver_number([1, 2, 4].into_iter()), 3);
+		assert_eq!(receiver_number([2, 3].into_iter()), 1);
+
+		let names = ReceiverNames::default();
+		names.set(2, "terminal");
+		// a new manifold gets the same names
+		let names = names.clone();
+		assert_eq!(names.label(1), "receiver 1");
+		assert_eq!(names.label(2), "terminal");
 	}
 }
@@ -0,0 +1,127 @@
+//! Window-less input straight from `/dev/input/event*`, for when there's no desktop to take input from.
+//!
+//! Devices aren't grabbed exclusively, so anything else reading them still sees the same input.
+
+use crate::{
+	commands::{Command, CommandSender},
+	input::InputEvent,
+};
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use std::{
+	fs::{self, File},
+	io::Read,
+	path::PathBuf,
+	thread,
+	time::Instant,
+};
+
+/// Size of `struct input_event`: a `timeval` followed by a u16 type, u16 code and i32 value.
+const EVENT_SIZE: usize = std::mem::size_of::<usize>() * 2 + 8;
+
+/// Every event device we're allowed to read.
+pub fn devices() -> Vec<PathBuf> {
+	let Ok(entries) = fs::read_dir("/dev/input") else {
+		return Vec::new();
+	};
+	entries
+		.filter_map(|entry| entry.ok().map(|e| e.path()))
+		.filter(|path| {
+			path.file_name()
+				.and_then(|n| n.to_str())
+				.is_some_and(|n| n.starts_with("event"))
+		})
+		.filter(|path| File::open(path).is_ok())
+		.collect()
+}
+
+/// Start a thread per device that forwards its keys, buttons, motion and scrolling to the emitters,
+/// through `commands` so it reaches whichever emitters exist after a reconnect.
+pub fn spawn(devices: &[PathBuf], commands: CommandSender) -> Result<()> {
+	if devices.is_empty() {
+		return Err(eyre!(
+			"No readable input devices, are you in the input group?"
+		));
+	}
+	for path in devices {
+		let file =
+			File::open(path).wrap_err_with(|| format!("Couldn't open {}", path.display()))?;
+		let name = format!("evdev {}", path.display());
+		let commands = commands.clone();
+		let path = path.clone();
+		thread::Builder::new().name(name).spawn(move || {
+			if let Err(e) = read_device(file, &commands) {
+				eprintln!("Stopped reading {}: {e}", path.display());
+			}
+		})?;
+	}
+	Ok(())
+}
+
+fn read_device(mut file: File, commands: &CommandSender) -> Result<()> {
+	let mut buffer = [0; EVENT_SIZE];
+	let mut motion = [0.0_f32; 2];
+	let mut steps = [0.0_f32; 2];
+	loop {
+		file.read_exact(&mut buffer)?;
+		let data = &buffer[EVENT_SIZE - 8..];
+		let received = Instant::now();
+		let kind = u16::from_ne_bytes([data[0], data[1]]);
+		let code = u16::from_ne_bytes([data[2], data[3]]);
+		let value = i32::from_ne_bytes([data[4], data[5], data[6], data[7]]);
+
+		let event = match kind {
+			input_event_codes::EV_KEY!() => translate_key(code as u32, value),
+			input_event_codes::EV_REL!() => {
+				match code {
+					input_event_codes::REL_X!() => motion[0] += value as f32,
+					input_event_codes::REL_Y!() => motion[1] += value as f32,
+					input_event_codes::REL_HWHEEL!() => steps[0] += value as f32,
+					input_event_codes::REL_WHEEL!() => steps[1] -= value as f32,
+					_ => (),
+				}
+				None
+			}
+			input_event_codes::EV_SYN!() => {
+				if motion != [0.0; 2] {
+					commands.send(Command::Input(
+						InputEvent::Motion { delta: motion },
+						received,
+					));
+				}
+				if steps != [0.0; 2] {
+					let scroll = InputEvent::Scroll {
+						distance: None,
+						steps: Some(steps),
+					};
+					commands.send(Command::Input(scroll, received));
+				}
+				motion = [0.0; 2];
+				steps = [0.0; 2];
+				None
+			}
+			_ => None,
+		};
+		if let Some(event) = event {
+			commands.send(Command::Input(event, received));
+		}
+	}
+}
+
+fn translate_key(code: u32, value: i32) -> Option<InputEvent> {
+	// 2 is the kernel's own key repeat
+	if value == 2 {
+		return None;
+	}
+	let pressed = value == 1;
+	if code < input_event_codes::BTN_MISC!() {
+		Some(InputEvent::Key { key: code, pressed })
+	} else if (input_event_codes::BTN_MOUSE!()..input_event_codes::BTN_JOYSTICK!()).contains(&code)
+	{
+		Some(InputEvent::Button {
+			button: code,
+			pressed,
+		})
+	} else {
+		None
+	}
+}
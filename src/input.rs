@@ -0,0 +1,66 @@
+use crate::{keyboard::Keyboard, latency::LATENCY, mouse::Mouse};
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+
+/// A single input event headed for the emitters, independent of where it came from.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum InputEvent {
+	/// An evdev keycode going down or up.
+	Key { key: u32, pressed: bool },
+	/// Relative pointer motion in pixels.
+	Motion { delta: [f32; 2] },
+	/// An evdev button code such as `BTN_LEFT` going down or up.
+	Button { button: u32, pressed: bool },
+	Scroll {
+		#[serde(default)]
+		distance: Option<[f32; 2]>,
+		#[serde(default)]
+		steps: Option<[f32; 2]>,
+	},
+}
+impl InputEvent {
+	/// Send this event to whichever emitter it's meant for, if that emitter exists. Keys go to mouse
+	/// keys first when it's on.
+	pub fn send(&self, keyboard: Option<&Keyboard>, mouse: Option<&Mouse>) {
+		self.send_received_at(keyboard, mouse, Instant::now());
+	}
+
+	/// Like [`InputEvent::send`], for an event that came in at `received`. Motion is queued up to
+	/// be sent with the rest of the frame's, everything else goes out right away.
+	pub fn send_received_at(
+		&self,
+		keyboard: Option<&Keyboard>,
+		mouse: Option<&Mouse>,
+		received: Instant,
+	) {
+		match self {
+			InputEvent::Key { key, pressed } => {
+				if mouse.is_some_and(|mouse| mouse.lock_flushed().mouse_key(*key, *pressed)) {
+					return;
+				}
+				if let Some(keyboard) = keyboard {
+					keyboard.lock().send_key(*key, *pressed);
+					LATENCY.key.record_since(received);
+				}
+			}
+			InputEvent::Motion { delta } => {
+				if let Some(mouse) = mouse {
+					mouse.queue_motion(*delta, received);
+				}
+			}
+			InputEvent::Button { .. } => {
+				if let Some(mouse) = mouse {
+					mouse.lock_flushed().send_input(self);
+					LATENCY.button.record_since(received);
+				}
+			}
+			InputEvent::Scroll { .. } => {
+				if let Some(mouse) = mouse {
+					mouse.lock_flushed().send_input(self);
+					LATENCY.scroll.record_since(received);
+				}
+			}
+		}
+	}
+}
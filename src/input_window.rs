@@ -1,14 +1,30 @@
-use crate::{keyboard::Keyboard, mouse::Mouse};
-use color_eyre::eyre::Result;
-use mint::Vector2;
+use crate::{
+	binding::{is_modifier, keysym_for_scancode, KeyBinding, Modifiers},
+	commands::{Command, CommandSender},
+	config::{
+		Config, GrabConfig, GrabTrigger, KeymapConfig, MacroConfig, RepeatConfig, WindowConfig,
+	},
+	input::InputEvent,
+	keyboard_grab::KeyboardGrab,
+	leds::LockLeds,
+	mouse::MouseStatus,
+	remap::KeyRepeat,
+	script::{Recorder, ScriptStep},
+};
+use color_eyre::eyre::{eyre, Result};
 use softbuffer::GraphicsContext;
-use stardust_xr_fusion::client::Client;
-use std::{mem::ManuallyDrop, sync::Arc};
+use std::{
+	mem::ManuallyDrop,
+	path::PathBuf,
+	sync::Arc,
+	time::{Duration, Instant},
+};
+use tokio::sync::Notify;
 use winit::{
 	dpi::{LogicalPosition, PhysicalPosition, Size},
 	event::{
-		ElementState, Event, KeyboardInput, ModifiersState, MouseButton, MouseScrollDelta,
-		VirtualKeyCode, WindowEvent,
+		DeviceEvent, ElementState, Event, KeyboardInput, ModifiersState, MouseButton,
+		MouseScrollDelta, WindowEvent,
 	},
 	event_loop::EventLoop,
 	platform::x11::WindowExtX11,
@@ -18,28 +34,102 @@ use xcb::ffi::xcb_connection_t;
 use xkbcommon::xkb::{
 	self,
 	x11::{get_core_keyboard_device_id, keymap_new_from_device},
-	Keymap, KEYMAP_COMPILE_NO_FLAGS,
+	Keymap, Keysym, KEYMAP_COMPILE_NO_FLAGS,
 };
 
-const RADIUS: u32 = 8;
+/// How often the dwell progress under the cursor is redrawn while it fills up.
+const DWELL_REDRAW_INTERVAL: Duration = Duration::from_millis(33);
+const DWELL_BAR_GAP: u32 = 2;
+const DWELL_BAR_HEIGHT: u32 = 3;
+
+/// Sent to the input window's event loop from the Stardust side.
+pub enum InputWindowEvent {
+	/// The config file changed, the emitters may have been recreated along with it. Also sent after
+	/// connecting to the server, for the new emitters to get the keymap.
+	Reload(Box<Config>),
+	/// Connected to the Stardust server, or lost the connection and trying to get it back.
+	Connected(bool),
+	/// Grab or release the cursor and keyboard as if the bindings had been used.
+	SetGrab(bool),
+	/// Read the keymap again, from the config or from the X server if the config doesn't set one.
+	ReloadKeymap,
+	/// The lock LEDs of the focused receiver changed.
+	SetLeds(LockLeds),
+	/// Mouse keys or dwell clicking changed.
+	MouseStatus(MouseStatus),
+	/// Something went wrong on the Stardust side that the user should know about.
+	Error(String),
+}
+
 pub struct InputWindow {
-	stardust_client: Arc<Client>,
-	keyboard: Keyboard,
-	mouse: Mouse,
+	/// Notified when the window is closed, to quit manifold.
+	quit: Arc<Notify>,
+	commands: CommandSender,
+	/// Whether there's a Stardust server for the input to go to.
+	connected: bool,
+	/// The last status the mouse sent, `None` without a mouse.
+	mouse_status: Option<MouseStatus>,
+	/// Shown in the title until input is grabbed again.
+	error: Option<String>,
 	window: Window,
 	graphics_context: GraphicsContext,
 	cursor_position: Option<LogicalPosition<u32>>,
 	grabbed: bool,
+	keyboard_grab: KeyboardGrab,
 	modifiers: ModifiersState,
+	/// The keymap bindings go by, the first layout if the config lists several.
+	keymap: Keymap,
+	keymap_config: KeymapConfig,
+	/// Which of `keymap_config.layouts` the receivers have, with its keymap unless it's the first.
+	layout: usize,
+	layout_keymap: Option<Keymap>,
+	/// Whether the cursor can be warped back to the center (X11), otherwise it's locked in place
+	/// and motion comes from raw device events instead (Wayland).
+	warp_cursor: bool,
+	window_config: WindowConfig,
+	grab_config: GrabConfig,
+	last_input: Instant,
+	/// When the event being handled came in, to measure how long it takes to reach the receivers.
+	received: Instant,
+	/// Key that triggered a binding, so its release doesn't reach the receivers.
+	swallowed_key: Option<u32>,
+	/// Keys sent down to the receivers and still held, to drop the desktop's auto-repeat of them.
+	/// They're released before a macro plays so they don't mix in.
+	held_keys: Vec<u32>,
+	record_macro: Option<KeyBinding>,
+	macros: Vec<MacroConfig>,
+	mouse_keys_toggle: Option<KeyBinding>,
+	dwell_cycle_mode: Option<KeyBinding>,
+	/// Dwell progress last drawn under the cursor.
+	drawn_progress: Option<f32>,
+	mirror_leds: bool,
+	/// The focused receiver's lock LEDs, for the desktop to match while grabbed.
+	leds: LockLeds,
+	/// The desktop's lock keys from before mirroring began, to restore when it stops.
+	desktop_leds: Option<LockLeds>,
+	macro_state: MacroState,
+	repeat_config: RepeatConfig,
+	/// Where recorded macros get saved.
+	config_path: Option<PathBuf>,
+}
+
+#[derive(Default)]
+enum MacroState {
+	#[default]
+	Idle,
+	Recording(Recorder),
+	/// Recorded, waiting for the chord to trigger it with.
+	Binding(Vec<ScriptStep>),
 }
 impl InputWindow {
 	pub fn new(
-		event_loop: &EventLoop<()>,
-		stardust_client: Arc<Client>,
-		keyboard: Keyboard,
-		mouse: Mouse,
+		event_loop: &EventLoop<InputWindowEvent>,
+		quit: Arc<Notify>,
+		commands: CommandSender,
+		config: Config,
+		config_path: Option<PathBuf>,
 	) -> Result<Self> {
-		let size = Size::Logical([512, 512].into());
+		let size = Size::Logical(config.window.size.into());
 		let window = WindowBuilder::new()
 			.with_title("Manifold")
 			.with_min_inner_size(size)
@@ -48,72 +138,156 @@ impl InputWindow {
 			.with_resizable(false)
 			.build(event_loop)?;
 
-		let keymap = match window.xcb_connection() {
-			Some(raw_conn) => {
-				let connection = unsafe {
-					ManuallyDrop::new(xcb::Connection::from_raw_conn(
-						raw_conn as *mut xcb_connection_t,
-					)) // this memory is leaked, gotta find a fix!
-				};
-				keymap_new_from_device(
-					&xkb::Context::new(0),
-					&connection,
-					get_core_keyboard_device_id(&connection),
-					KEYMAP_COMPILE_NO_FLAGS,
-				)
-			}
-			None => Keymap::new_from_names(&xkb::Context::new(0), "", "", "", "", None, 0).unwrap(),
-		};
-		keyboard.lock().set_keymap(keymap);
+		let warp_cursor = window.xcb_connection().is_some();
+		let keymap = load_keymap(&window, &config.keymap)?;
+		commands.set_keymap(&keymap);
 
-		let graphics_context = unsafe { GraphicsContext::new(&window, &window) }.unwrap();
+		let graphics_context = unsafe { GraphicsContext::new(&window, &window) }
+			.map_err(|e| eyre!("Couldn't draw in the input window: {e}"))?;
 
 		let mut input_window = InputWindow {
-			stardust_client,
-			keyboard,
-			mouse,
+			quit,
+			commands,
+			connected: false,
+			mouse_status: None,
+			error: None,
 			window,
 			graphics_context,
 			cursor_position: None,
 			grabbed: true,
+			keyboard_grab: KeyboardGrab::Released,
 			modifiers: ModifiersState::empty(),
+			keymap,
+			keymap_config: config.keymap,
+			layout: 0,
+			layout_keymap: None,
+			warp_cursor,
+			window_config: config.window,
+			grab_config: config.grab,
+			last_input: Instant::now(),
+			received: Instant::now(),
+			swallowed_key: None,
+			held_keys: Vec::new(),
+			record_macro: config.record_macro,
+			macros: config.macros,
+			mouse_keys_toggle: config.mouse_keys.toggle,
+			dwell_cycle_mode: config.dwell.cycle_mode,
+			drawn_progress: None,
+			mirror_leds: config.mirror_leds,
+			leds: LockLeds::default(),
+			desktop_leds: None,
+			macro_state: MacroState::Idle,
+			repeat_config: config.repeat,
+			config_path,
 		};
+		input_window.apply_desktop_repeat();
 		input_window.set_grab(false);
 
 		Ok(input_window)
 	}
 
-	pub fn handle_event(&mut self, event: Event<()>) {
+	/// When the event loop needs to wake up next to release an idle grab or redraw the dwell progress.
+	pub fn next_deadline(&self) -> Option<Instant> {
+		let idle_release = self
+			.grab_config
+			.idle_timeout()
+			.filter(|_| self.grabbed)
+			.map(|timeout| self.last_input + timeout);
+		let redraw = self
+			.drawn_progress
+			.map(|_| Instant::now() + DWELL_REDRAW_INTERVAL);
+		idle_release.into_iter().chain(redraw).min()
+	}
+
+	pub fn handle_event(&mut self, event: Event<InputWindowEvent>) {
+		self.received = Instant::now();
+		if self
+			.next_deadline()
+			.is_some_and(|deadline| deadline <= Instant::now())
+		{
+			self.set_grab(false);
+		}
+		if self.grabbed && self.dwell_progress() != self.drawn_progress {
+			self.window.request_redraw();
+		}
+
 		match event {
 			Event::WindowEvent { event, .. } => self.handle_window_event(event),
-			Event::RedrawRequested(_window_id) => {
-				let window_size = self.window.inner_size();
-				let buffer_len = window_size.width * window_size.height;
-				let mut buffer = vec![0; buffer_len as usize];
-				if let Some(mouse_position) = self.cursor_position {
-					for x in (mouse_position.x.checked_sub(RADIUS).unwrap_or(0))
-						..(mouse_position.x.checked_add(RADIUS).unwrap_or(u32::MAX))
-					{
-						for y in (mouse_position.y.checked_sub(RADIUS).unwrap_or(0))
-							..(mouse_position.y.checked_add(RADIUS).unwrap_or(u32::MAX))
-						{
-							if let Some(pixel) =
-								buffer.get_mut((x + (y * window_size.width)) as usize)
-							{
-								*pixel = u32::MAX;
-							}
-						}
+			Event::DeviceEvent {
+				event: DeviceEvent::MouseMotion { delta },
+				..
+			} if !self.warp_cursor => self.handle_raw_motion(delta),
+			Event::UserEvent(InputWindowEvent::Reload(config)) => self.reload(*config),
+			Event::UserEvent(InputWindowEvent::SetGrab(grab)) => self.set_grab(grab),
+			Event::UserEvent(InputWindowEvent::ReloadKeymap) => self.reload_keymap(),
+			Event::UserEvent(InputWindowEvent::SetLeds(leds)) => {
+				self.leds = leds;
+				self.mirror_leds();
+			}
+			Event::UserEvent(InputWindowEvent::Connected(connected)) => {
+				self.connected = connected;
+				if connected {
+					self.error = None;
+				} else {
+					self.mouse_status = None;
+				}
+				self.window.set_title(&self.window_title());
+			}
+			Event::UserEvent(InputWindowEvent::MouseStatus(status)) => {
+				self.mouse_status = Some(status);
+				self.window.set_title(&self.window_title());
+			}
+			Event::UserEvent(InputWindowEvent::Error(error)) => {
+				self.error = Some(error);
+				self.window.set_title(&self.window_title());
+			}
+			Event::RedrawRequested(_window_id) => self.redraw(),
+			_ => (),
+		}
+	}
+
+	fn redraw(&mut self) {
+		let window_size = self.window.inner_size();
+		let buffer_len = window_size.width * window_size.height;
+		let mut buffer = vec![0; buffer_len as usize];
+		let mut fill = |xs: std::ops::Range<u32>, ys: std::ops::Range<u32>| {
+			for x in xs {
+				for y in ys.clone() {
+					if let Some(pixel) = buffer.get_mut((x + (y * window_size.width)) as usize) {
+						*pixel = u32::MAX;
 					}
 				}
+			}
+		};
+		self.drawn_progress = None;
+		if let Some(mouse_position) = self.cursor_position {
+			let radius = self.window_config.cursor_radius;
+			fill(
+				mouse_position.x.saturating_sub(radius)..mouse_position.x.saturating_add(radius),
+				mouse_position.y.saturating_sub(radius)..mouse_position.y.saturating_add(radius),
+			);
 
-				self.graphics_context.set_buffer(
-					&buffer,
-					window_size.width as u16,
-					window_size.height as u16,
+			// a bar under the cursor filling up until the dwell click
+			self.drawn_progress = self.dwell_progress();
+			if let Some(progress) = self.drawn_progress {
+				let left = mouse_position.x.saturating_sub(radius * 2);
+				let top = mouse_position.y.saturating_add(radius + DWELL_BAR_GAP);
+				fill(
+					left..left + (radius as f32 * 4.0 * progress) as u32,
+					top..top + DWELL_BAR_HEIGHT,
 				);
 			}
-			_ => (),
 		}
+
+		self.graphics_context.set_buffer(
+			&buffer,
+			window_size.width as u16,
+			window_size.height as u16,
+		);
+	}
+
+	fn dwell_progress(&self) -> Option<f32> {
+		self.mouse_status?.dwell_progress(Instant::now())
 	}
 
 	fn handle_window_event(&mut self, event: WindowEvent) {
@@ -123,8 +297,9 @@ impl InputWindow {
 			WindowEvent::CursorMoved { position, .. } => self.handle_mouse_move(position),
 			WindowEvent::KeyboardInput { input, .. } => self.handle_keyboard_input(input),
 			WindowEvent::ModifiersChanged(state) => self.modifiers = state,
-			WindowEvent::CloseRequested => self.stardust_client.stop_loop(),
-			WindowEvent::Destroyed => self.stardust_client.stop_loop(),
+			WindowEvent::Focused(focused) => self.handle_focus(focused),
+			WindowEvent::CloseRequested => self.quit.notify_one(),
+			WindowEvent::Destroyed => self.quit.notify_one(),
 			_ => (),
 		}
 	}
@@ -137,32 +312,47 @@ impl InputWindow {
 			None
 		};
 
-		if self.grabbed {
+		if self.grabbed && self.warp_cursor {
+			self.last_input = Instant::now();
 			let window_size = self.window.inner_size();
 			let cursor_position = position.to_logical::<f64>(self.window.scale_factor());
 			let center_position = LogicalPosition::new(
 				window_size.width as f64 / 2.0,
 				window_size.height as f64 / 2.0,
 			);
-			let cursor_delta = Vector2::from_slice(&[
-				(cursor_position.x - center_position.x) as f32,
-				(cursor_position.y - center_position.y) as f32,
-			]);
+			self.emit(InputEvent::Motion {
+				delta: [
+					(cursor_position.x - center_position.x) as f32,
+					(cursor_position.y - center_position.y) as f32,
+				],
+			});
 
-			self.mouse
-				.lock()
-				.send_event(Some(cursor_delta), None, None, None, None);
+			if let Err(e) = self.window.set_cursor_position(center_position) {
+				eprintln!("Couldn't warp the cursor back to the center: {e}");
+			}
+		}
+	}
 
-			self.window.set_cursor_position(center_position).unwrap();
+	fn handle_raw_motion(&mut self, (x, y): (f64, f64)) {
+		if !self.grabbed {
+			return;
 		}
+		self.last_input = Instant::now();
+		self.emit(InputEvent::Motion {
+			delta: [x as f32, y as f32],
+		});
 	}
 
 	fn handle_mouse_input(&mut self, state: ElementState, button: MouseButton) {
 		if !self.grabbed {
-			if state == ElementState::Released && button == MouseButton::Left {
+			if self.grab_config.trigger == GrabTrigger::Click
+				&& state == ElementState::Released
+				&& button == MouseButton::Left
+			{
 				self.set_grab(true);
 			}
 		} else {
+			self.last_input = Instant::now();
 			let button = match button {
 				MouseButton::Left => input_event_codes::BTN_LEFT!(),
 				MouseButton::Right => input_event_codes::BTN_RIGHT!(),
@@ -171,80 +361,551 @@ impl InputWindow {
 					return;
 				}
 			};
-			let (buttons_up, buttons_down) = match state {
-				ElementState::Pressed => (None, Some(vec![button])),
-				ElementState::Released => (Some(vec![button]), None),
-			};
-			self.mouse
-				.lock()
-				.send_event(None, None, None, buttons_up, buttons_down);
+			self.emit(InputEvent::Button {
+				button,
+				pressed: state == ElementState::Pressed,
+			});
 		}
 	}
 
 	fn handle_axis(&mut self, delta: MouseScrollDelta) {
 		if self.grabbed {
-			let (scroll_distance, scroll_steps) = match delta {
-				MouseScrollDelta::LineDelta(right, down) => {
-					(None, Some(Vector2::from([-right, -down])))
+			self.last_input = Instant::now();
+			let (distance, steps) = match delta {
+				MouseScrollDelta::LineDelta(right, down) => (None, Some([-right, -down])),
+				MouseScrollDelta::PixelDelta(offset) => {
+					(Some([-offset.x as f32, -offset.y as f32]), None)
 				}
-				MouseScrollDelta::PixelDelta(offset) => (
-					Some(Vector2::from([-offset.x as f32, -offset.y as f32])),
-					None,
-				),
 			};
-			self.mouse
-				.lock()
-				.send_event(None, scroll_distance, scroll_steps, None, None);
+			self.emit(InputEvent::Scroll { distance, steps });
 		}
 	}
 
 	fn handle_keyboard_input(&mut self, input: KeyboardInput) {
-		if input.virtual_keycode == Some(VirtualKeyCode::Escape)
-			&& input.state == ElementState::Released
-			&& self.modifiers.ctrl()
+		let pressed = input.state == ElementState::Pressed;
+		if self.swallowed_key == Some(input.scancode) {
+			if !pressed {
+				self.swallowed_key = None;
+			}
+			return;
+		}
+
+		// the desktop's auto-repeat, the keyboard emitter does its own
+		if pressed && self.held_keys.contains(&input.scancode) {
+			return;
+		}
+		if pressed {
+			let keysym = keysym_for_scancode(&self.keymap, input.scancode);
+			if self.handle_binding(keysym) {
+				self.swallowed_key = Some(input.scancode);
+				return;
+			}
+		}
+
+		self.last_input = Instant::now();
+		self.emit(InputEvent::Key {
+			key: input.scancode,
+			pressed,
+		});
+	}
+
+	/// Act on a chord meant for manifold itself, returning whether there was one.
+	fn handle_binding(&mut self, keysym: Keysym) -> bool {
+		let modifiers = Modifiers::from(self.modifiers);
+		if let MacroState::Binding(steps) = &mut self.macro_state {
+			if is_modifier(keysym) {
+				return false;
+			}
+			let steps = std::mem::take(steps);
+			self.macro_state = MacroState::Idle;
+			if keysym != xkb::KEY_Escape || modifiers != Modifiers::default() {
+				let trigger = KeyBinding { modifiers, keysym };
+				self.save_macro(MacroConfig { trigger, steps });
+			}
+			self.window.set_title(&self.window_title());
+			return true;
+		}
+
+		let grab_binding = if self.grabbed {
+			Some(self.grab_config.release)
+		} else {
+			(self.grab_config.trigger == GrabTrigger::Hotkey).then_some(self.grab_config.hotkey)
+		};
+		if grab_binding.is_some_and(|binding| binding.matches(modifiers, keysym)) {
+			self.set_grab(!self.grabbed);
+			return true;
+		}
+		if self
+			.record_macro
+			.is_some_and(|binding| binding.matches(modifiers, keysym))
+		{
+			self.toggle_recording();
+			return true;
+		}
+		if self
+			.keymap_config
+			.cycle_layout
+			.is_some_and(|binding| binding.matches(modifiers, keysym))
+		{
+			self.cycle_layout();
+			return true;
+		}
+		if self
+			.mouse_keys_toggle
+			.is_some_and(|binding| binding.matches(modifiers, keysym))
+		{
+			// the title follows once the mouse's status comes back
+			self.commands.send(Command::ToggleMouseKeys);
+			return true;
+		}
+		if self
+			.dwell_cycle_mode
+			.is_some_and(|binding| binding.matches(modifiers, keysym))
+		{
+			self.commands.send(Command::CycleDwellMode);
+			return true;
+		}
+		// later macros win so re-recording a trigger replaces the old macro
+		let steps = self
+			.macros
+			.iter()
+			.rev()
+			.find(|m| m.trigger.matches(modifiers, keysym))
+			.map(|m| m.steps.clone());
+		if let Some(steps) = steps {
+			self.play_macro(steps);
+			return true;
+		}
+		false
+	}
+
+	/// Send input on to the emitters, recording it too if a macro is being recorded.
+	fn emit(&mut self, event: InputEvent) {
+		if let InputEvent::Key { key, pressed } = event {
+			self.held_keys.retain(|held| *held != key);
+			if pressed {
+				self.held_keys.push(key);
+			}
+		}
+		if let MacroState::Recording(recorder) = &mut self.macro_state {
+			recorder.record(event.clone());
+		}
+		self.commands.send(Command::Input(event, self.received));
+	}
+
+	fn toggle_recording(&mut self) {
+		self.macro_state = match std::mem::take(&mut self.macro_state) {
+			MacroState::Idle => MacroState::Recording(Recorder::new()),
+			MacroState::Recording(recorder) => {
+				let steps = recorder.finish();
+				if steps.is_empty() {
+					MacroState::Idle
+				} else {
+					MacroState::Binding(steps)
+				}
+			}
+			binding => binding,
+		};
+		self.window.set_title(&self.window_title());
+	}
+
+	fn play_macro(&mut self, steps: Vec<ScriptStep>) {
+		// let go of the trigger's modifiers first so they don't change what the macro types, they
+		// stay held here so their auto-repeat is still dropped until they physically come up
+		self.commands.send(Command::PlayMacro {
+			release: self.held_keys.clone(),
+			steps,
+		});
+	}
+
+	fn save_macro(&mut self, macro_config: MacroConfig) {
+		match &self.config_path {
+			Some(path) => {
+				if let Err(e) = Config::append_macro(path, &macro_config) {
+					self.show_error(format!("{e:#}"));
+				}
+			}
+			None => eprintln!(
+				"There's no config file to save the macro in, it only lasts until manifold quits"
+			),
+		}
+		self.macros.push(macro_config);
+	}
+
+	fn reload(&mut self, config: Config) {
+		let connection = xcb_connection(&self.window);
+		if let Some(keymap) =
+			reloaded_keymap(connection.as_deref(), &self.keymap_config, &config.keymap)
 		{
+			self.layout = 0;
+			self.layout_keymap = None;
+			match keymap {
+				Ok(keymap) => self.keymap = keymap,
+				Err(e) => self.show_error(format!("Keeping the old keymap: {e}")),
+			}
+		}
+		self.keymap_config = config.keymap;
+		self.commands.set_keymap(&self.receiver_keymap());
+		if !config.mouse.enabled {
+			self.mouse_status = None;
+		}
+		self.repeat_config = config.repeat;
+		self.apply_desktop_repeat();
+
+		if config.window != self.window_config {
+			let size = Size::Logical(config.window.size.into());
+			self.window.set_min_inner_size(Some(size));
+			self.window.set_max_inner_size(Some(size));
+			self.window.set_inner_size(size);
+			self.window_config = config.window;
+		}
+		self.grab_config = config.grab;
+		self.record_macro = config.record_macro;
+		self.macros = config.macros;
+		self.mouse_keys_toggle = config.mouse_keys.toggle;
+		self.dwell_cycle_mode = config.dwell.cycle_mode;
+		self.mirror_leds = config.mirror_leds;
+		self.mirror_leds();
+		self.window.set_title(&self.window_title());
+	}
+
+	/// Show the focused receiver's lock keys on the desktop while grabbed, saving the desktop's own
+	/// first and putting them back once not grabbed or not mirroring anymore.
+	fn mirror_leds(&mut self) {
+		if self.mirror_leds && self.grabbed {
+			if self.desktop_leds.is_none() {
+				self.desktop_leds = desktop_leds(&self.window);
+			}
+			set_desktop_leds(&self.window, self.leds);
+		} else if let Some(leds) = self.desktop_leds.take() {
+			set_desktop_leds(&self.window, leds);
+		}
+	}
+
+	fn reload_keymap(&mut self) {
+		match load_keymap(&self.window, &self.keymap_config) {
+			Ok(keymap) => self.keymap = keymap,
+			Err(e) => {
+				self.show_error(format!("Keeping the old keymap: {e}"));
+				return;
+			}
+		}
+		self.commands.set_keymap(&self.receiver_keymap());
+		self.apply_desktop_repeat();
+	}
+
+	/// Switch the receivers to the next of `keymap.layouts`, bindings keep going by the first.
+	fn cycle_layout(&mut self) {
+		let layouts = self.keymap_config.layouts.len();
+		if layouts < 2 {
+			return;
+		}
+		let layout = (self.layout + 1) % layouts;
+		match self.keymap_config.compile_layout(layout) {
+			Ok(keymap) => {
+				self.layout = layout;
+				self.layout_keymap = (layout != 0).then_some(keymap);
+				self.commands.set_keymap(&self.receiver_keymap());
+			}
+			Err(e) => self.show_error(format!("Keeping the current layout: {e}")),
+		}
+	}
+
+	fn receiver_keymap(&self) -> Keymap {
+		self.layout_keymap
+			.clone()
+			.unwrap_or_else(|| self.keymap.clone())
+	}
+
+	/// Repeat keys at the X server's delay and rate if the config asks for it.
+	fn apply_desktop_repeat(&self) {
+		if !(self.repeat_config.enabled && self.repeat_config.from_desktop) {
+			return;
+		}
+		if let Some(repeat) = desktop_repeat(&self.window) {
+			self.commands.send(Command::SetRepeat(repeat));
+		}
+	}
+
+	fn handle_focus(&mut self, focused: bool) {
+		if focused && self.grab_config.trigger == GrabTrigger::Focus {
+			self.set_grab(true);
+		} else if !focused && self.grab_config.ungrab_on_focus_loss {
 			self.set_grab(false);
-		} else {
-			self.keyboard
-				.lock()
-				.send_key(input.scancode, input.state == ElementState::Pressed);
 		}
 	}
 
-	const GRABBED_WINDOW_TITLE: &'static str = "Flatland Input (ctrl+esc to release cursor)";
-	const UNGRABBED_WINDOW_TITLE: &'static str = "Flatland Input (click to grab input)";
+	/// Log an error and show it in the title until input is grabbed again.
+	fn show_error(&mut self, error: String) {
+		eprintln!("{error}");
+		self.error = Some(error);
+		self.window.set_title(&self.window_title());
+	}
+
+	fn window_title(&self) -> String {
+		if let Some(error) = &self.error {
+			return format!("Flatland Input (error: {error})");
+		}
+		if !self.connected {
+			return "Flatland Input (disconnected, reconnecting to Stardust)".to_string();
+		}
+		match (&self.macro_state, self.record_macro) {
+			(MacroState::Recording(_), Some(record_macro)) => {
+				return format!("Flatland Input (recording a macro, {record_macro} to stop)");
+			}
+			(MacroState::Binding(_), _) => {
+				return "Flatland Input (press a chord to play the macro with, Escape to discard it)"
+					.to_string();
+			}
+			_ => (),
+		}
+		if self.grabbed {
+			let mut status = String::new();
+			if let Some(mouse) = &self.mouse_status {
+				if mouse.mouse_keys {
+					status.push_str(", mouse keys on");
+				}
+				if self.dwell_cycle_mode.is_some() {
+					status.push_str(&format!(", dwell to {}", mouse.dwell_mode));
+				}
+			}
+			return format!(
+				"Flatland Input ({} to release cursor{status})",
+				self.grab_config.release
+			);
+		}
+		match self.grab_config.trigger {
+			GrabTrigger::Click => "Flatland Input (click to grab input)".to_string(),
+			GrabTrigger::Hotkey => {
+				format!("Flatland Input ({} to grab input)", self.grab_config.hotkey)
+			}
+			GrabTrigger::Focus => "Flatland Input (focus to grab input)".to_string(),
+		}
+	}
 	fn set_grab(&mut self, grab: bool) {
 		if grab == self.grabbed {
 			return;
 		}
 		self.grabbed = grab;
+		self.last_input = Instant::now();
+		if grab {
+			self.error = None;
+		}
 
 		self.window.set_cursor_visible(!grab);
-		if grab {
+		if grab && self.warp_cursor {
 			let window_size = self.window.inner_size();
 			let center_position =
 				LogicalPosition::new(window_size.width / 2, window_size.height / 2);
-			self.window.set_cursor_position(center_position).unwrap();
+			if let Err(e) = self.window.set_cursor_position(center_position) {
+				eprintln!("Couldn't warp the cursor to the center: {e}");
+			}
 		// self.keyboard.lock().
 		} else {
 			// self.flatland.lock().with_focused(|item| {
 			// 	item.keyboard_deactivate().unwrap();
 			// });
 		}
-		let window_title = if grab {
-			Self::GRABBED_WINDOW_TITLE
-		} else {
-			Self::UNGRABBED_WINDOW_TITLE
-		};
+		let window_title = self.window_title();
 
-		let grab = if grab {
-			CursorGrabMode::Confined
+		if grab && self.grab_config.keyboard {
+			match KeyboardGrab::grab(&self.window) {
+				Ok(keyboard_grab) => self.keyboard_grab = keyboard_grab,
+				Err(e) => {
+					eprintln!("Couldn't grab the keyboard, desktop shortcuts still work: {e}")
+				}
+			}
 		} else {
-			CursorGrabMode::None
+			self.keyboard_grab.release(&self.window);
+		}
+		self.mirror_leds();
+
+		let grab = match (grab, self.warp_cursor) {
+			(false, _) => CursorGrabMode::None,
+			(true, true) => CursorGrabMode::Confined,
+			(true, false) => CursorGrabMode::Locked,
 		};
 
 		if self.window.set_cursor_grab(grab).is_ok() {
-			self.window.set_title(window_title);
+			self.window.set_title(&window_title);
 		}
 	}
 }
+/// Lets go of the cursor and keyboard and puts the desktop's lock keys back when the event loop
+/// drops the window, which it also does when its thread panics. Nothing is left to forward input
+/// after that, so manifold quits.
+impl Drop for InputWindow {
+	fn drop(&mut self) {
+		self.set_grab(false);
+		self.quit.notify_one();
+	}
+}
+
+/// The keymap named in the config, or the X server's current one if the config doesn't name one.
+fn load_keymap(window: &Window, config: &KeymapConfig) -> Result<Keymap> {
+	keymap_from(xcb_connection(window).as_deref(), config)
+}
+
+/// The keymap named in the config, or else the one of the X server on `connection`, or xkb's
+/// default without an X server.
+fn keymap_from(connection: Option<&xcb::Connection>, config: &KeymapConfig) -> Result<Keymap> {
+	Ok(match connection {
+		_ if config.is_set() => config.compile()?,
+		Some(connection) => keymap_new_from_device(
+			&xkb::Context::new(0),
+			connection,
+			get_core_keyboard_device_id(connection),
+			KEYMAP_COMPILE_NO_FLAGS,
+		),
+		None => Keymap::new_from_names(&xkb::Context::new(0), "", "", "", "", None, 0)
+			.ok_or_else(|| eyre!("Couldn't compile the default keymap"))?,
+	})
+}
+
+/// The keymap to switch to if the config's keymap changed from `old` to `new`, going back to the
+/// desktop's when `new` doesn't name one anymore.
+fn reloaded_keymap(
+	connection: Option<&xcb::Connection>,
+	old: &KeymapConfig,
+	new: &KeymapConfig,
+) -> Option<Result<Keymap>> {
+	(old != new).then(|| keymap_from(connection, new))
+}
+
+/// The X server's key repeat delay and rate, if this is an X11 window and repeat is on.
+fn desktop_repeat(window: &Window) -> Option<KeyRepeat> {
+	let connection = xkb_connection(window)?;
+	let controls = connection
+		.wait_for_reply(connection.send_request(&xcb::xkb::GetControls {
+			device_spec: xcb::xkb::Id::UseCoreKbd as xcb::xkb::DeviceSpec,
+		}))
+		.ok()?;
+	(controls.repeat_interval() > 0).then(|| KeyRepeat {
+		delay: Duration::from_millis(controls.repeat_delay().into()),
+		interval: Duration::from_millis(controls.repeat_interval().into()),
+	})
+}
+
+/// Which of Caps and Num Lock are locked and whether Scroll Lock is lit on the X server.
+fn desktop_leds(window: &Window) -> Option<LockLeds> {
+	let connection = xkb_connection(window)?;
+	let state = connection
+		.wait_for_reply(connection.send_request(&xcb::xkb::GetState {
+			device_spec: xcb::xkb::Id::UseCoreKbd as xcb::xkb::DeviceSpec,
+		}))
+		.ok()?;
+	let scroll_lock = scroll_lock_atom(&connection)
+		.and_then(|indicator| {
+			connection
+				.wait_for_reply(connection.send_request(&xcb::xkb::GetNamedIndicator {
+					device_spec: xcb::xkb::Id::UseCoreKbd as xcb::xkb::DeviceSpec,
+					led_class: xcb::xkb::LedClass::DfltXiClass,
+					led_id: xcb::xkb::Id::DfltXiId as xcb::xkb::IdSpec,
+					indicator,
+				}))
+				.ok()
+		})
+		.is_some_and(|reply| reply.on());
+	Some(LockLeds {
+		caps_lock: state.locked_mods().contains(xcb::x::ModMask::LOCK),
+		num_lock: state.locked_mods().contains(xcb::x::ModMask::N2),
+		scroll_lock,
+	})
+}
+
+/// Lock Caps and Num Lock and light Scroll Lock on the X server to match `leds`.
+fn set_desktop_leds(window: &Window, leds: LockLeds) {
+	let Some(connection) = xkb_connection(window) else {
+		return;
+	};
+	let lock = |on, mask| if on { mask } else { xcb::x::ModMask::empty() };
+	connection.send_request(&xcb::xkb::LatchLockState {
+		device_spec: xcb::xkb::Id::UseCoreKbd as xcb::xkb::DeviceSpec,
+		// Num Lock is on Mod2 in every keymap that matters
+		affect_mod_locks: xcb::x::ModMask::LOCK | xcb::x::ModMask::N2,
+		mod_locks: lock(leds.caps_lock, xcb::x::ModMask::LOCK)
+			| lock(leds.num_lock, xcb::x::ModMask::N2),
+		lock_group: false,
+		group_lock: xcb::xkb::Group::N1,
+		affect_mod_latches: xcb::x::ModMask::empty(),
+		latch_group: false,
+		group_latch: 0,
+	});
+	if let Some(indicator) = scroll_lock_atom(&connection) {
+		connection.send_request(&xcb::xkb::SetNamedIndicator {
+			device_spec: xcb::xkb::Id::UseCoreKbd as xcb::xkb::DeviceSpec,
+			led_class: xcb::xkb::LedClass::DfltXiClass,
+			led_id: xcb::xkb::Id::DfltXiId as xcb::xkb::IdSpec,
+			indicator,
+			set_state: true,
+			on: leds.scroll_lock,
+			set_map: false,
+			create_map: false,
+			map_flags: xcb::xkb::ImFlag::empty(),
+			map_which_groups: xcb::xkb::ImGroupsWhich::empty(),
+			map_groups: xcb::xkb::SetOfGroups::empty(),
+			map_which_mods: xcb::xkb::ImModsWhich::empty(),
+			map_real_mods: xcb::x::ModMask::empty(),
+			map_vmods: xcb::xkb::VMod::empty(),
+			map_ctrls: xcb::xkb::BoolCtrl::empty(),
+		});
+	}
+	let _ = connection.flush();
+}
+
+/// The name XKB knows the Scroll Lock light by, if the server has one.
+fn scroll_lock_atom(connection: &xcb::Connection) -> Option<xcb::x::Atom> {
+	let reply = connection
+		.wait_for_reply(connection.send_request(&xcb::x::InternAtom {
+			only_if_exists: true,
+			name: b"Scroll Lock",
+		}))
+		.ok()?;
+	Some(reply.atom()).filter(|atom| *atom != xcb::x::ATOM_NONE)
+}
+
+/// Winit's X connection with the XKB extension ready to use, if this is an X11 window.
+fn xkb_connection(window: &Window) -> Option<ManuallyDrop<xcb::Connection>> {
+	let connection = xcb_connection(window)?;
+	connection
+		.wait_for_reply(connection.send_request(&xcb::xkb::UseExtension {
+			wanted_major: 1,
+			wanted_minor: 0,
+		}))
+		.ok()
+		.filter(|reply| reply.supported())?;
+	Some(connection)
+}
+
+/// Winit's own X connection, which must not be dropped.
+pub(crate) fn xcb_connection(window: &Window) -> Option<ManuallyDrop<xcb::Connection>> {
+	let raw_conn = window.xcb_connection()?;
+	Some(unsafe {
+		ManuallyDrop::new(xcb::Connection::from_raw_conn(
+			raw_conn as *mut xcb_connection_t,
+		)) // this memory is leaked, gotta find a fix!
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn unsetting_the_keymap_goes_back_to_the_desktops() {
+		let german = KeymapConfig {
+			layout: "de".into(),
+			..Default::default()
+		};
+		let unset = KeymapConfig::default();
+		assert!(reloaded_keymap(None, &german, &german).is_none());
+		let set = reloaded_keymap(None, &unset, &german).unwrap().unwrap();
+		let desktop = reloaded_keymap(None, &german, &unset).unwrap().unwrap();
+		assert_eq!(set.layout_get_name(0), "German");
+		assert_eq!(
+			desktop.layout_get_name(0),
+			keymap_from(None, &unset).unwrap().layout_get_name(0)
+		);
+		assert_ne!(desktop.layout_get_name(0), "German");
+	}
+}
@@ -1,111 +1,670 @@
 use color::{rgba, Rgba};
-use mint::Vector3;
+use color_eyre::eyre::{eyre, Result};
 use parking_lot::MutexGuard;
-use rustc_hash::FxHashMap;
+use rustc_hash::{FxHashMap, FxHashSet};
 use stardust_xr_fusion::{
 	client::FrameInfo,
 	core::values::Transform,
 	data::{NewReceiverInfo, PulseReceiver, PulseSender, PulseSenderHandler},
-	drawable::{LinePoint, Lines, ResourceID},
+	drawable::{Alignment, ResourceID, Text, TextStyle},
 	fields::UnknownField,
 	node::NodeType,
 	spatial::Spatial,
 	HandlerWrapper,
 };
 use stardust_xr_molecules::keyboard::{xkb::State, KeyboardEvent, KEYBOARD_MASK};
-use std::sync::Arc;
-use xkbcommon::xkb::Keymap;
+use std::{collections::BTreeMap, sync::Arc, time::Instant};
+use tokio::sync::{broadcast, watch};
+use xkbcommon::xkb::{self, Keymap};
 
-use crate::emitter::Emittable;
+use crate::{
+	accessibility::{FilterState, KeyFilter},
+	binding::EVDEV_OFFSET,
+	config::{AccessibilityConfig, EmitterConfig, KeymapConfig, RemapConfig, RemapRules},
+	emitter::{
+		receiver_number, ConnectionEvent, Emittable, ReceiverLine, ReceiverLines, ReceiverNames,
+	},
+	keycaps::Keycaps,
+	keymap::{self, KeyPress, Xkb},
+	leds::{LedIndicators, LockLeds},
+	remap::{KeyEvent, KeyRepeat, Remapper},
+};
 
-static KEYBOARD_COLOR: Rgba<f32> = rgba!(0.576, 0.38, 0.91, 1.0);
+/// Where the name of the current layout sits on the keyboard model, in the model's space.
+const LAYOUT_LABEL_POSITION: [f32; 3] = [-0.022, 0.012, 0.0025];
+const LAYOUT_LABEL_HEIGHT: f32 = 0.003;
 
 #[derive(Clone)]
 pub struct Keyboard(Arc<HandlerWrapper<PulseSender, KeyboardHandler>>);
 impl Keyboard {
-	pub fn create(spatial_parent: &Spatial) -> Self {
+	pub fn create(spatial_parent: &Spatial, config: &EmitterConfig) -> Result<Self> {
 		let pulse_sender = PulseSender::create(
 			spatial_parent,
-			Transform::from_position(Self::EMIT_POINT),
+			Transform::from_position(config.emit_point::<Self>()),
 			&KEYBOARD_MASK,
-		)
-		.unwrap();
-		let keyboard_handler = KeyboardHandler::new(pulse_sender.alias());
-		Keyboard(Arc::new(pulse_sender.wrap(keyboard_handler).unwrap()))
+		)?;
+		let keyboard_handler = KeyboardHandler::new(
+			pulse_sender.alias(),
+			LedIndicators::create(spatial_parent)?,
+			Keycaps::create(spatial_parent)?,
+			Text::create(
+				spatial_parent,
+				Transform::from_position(LAYOUT_LABEL_POSITION),
+				"",
+				TextStyle {
+					character_height: LAYOUT_LABEL_HEIGHT,
+					text_align: Alignment::CenterLeft.into(),
+					..Default::default()
+				},
+			)?,
+			config.color::<Self>(),
+			config.line_thickness,
+		);
+		Ok(Keyboard(Arc::new(pulse_sender.wrap(keyboard_handler)?)))
 	}
-	pub fn lock(&self) -> MutexGuard<KeyboardHandler> {
+	pub fn lock(&self) -> MutexGuard<'_, KeyboardHandler> {
 		self.0.lock_wrapped()
 	}
 }
 impl Emittable for Keyboard {
 	const SIZE: [f32; 3] = [0.05, 0.03, 0.004];
 	const EMIT_POINT: [f32; 3] = [0.0, 0.017667, 0.0];
+	const COLOR: Rgba<f32> = rgba!(0.576, 0.38, 0.91, 1.0);
+	const MODEL_PART: &'static str = "Keyboard";
 
 	fn model_resource() -> ResourceID {
 		ResourceID::new_namespaced("manifold", "keyboard")
 	}
-	fn update(&mut self, info: FrameInfo) {
-		self.lock().frame(info);
+	fn update(&mut self, info: FrameInfo, moving: bool) {
+		self.lock().frame(info, moving);
+	}
+	fn tint(&self) -> Option<Rgba<f32>> {
+		self.lock().tint()
+	}
+	fn configure(&mut self, config: &EmitterConfig) {
+		self.lock()
+			.set_line_style(config.color::<Self>(), config.line_thickness);
 	}
 }
 
 pub struct KeyboardHandler {
 	pulse_sender: PulseSender,
 	receivers_info: FxHashMap<String, KeyboardReceiverInfo>,
-	keymap: Option<Keymap>,
+	connection_events: broadcast::Sender<ConnectionEvent>,
+	focus: Option<String>,
+	keymap: Xkb<Option<Keymap>>,
+	names: ReceiverNames,
+	lines: ReceiverLines,
+	/// For the layouts in `keymap.receivers`, compiled for those receivers when they get their labels.
+	keymap_config: KeymapConfig,
+	filter: KeyFilter,
+	remap: RemapConfig,
+	/// Remapping for every receiver without its own keymap or remap rules.
+	remapper: Remapper,
+	receiver_remappers: FxHashMap<String, Remapper>,
+	repeat: Option<KeyRepeat>,
+	led_indicators: LedIndicators,
+	leds: watch::Sender<LockLeds>,
+	keycaps: Keycaps,
+	/// Keys sent down to the receivers and not released yet, for lighting up the keycaps.
+	held: FxHashSet<u32>,
+	layout_label: Text,
+	/// The layout the label shows, that of the focused receiver.
+	layout_name: String,
+	line_color: Rgba<f32>,
+	line_thickness: f32,
 }
 impl KeyboardHandler {
-	fn new(pulse_sender: PulseSender) -> Self {
+	fn new(
+		pulse_sender: PulseSender,
+		led_indicators: LedIndicators,
+		keycaps: Keycaps,
+		layout_label: Text,
+		line_color: Rgba<f32>,
+		line_thickness: f32,
+	) -> Self {
 		KeyboardHandler {
 			pulse_sender,
 			receivers_info: FxHashMap::default(),
-			keymap: None,
+			connection_events: broadcast::channel(16).0,
+			focus: None,
+			keymap: Xkb::new(None),
+			names: ReceiverNames::default(),
+			lines: ReceiverLines::default(),
+			keymap_config: KeymapConfig::default(),
+			filter: KeyFilter::default(),
+			remap: RemapConfig::default(),
+			remapper: Remapper::default(),
+			receiver_remappers: FxHashMap::default(),
+			repeat: None,
+			led_indicators,
+			leds: watch::channel(LockLeds::default()).0,
+			keycaps,
+			held: FxHashSet::default(),
+			layout_label,
+			layout_name: String::new(),
+			line_color,
+			line_thickness,
 		}
 	}
-	pub fn frame(&mut self, _info: FrameInfo) {
-		for receiver_info in self.receivers_info.values_mut() {
-			receiver_info.update_sender(&self.pulse_sender);
+	pub fn frame(&mut self, info: FrameInfo, moving: bool) {
+		let focus = self.focus.as_deref();
+		self.lines.update(
+			&self.pulse_sender,
+			self.receivers_info.iter_mut().filter_map(|(uid, info)| {
+				Some((info.line.as_mut()?, focus.map(|focus| focus == uid)))
+			}),
+			self.line_color,
+			self.line_thickness,
+			info.delta as f32,
+			moving,
+		);
+		let now = Instant::now();
+		for (key, state) in self.filter.tick(now) {
+			self.remap(|remapper| remapper.key(key, state, now));
 		}
+		self.remap(|remapper| remapper.tick(now));
+
+		let leds = self.lock_leds();
+		if *self.leds.borrow() != leds {
+			self.led_indicators.show(leds);
+			self.leds.send_replace(leds);
+		}
+
+		let state = self
+			.shown_receiver()
+			.and_then(|info| (*info.state).clone())
+			.or_else(|| self.keymap.as_ref().map(State::new));
+		self.keycaps
+			.show(state.as_ref(), &self.held, self.line_color);
+
+		let layout_name = self
+			.focused_keymap()
+			.map(|keymap| keymap.layout_get_name(0).to_string())
+			.unwrap_or_default();
+		if layout_name != self.layout_name {
+			if let Err(e) = self.layout_label.set_text(&layout_name) {
+				eprintln!("Couldn't show the keyboard layout: {e}");
+			}
+			self.layout_name = layout_name;
+		}
+
+		let receivers = self.pulse_sender.receivers();
+		for (uid, info) in self.receivers_info.iter_mut() {
+			if !info.connected() || info.sent_keymap {
+				continue;
+			}
+			let Some(keymap) = info.keymap.as_ref().or(self.keymap.as_ref()) else {
+				continue;
+			};
+			if let Some((receiver, _)) = receivers.get(uid) {
+				let event = KeyboardEvent::new(Some(keymap), None, None);
+				event.send_event(&self.pulse_sender, &[receiver]);
+				info.sent_keymap = true;
+			}
+		}
+	}
 
-		if self.keymap.is_some() {
-			let receivers = self.pulse_sender.receivers();
-			let receivers: Vec<&PulseReceiver> = self
+	/// The keymap of the focused receiver, or the shared one if none is focused.
+	fn focused_keymap(&self) -> Option<&Keymap> {
+		self.focus
+			.as_ref()
+			.and_then(|uid| self.receivers_info.get(uid))
+			.and_then(|info| info.keymap.as_ref())
+			.or(self.keymap.as_ref())
+	}
+
+	pub fn set_line_style(&mut self, color: Rgba<f32>, thickness: f32) {
+		self.line_color = color;
+		self.line_thickness = thickness;
+	}
+	/// Label the line to a connected receiver with this name instead of its number, and whichever
+	/// receiver gets its number after it, giving it the layout and remap rules the config has for the
+	/// name. Returns false if no such receiver is connected.
+	pub fn set_receiver_name(&mut self, uid: &str, name: &str) -> bool {
+		let Some(info) = self.receivers_info.get_mut(uid) else {
+			return false;
+		};
+		let Some(line) = &mut info.line else {
+			return false;
+		};
+		self.names.set(info.number, name);
+		line.set_name(name);
+		self.apply_receiver_rules(uid);
+		self.rebuild_remappers();
+		true
+	}
+	/// Take the names given to receivers from an earlier handler.
+	pub fn set_names(&mut self, names: ReceiverNames) {
+		for info in self.receivers_info.values_mut() {
+			if let Some(line) = &mut info.line {
+				line.set_name(&names.label(info.number));
+			}
+		}
+		self.names = names;
+	}
+
+	/// Tint for the keyboard model while the accessibility filters are holding on to keys: grey for a
+	/// slow key that's still going down, a pale line color for latched modifiers and the line color for
+	/// locked ones.
+	pub fn tint(&self) -> Option<Rgba<f32>> {
+		let color = self.line_color;
+		Some(match self.filter.state()? {
+			FilterState::Pending => rgba!(0.5, 0.5, 0.5, 1.0),
+			FilterState::Latched => rgba!(
+				(1.0 + color.c.r) / 2.0,
+				(1.0 + color.c.g) / 2.0,
+				(1.0 + color.c.b) / 2.0,
+				1.0
+			),
+			FilterState::Locked => color,
+		})
+	}
+
+	/// UIDs of the receivers currently connected to this keyboard.
+	pub fn connected_receivers(&self) -> Vec<String> {
+		self.receivers_info
+			.iter()
+			.filter(|(_, info)| info.connected())
+			.map(|(uid, _)| uid.clone())
+			.collect()
+	}
+	/// What the lines to the connected receivers are labelled, by UID.
+	pub fn receiver_names(&self) -> BTreeMap<String, String> {
+		self.receivers_info
+			.iter()
+			.filter_map(|(uid, info)| Some((uid.clone(), info.line.as_ref()?.name().to_string())))
+			.collect()
+	}
+	/// Get notified whenever a receiver connects to or disconnects from this keyboard.
+	pub fn subscribe(&self) -> broadcast::Receiver<ConnectionEvent> {
+		self.connection_events.subscribe()
+	}
+
+	/// The lock keys of the focused receiver, or of the first connected one if none is focused, going
+	/// by the keys manifold sent it.
+	pub fn lock_leds(&self) -> LockLeds {
+		self.shown_receiver()
+			.and_then(|info| info.state.as_ref())
+			.map(LockLeds::from_state)
+			.unwrap_or_default()
+	}
+	/// The receiver whose state the model shows: the focused one, or the first connected one if none
+	/// is focused.
+	fn shown_receiver(&self) -> Option<&KeyboardReceiverInfo> {
+		match &self.focus {
+			Some(uid) => self.receivers_info.get(uid),
+			None => self
 				.receivers_info
 				.iter()
-				.filter(|(_, info)| info.connected() && !info.sent_keymap)
-				.filter_map(|(uid, _)| receivers.get(uid).map(|(rx, _)| rx))
-				.collect();
-			if !receivers.is_empty() {
-				let event = KeyboardEvent::new(self.keymap.as_ref(), None, None);
-				event.send_event(&self.pulse_sender, &receivers);
-				for receiver in self.receivers_info.values_mut() {
-					receiver.sent_keymap = true;
-				}
+				.filter(|(_, info)| info.connected())
+				.min_by_key(|(uid, _)| *uid)
+				.map(|(_, info)| info),
+		}
+	}
+	/// Get notified whenever [`Self::lock_leds`] changes.
+	pub fn watch_leds(&self) -> watch::Receiver<LockLeds> {
+		self.leds.subscribe()
+	}
+
+	/// The receiver input is limited to, if any.
+	pub fn focus(&self) -> Option<&str> {
+		self.focus.as_deref()
+	}
+	/// Only send input to the receiver with this UID, or to every connected receiver if `None`.
+	/// Returns false and keeps the old focus if no such receiver is connected.
+	pub fn set_focus(&mut self, uid: Option<String>) -> bool {
+		if let Some(uid) = &uid {
+			if !self
+				.receivers_info
+				.get(uid)
+				.is_some_and(|info| info.connected())
+			{
+				return false;
 			}
 		}
+		self.focus = uid;
+		true
+	}
+
+	/// Whether a keymap is set for the receivers without one of their own.
+	pub fn has_keymap(&self) -> bool {
+		self.keymap.is_some()
 	}
 
-	pub fn set_keymap(&mut self, keymap: Keymap) {
+	/// Set the keymap shared by every receiver without one of its own, from xkb's text format.
+	pub fn set_keymap_string(&mut self, keymap: &str) -> Result<()> {
+		let keymap = Keymap::new_from_string(
+			&xkb::Context::new(xkb::CONTEXT_NO_FLAGS),
+			keymap.to_string(),
+			xkb::KEYMAP_FORMAT_TEXT_V1,
+			xkb::KEYMAP_COMPILE_NO_FLAGS,
+		)
+		.ok_or_else(|| eyre!("Couldn't compile the keymap"))?;
+		self.set_keymap(keymap);
+		Ok(())
+	}
+	/// Set the keymap shared by every receiver without one of its own. Nothing else may keep a
+	/// reference to `keymap`, see [`Xkb`].
+	pub(crate) fn set_keymap(&mut self, keymap: Keymap) {
 		for receiver_info in self.receivers_info.values_mut() {
-			receiver_info.state = Some(State::new(&keymap));
-			receiver_info.sent_keymap = false;
+			if receiver_info.keymap.is_none() {
+				*receiver_info.state = Some(State::new(&keymap));
+				receiver_info.sent_keymap = false;
+			}
 		}
-		self.keymap = Some(keymap);
+		self.filter.set_keymap(keymap.clone());
+		*self.keymap = Some(keymap);
+		self.rebuild_remappers();
+	}
+	/// Give a connected receiver a keymap of its own, or the shared one again if `None`. Returns false
+	/// if no such receiver is connected.
+	pub(crate) fn set_receiver_keymap(&mut self, uid: &str, keymap: Option<Keymap>) -> bool {
+		let Some(receiver_info) = self
+			.receivers_info
+			.get_mut(uid)
+			.filter(|info| info.connected())
+		else {
+			return false;
+		};
+		receiver_info.set_keymap(keymap, self.keymap.as_ref());
+		self.rebuild_remappers();
+		true
+	}
+	/// Remap a connected receiver's keys with these rules on top of `remap`, or with only `remap`
+	/// again if `None`. Returns false if no such receiver is connected.
+	pub fn set_receiver_remap(&mut self, uid: &str, rules: Option<RemapRules>) -> bool {
+		let Some(receiver_info) = self
+			.receivers_info
+			.get_mut(uid)
+			.filter(|info| info.connected())
+		else {
+			return false;
+		};
+		receiver_info.remap = rules;
+		self.rebuild_remappers();
+		true
 	}
 
-	pub fn send_key(&self, key: u32, state: bool) {
-		let keys_down = state.then_some(vec![key]);
-		let keys_up = (!state).then_some(vec![key]);
-		let event = KeyboardEvent::new(None, keys_up, keys_down);
-		let receivers = self.pulse_sender.receivers();
-		let receivers: Vec<&PulseReceiver> = self
+	/// Remap keys with these rules, and give the receivers in `remap.receivers` theirs, replacing
+	/// any set by hand.
+	pub fn set_remap(&mut self, remap: RemapConfig) {
+		self.remap = remap;
+		self.apply_all_receiver_rules();
+	}
+	/// Give the receivers in `keymap.receivers` their layouts, replacing any set by hand.
+	pub fn set_keymap_config(&mut self, config: &KeymapConfig) {
+		self.keymap_config = config.clone();
+		self.apply_all_receiver_rules();
+	}
+	fn apply_all_receiver_rules(&mut self) {
+		let uids: Vec<String> = self.receivers_info.keys().cloned().collect();
+		for uid in uids {
+			self.apply_receiver_rules(&uid);
+		}
+		self.rebuild_remappers();
+	}
+	/// Give a receiver the layout and remap rules the config has for its label, or the shared ones if
+	/// there are none. Its remapper is left for the caller to rebuild.
+	fn apply_receiver_rules(&mut self, uid: &str) {
+		let Some(label) = self
+			.receivers_info
+			.get(uid)
+			.and_then(|info| Some(info.line.as_ref()?.name().to_string()))
+		else {
+			return;
+		};
+		let (keymap, remap) = self.receiver_rules(&label);
+		let receiver_info = self.receivers_info.get_mut(uid).unwrap();
+		if keymap.is_some() || receiver_info.keymap.is_some() {
+			receiver_info.set_keymap(keymap, self.keymap.as_ref());
+		}
+		receiver_info.remap = remap;
+	}
+	/// The layout and remap rules the config has for receivers with this label.
+	fn receiver_rules(&self, label: &str) -> (Option<Keymap>, Option<RemapRules>) {
+		let keymap = self.keymap_config.receivers.get(label).and_then(|layout| {
+			self.keymap_config
+				.compile_named(layout)
+				.map_err(|e| eprintln!("Giving {label} the shared keymap: {e}"))
+				.ok()
+		});
+		(keymap, self.remap.receivers.get(label).cloned())
+	}
+	/// Repeat held keys like this, or not at all if `None`.
+	pub fn set_repeat(&mut self, repeat: Option<KeyRepeat>) {
+		self.repeat = repeat;
+		self.remapper.set_repeat(repeat);
+		for remapper in self.receiver_remappers.values_mut() {
+			remapper.set_repeat(repeat);
+		}
+	}
+	/// Turn the sticky, slow and bounce key filters on or off, releasing whatever they were holding.
+	pub fn set_accessibility(&mut self, config: &AccessibilityConfig) {
+		let now = Instant::now();
+		for (key, state) in self.filter.release_all() {
+			self.remap(|remapper| remapper.key(key, state, now));
+		}
+		self.filter = KeyFilter::new(config);
+		if let Some(keymap) = &*self.keymap {
+			self.filter.set_keymap(keymap.clone());
+		}
+	}
+	fn rebuild_remappers(&mut self) {
+		self.filter.reset();
+		self.remap(Remapper::release_all);
+		if let Some(keymap) = &*self.keymap {
+			self.remapper = Remapper::new(
+				keymap,
+				&self.remap.rules(),
+				self.remap.tap_timeout(),
+				self.repeat,
+			);
+		}
+		self.receiver_remappers = self
 			.receivers_info
 			.iter()
-			.filter(|(_, info)| info.connected())
-			.filter_map(|(uid, _)| receivers.get(uid).map(|(rx, _)| rx))
+			.filter_map(|(uid, info)| Some((uid.clone(), self.receiver_remapper(info)?)))
 			.collect();
-		event.send_event(&self.pulse_sender, &receivers);
 	}
+	/// A remapper of its own for a receiver with its own remap rules or keymap, since remapping goes
+	/// by keysym.
+	fn receiver_remapper(&self, info: &KeyboardReceiverInfo) -> Option<Remapper> {
+		if info.remap.is_none() && info.keymap.is_none() {
+			return None;
+		}
+		let rules = match &info.remap {
+			Some(overrides) => self.remap.rules().with(overrides),
+			None => self.remap.rules(),
+		};
+		let keymap = info.keymap.as_ref().or(self.keymap.as_ref())?;
+		Some(Remapper::new(
+			keymap,
+			&rules,
+			self.remap.tap_timeout(),
+			self.repeat,
+		))
+	}
+
+	/// Press or release a key, going through the accessibility filters and the remapping first.
+	pub fn send_key(&mut self, key: u32, state: bool) {
+		let now = Instant::now();
+		for (key, state) in self.filter.key(key, state, now) {
+			self.remap(|remapper| remapper.key(key, state, now));
+		}
+	}
+
+	/// Run every remapper and send what comes out of each to the receivers it's for.
+	fn remap(&mut self, mut f: impl FnMut(&mut Remapper) -> Vec<KeyEvent>) {
+		let events = f(&mut self.remapper);
+		let receiver_events: Vec<(String, Vec<KeyEvent>)> = self
+			.receiver_remappers
+			.iter_mut()
+			.map(|(uid, remapper)| (uid.clone(), f(remapper)))
+			.collect();
+		let overridden: Vec<String> = self.receiver_remappers.keys().cloned().collect();
+		for (key, state) in events {
+			self.send_key_event(key, state, |uid| !overridden.iter().any(|o| o == uid));
+		}
+		for (uid, events) in receiver_events {
+			for (key, state) in events {
+				self.send_key_event(key, state, |receiver| receiver == uid);
+			}
+		}
+	}
+
+	/// Send a key, keeping track of the xkb state of each receiver it goes to.
+	fn send_key_event(&mut self, key: u32, pressed: bool, to: impl Fn(&str) -> bool) {
+		let direction = || {
+			if pressed {
+				xkb::KeyDirection::Down
+			} else {
+				xkb::KeyDirection::Up
+			}
+		};
+		if pressed {
+			self.held.insert(key);
+		} else {
+			self.held.remove(&key);
+		}
+		let focus = self.focus.as_deref();
+		for (uid, info) in self.receivers_info.iter_mut() {
+			if info.connected() && focus.is_none_or(|focus| focus == uid) && to(uid) {
+				if let Some(state) = &mut *info.state {
+					state.update_key(key + EVDEV_OFFSET, direction());
+				}
+			}
+		}
+		self.send_event(key_event(key, pressed), to);
+	}
+
+	/// Send to the focused receiver, or every connected receiver if none is focused, that `to` accepts.
+	fn send_event(&mut self, event: KeyboardEvent, to: impl Fn(&str) -> bool) {
+		let focus = self.focus.as_deref();
+		let targets: Vec<&str> = self
+			.receivers_info
+			.iter_mut()
+			.filter(|(uid, info)| {
+				info.connected() && focus.is_none_or(|focus| focus == *uid) && to(uid)
+			})
+			.map(|(uid, info)| {
+				if let Some(line) = &mut info.line {
+					line.pulse();
+				}
+				uid.as_str()
+			})
+			.collect();
+		let receivers = self.pulse_sender.receivers();
+		let receivers: Vec<&PulseReceiver> = targets
+			.into_iter()
+			.filter_map(|uid| receivers.get(uid).map(|(rx, _)| rx))
+			.collect();
+		if !receivers.is_empty() {
+			event.send_event(&self.pulse_sender, &receivers);
+		}
+	}
+
+	/// Type out `text` by tapping the key for each character while holding the modifiers that reach
+	/// its level. Characters the keymap doesn't have are put on unused keys of a temporary keymap, which
+	/// is swapped back out afterwards. Receivers with keymaps of their own get the keys for those.
+	/// Returns the characters that couldn't be typed with one of the keymaps the text went to.
+	pub fn type_text(&mut self, text: &str) -> Vec<char> {
+		let focus = self.focus.as_deref();
+		let mut shared_keymap_used = false;
+		let mut own_keymaps: Vec<(String, Keymap)> = Vec::new();
+		for (uid, info) in &self.receivers_info {
+			if !info.connected() || focus.is_some_and(|focus| focus != uid) {
+				continue;
+			}
+			match &*info.keymap {
+				Some(keymap) => own_keymaps.push((uid.clone(), keymap.clone())),
+				None => shared_keymap_used = true,
+			}
+		}
+		let mut missing = match (*self.keymap).clone() {
+			_ if !shared_keymap_used => Vec::new(),
+			Some(keymap) => self.type_text_with(&keymap, text, |uid| {
+				!own_keymaps.iter().any(|(own, _)| own == uid)
+			}),
+			// no keys to find, so none of it reaches the receivers waiting for the shared keymap
+			None => text.chars().collect(),
+		};
+		for (uid, keymap) in &own_keymaps {
+			missing.extend(self.type_text_with(keymap, text, |receiver| receiver == uid));
+		}
+		let mut seen = FxHashSet::default();
+		missing.retain(|character| seen.insert(*character));
+		missing
+	}
+	fn type_text_with(
+		&mut self,
+		keymap: &Keymap,
+		text: &str,
+		to: impl Fn(&str) -> bool + Copy,
+	) -> Vec<char> {
+		let modifier_keys = keymap::level_modifier_keys(keymap);
+		let mut presses: Vec<(char, Option<KeyPress>)> = text
+			.chars()
+			.map(|c| (c, keymap::find_key_press(keymap, &modifier_keys, c)))
+			.collect();
+
+		let mut unmapped: Vec<char> = presses
+			.iter()
+			.filter(|(_, press)| press.is_none())
+			.map(|(c, _)| *c)
+			.collect();
+		unmapped.sort_unstable();
+		unmapped.dedup();
+		let keysyms: Vec<xkb::Keysym> = unmapped
+			.iter()
+			.map(|c| keymap::keysym_for_char(*c))
+			.collect();
+		let remapped = (!unmapped.is_empty())
+			.then(|| keymap::with_extra_keysyms(keymap, &keysyms))
+			.flatten();
+		if let Some((remapped_keymap, keys)) = &remapped {
+			for (character, press) in &mut presses {
+				if press.is_none() {
+					*press = unmapped
+						.iter()
+						.zip(keys)
+						.find(|(c, _)| *c == character)
+						.map(|(_, key)| KeyPress {
+							key: *key,
+							modifiers: Vec::new(),
+						});
+				}
+			}
+			self.send_event(KeyboardEvent::new(Some(remapped_keymap), None, None), to);
+		}
+
+		let mut missing = Vec::new();
+		for (character, press) in presses {
+			let Some(press) = press else {
+				missing.push(character);
+				continue;
+			};
+			for modifier in &press.modifiers {
+				self.send_key_event(*modifier, true, to);
+			}
+			self.send_key_event(press.key, true, to);
+			self.send_key_event(press.key, false, to);
+			for modifier in press.modifiers.iter().rev() {
+				self.send_key_event(*modifier, false, to);
+			}
+		}
+
+		if remapped.is_some() {
+			self.send_event(KeyboardEvent::new(Some(keymap), None, None), to);
+		}
+		missing
+	}
+}
+
+fn key_event(key: u32, state: bool) -> KeyboardEvent {
+	let keys_down = state.then_some(vec![key]);
+	let keys_up = (!state).then_some(vec![key]);
+	KeyboardEvent::new(None, keys_up, keys_down)
 }
 impl PulseSenderHandler for KeyboardHandler {
 	fn new_receiver(
@@ -114,68 +673,87 @@ impl PulseSenderHandler for KeyboardHandler {
 		receiver: PulseReceiver,
 		_field: UnknownField,
 	) {
-		let mut keyboard_info = KeyboardReceiverInfo::new(self.keymap.as_ref(), receiver.alias());
-		keyboard_info.connect(&self.pulse_sender, self.keymap.as_ref()); // temporary
+		let number = receiver_number(self.receivers_info.values().map(|info| info.number));
+		let mut keyboard_info =
+			KeyboardReceiverInfo::new(number, self.keymap.as_ref(), receiver.alias());
+		let name = self.names.label(number);
+		let (keymap, remap) = self.receiver_rules(&name);
+		if keymap.is_some() {
+			keyboard_info.set_keymap(keymap, self.keymap.as_ref());
+		}
+		keyboard_info.remap = remap;
+		// temporary
+		match keyboard_info.connect(&self.pulse_sender, self.keymap.as_ref(), &name) {
+			Ok(()) => {
+				let _ = self
+					.connection_events
+					.send(ConnectionEvent::Connected(info.uid.clone()));
+			}
+			Err(e) => eprintln!("Couldn't connect to receiver {}: {e}", info.uid),
+		}
+		if let Some(remapper) = self.receiver_remapper(&keyboard_info) {
+			self.receiver_remappers.insert(info.uid.clone(), remapper);
+		}
 		self.receivers_info.insert(info.uid, keyboard_info);
 	}
 	fn drop_receiver(&mut self, uid: &str) {
-		self.receivers_info.remove(uid);
+		if self.receivers_info.remove(uid).is_some() {
+			if self.focus.as_deref() == Some(uid) {
+				self.focus = None;
+			}
+			self.receiver_remappers.remove(uid);
+			let _ = self
+				.connection_events
+				.send(ConnectionEvent::Disconnected(uid.to_string()));
+		}
 	}
 }
-unsafe impl Send for KeyboardHandler {}
-unsafe impl Sync for KeyboardHandler {}
 
 struct KeyboardReceiverInfo {
-	lines: Option<Arc<Lines>>,
-	state: Option<State>,
+	/// What it's labelled by until it's named, see [`ReceiverNames`].
+	number: usize,
+	line: Option<ReceiverLine>,
+	/// Its own keymap, instead of the shared one.
+	keymap: Xkb<Option<Keymap>>,
+	state: Xkb<Option<State>>,
+	/// Its own remap rules, on top of the shared ones.
+	remap: Option<RemapRules>,
 	receiver: PulseReceiver,
 	sent_keymap: bool,
 }
 impl KeyboardReceiverInfo {
-	fn new(keymap: Option<&Keymap>, receiver: PulseReceiver) -> Self {
+	fn new(number: usize, shared_keymap: Option<&Keymap>, receiver: PulseReceiver) -> Self {
 		KeyboardReceiverInfo {
-			lines: None,
-			state: keymap.map(State::new),
+			number,
+			line: None,
+			keymap: Xkb::new(None),
+			state: Xkb::new(shared_keymap.map(State::new)),
+			remap: None,
 			receiver,
 			sent_keymap: false,
 		}
 	}
 	fn connected(&self) -> bool {
-		self.lines.is_some()
+		self.line.is_some()
+	}
+	/// Switch to its own keymap, or to the shared one if `None`, sending it again on the next frame.
+	fn set_keymap(&mut self, keymap: Option<Keymap>, shared_keymap: Option<&Keymap>) {
+		*self.keymap = keymap;
+		*self.state = self.keymap.as_ref().or(shared_keymap).map(State::new);
+		self.sent_keymap = false;
 	}
-	fn connect(&mut self, sender: &PulseSender, keymap: Option<&Keymap>) {
-		self.lines = Some(Arc::new(
-			Lines::create(&self.receiver, Transform::default(), &[], false).unwrap(),
-		));
+	fn connect(
+		&mut self,
+		sender: &PulseSender,
+		shared_keymap: Option<&Keymap>,
+		name: &str,
+	) -> Result<()> {
+		self.line = Some(ReceiverLine::create(&self.receiver, name)?);
+		let keymap = self.keymap.as_ref().or(shared_keymap);
 		if keymap.is_some() {
 			let keymap_event = KeyboardEvent::new(keymap, None, None);
 			keymap_event.send_event(sender, &[&self.receiver]);
 		}
-	}
-	const LINE_THICKNESS: f32 = 0.005;
-	fn update_sender(&mut self, sender: &PulseSender) {
-		if let Some(lines) = self.lines.clone() {
-			let future = sender.get_position_rotation_scale(&lines).unwrap();
-			tokio::task::spawn(async move {
-				if let Ok((position, _, _)) = future.await {
-					lines
-						.update_points(&[
-							LinePoint {
-								point: Vector3::from([0.0; 3]),
-								thickness: Self::LINE_THICKNESS,
-								color: KEYBOARD_COLOR,
-							},
-							LinePoint {
-								point: position,
-								thickness: Self::LINE_THICKNESS,
-								color: KEYBOARD_COLOR,
-							},
-						])
-						.unwrap();
-				}
-			});
-		}
+		Ok(())
 	}
 }
-unsafe impl Send for KeyboardReceiverInfo {}
-unsafe impl Sync for KeyboardReceiverInfo {}
@@ -0,0 +1,105 @@
+//! Grabbing the whole keyboard while input is grabbed, so the desktop's own shortcuts like Alt+Tab
+//! reach the receivers too.
+
+use crate::input_window::xcb_connection;
+use color_eyre::eyre::{bail, eyre, Result};
+use wayland_client::{
+	protocol::{wl_seat::WlSeat, wl_surface::WlSurface},
+	Display, EventQueue, GlobalManager, Main, Proxy,
+};
+use wayland_protocols::unstable::keyboard_shortcuts_inhibit::v1::client::{
+	zwp_keyboard_shortcuts_inhibit_manager_v1::ZwpKeyboardShortcutsInhibitManagerV1,
+	zwp_keyboard_shortcuts_inhibitor_v1::ZwpKeyboardShortcutsInhibitorV1,
+};
+use winit::{
+	platform::{wayland::WindowExtWayland, x11::WindowExtX11},
+	window::Window,
+};
+use xcb::{x, XidNew};
+
+#[derive(Default)]
+pub enum KeyboardGrab {
+	#[default]
+	Released,
+	X11,
+	/// The compositor's shortcuts are inhibited for as long as the inhibitor is alive.
+	Wayland {
+		display: Display,
+		queue: EventQueue,
+		inhibitor: Main<ZwpKeyboardShortcutsInhibitorV1>,
+	},
+}
+impl KeyboardGrab {
+	/// Grab the keyboard for `window`, keys still arrive at the window as usual.
+	pub fn grab(window: &Window) -> Result<Self> {
+		if let Some(xlib_window) = window.xlib_window() {
+			grab_x11(window, xlib_window as u32)?;
+			Ok(KeyboardGrab::X11)
+		} else {
+			inhibit_wayland(window)
+		}
+	}
+
+	pub fn release(&mut self, window: &Window) {
+		match std::mem::take(self) {
+			KeyboardGrab::Released => (),
+			KeyboardGrab::X11 => {
+				if let Some(connection) = xcb_connection(window) {
+					let _ = connection.send_and_check_request(&x::UngrabKeyboard {
+						time: x::CURRENT_TIME,
+					});
+				}
+			}
+			KeyboardGrab::Wayland {
+				display,
+				mut queue,
+				inhibitor,
+			} => {
+				inhibitor.destroy();
+				let _ = queue.sync_roundtrip(&mut (), |_, _, _| {});
+				let _ = display.flush();
+			}
+		}
+	}
+}
+
+fn grab_x11(window: &Window, xlib_window: u32) -> Result<()> {
+	let connection = xcb_connection(window).ok_or_else(|| eyre!("No X connection"))?;
+	let cookie = connection.send_request(&x::GrabKeyboard {
+		// keep delivering keys to the window itself, including the release chord
+		owner_events: true,
+		grab_window: unsafe { x::Window::new(xlib_window) },
+		time: x::CURRENT_TIME,
+		pointer_mode: x::GrabMode::Async,
+		keyboard_mode: x::GrabMode::Async,
+	});
+	let status = connection.wait_for_reply(cookie)?.status();
+	if status != x::GrabStatus::Success {
+		bail!("The X server refused the keyboard grab: {status:?}");
+	}
+	Ok(())
+}
+
+fn inhibit_wayland(window: &Window) -> Result<KeyboardGrab> {
+	let (Some(display), Some(surface)) = (window.wayland_display(), window.wayland_surface())
+	else {
+		bail!("Not an X11 or Wayland window");
+	};
+	let display = unsafe { Display::from_external_display(display as *mut _) };
+	let surface: WlSurface = unsafe { Proxy::<WlSurface>::from_c_ptr(surface as *mut _) }.into();
+
+	let mut queue = display.create_event_queue();
+	let globals = GlobalManager::new(&display.attach(queue.token()));
+	queue.sync_roundtrip(&mut (), |_, _, _| {})?;
+	let seat = globals.instantiate_exact::<WlSeat>(1)?;
+	let manager = globals
+		.instantiate_exact::<ZwpKeyboardShortcutsInhibitManagerV1>(1)
+		.map_err(|e| eyre!("The compositor can't inhibit its shortcuts: {e}"))?;
+	let inhibitor = manager.inhibit_shortcuts(&surface, &seat);
+	queue.sync_roundtrip(&mut (), |_, _, _| {})?;
+	Ok(KeyboardGrab::Wayland {
+		display,
+		queue,
+		inhibitor,
+	})
+}
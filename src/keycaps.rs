@@ -0,0 +1,239 @@
+//! Legends for the keys on the keyboard model, showing what each key types with the layout and
+//! modifiers the receivers have, and lighting up the keys that are down.
+
+use crate::binding::EVDEV_OFFSET;
+use color::{rgba, Rgba};
+use color_eyre::eyre::Result;
+use input_event_codes::*;
+use mint::Vector3;
+use rustc_hash::FxHashSet;
+use stardust_xr_fusion::{
+	core::values::Transform,
+	drawable::{Alignment, LinePoint, Lines, Text, TextStyle},
+	spatial::Spatial,
+};
+use xkbcommon::xkb::{self, State};
+
+/// The keys drawn on the model, row by row from the top, with their widths in keys.
+const ROWS: [&[(u32, f32)]; 5] = [
+	&[
+		(KEY_GRAVE!(), 1.0),
+		(KEY_1!(), 1.0),
+		(KEY_2!(), 1.0),
+		(KEY_3!(), 1.0),
+		(KEY_4!(), 1.0),
+		(KEY_5!(), 1.0),
+		(KEY_6!(), 1.0),
+		(KEY_7!(), 1.0),
+		(KEY_8!(), 1.0),
+		(KEY_9!(), 1.0),
+		(KEY_0!(), 1.0),
+		(KEY_MINUS!(), 1.0),
+		(KEY_EQUAL!(), 1.0),
+		(KEY_BACKSPACE!(), 2.0),
+	],
+	&[
+		(KEY_TAB!(), 1.5),
+		(KEY_Q!(), 1.0),
+		(KEY_W!(), 1.0),
+		(KEY_E!(), 1.0),
+		(KEY_R!(), 1.0),
+		(KEY_T!(), 1.0),
+		(KEY_Y!(), 1.0),
+		(KEY_U!(), 1.0),
+		(KEY_I!(), 1.0),
+		(KEY_O!(), 1.0),
+		(KEY_P!(), 1.0),
+		(KEY_LEFTBRACE!(), 1.0),
+		(KEY_RIGHTBRACE!(), 1.0),
+		(KEY_BACKSLASH!(), 1.5),
+	],
+	&[
+		(KEY_CAPSLOCK!(), 1.75),
+		(KEY_A!(), 1.0),
+		(KEY_S!(), 1.0),
+		(KEY_D!(), 1.0),
+		(KEY_F!(), 1.0),
+		(KEY_G!(), 1.0),
+		(KEY_H!(), 1.0),
+		(KEY_J!(), 1.0),
+		(KEY_K!(), 1.0),
+		(KEY_L!(), 1.0),
+		(KEY_SEMICOLON!(), 1.0),
+		(KEY_APOSTROPHE!(), 1.0),
+		(KEY_ENTER!(), 2.25),
+	],
+	&[
+		(KEY_LEFTSHIFT!(), 2.25),
+		(KEY_Z!(), 1.0),
+		(KEY_X!(), 1.0),
+		(KEY_C!(), 1.0),
+		(KEY_V!(), 1.0),
+		(KEY_B!(), 1.0),
+		(KEY_N!(), 1.0),
+		(KEY_M!(), 1.0),
+		(KEY_COMMA!(), 1.0),
+		(KEY_DOT!(), 1.0),
+		(KEY_SLASH!(), 1.0),
+		(KEY_RIGHTSHIFT!(), 2.75),
+	],
+	&[
+		(KEY_LEFTCTRL!(), 1.25),
+		(KEY_LEFTMETA!(), 1.25),
+		(KEY_LEFTALT!(), 1.25),
+		(KEY_SPACE!(), 6.25),
+		(KEY_RIGHTALT!(), 1.25),
+		(KEY_RIGHTMETA!(), 1.25),
+		(KEY_COMPOSE!(), 1.25),
+		(KEY_RIGHTCTRL!(), 1.25),
+	],
+];
+/// Modifier keys that stay lit while their modifier is on, such as when it's latched or locked.
+const MODIFIER_KEYS: [(u32, &str); 9] = [
+	(KEY_LEFTSHIFT!(), xkb::MOD_NAME_SHIFT),
+	(KEY_RIGHTSHIFT!(), xkb::MOD_NAME_SHIFT),
+	(KEY_LEFTCTRL!(), xkb::MOD_NAME_CTRL),
+	(KEY_RIGHTCTRL!(), xkb::MOD_NAME_CTRL),
+	(KEY_LEFTALT!(), xkb::MOD_NAME_ALT),
+	(KEY_RIGHTALT!(), xkb::MOD_NAME_ALT),
+	(KEY_LEFTMETA!(), xkb::MOD_NAME_LOGO),
+	(KEY_RIGHTMETA!(), xkb::MOD_NAME_LOGO),
+	(KEY_CAPSLOCK!(), xkb::MOD_NAME_CAPS),
+];
+/// Shorter names for keys that don't type a character, going by their keysym name.
+const SHORT_NAMES: [(&str, &str); 11] = [
+	("BackSpace", "Bksp"),
+	("Return", "Enter"),
+	("Escape", "Esc"),
+	("Control", "Ctrl"),
+	("Caps_Lock", "Caps"),
+	("ISO_Level3_Shift", "AltGr"),
+	("Mode_switch", "AltGr"),
+	("ISO_Left_Tab", "Tab"),
+	("Multi_key", "Menu"),
+	("space", ""),
+	("NoSymbol", ""),
+];
+
+/// Width of a key and height of a row on the model, in meters.
+const KEY_PITCH: f32 = 0.0032;
+const ROW_PITCH: f32 = 0.0045;
+/// Where the middle of the top row's left edge is on the model.
+const ORIGIN: [f32; 2] = [-0.024, 0.0065];
+const KEY_GAP: f32 = 0.0004;
+const LEGEND_HEIGHT: f32 = 0.0012;
+const PAD_Z: f32 = 0.0026;
+const LEGEND_Z: f32 = 0.0028;
+const PAD_OFF: Rgba<f32> = rgba!(0.1, 0.1, 0.1, 1.0);
+
+struct Keycap {
+	scancode: u32,
+	half_width: f32,
+	legend: Text,
+	pad: Lines,
+	/// Legend and pad color last sent, to only send changes.
+	shown: Option<(String, Rgba<f32>)>,
+}
+
+pub struct Keycaps(Vec<Keycap>);
+impl Keycaps {
+	pub fn create(model_parent: &Spatial) -> Result<Self> {
+		let mut keycaps = Vec::new();
+		for (row, keys) in ROWS.iter().enumerate() {
+			let y = ORIGIN[1] - row as f32 * ROW_PITCH;
+			let mut x = ORIGIN[0];
+			for (scancode, width) in keys.iter() {
+				let width = width * KEY_PITCH;
+				let center = x + width / 2.0;
+				x += width;
+				let half_width = (width - KEY_GAP) / 2.0;
+				keycaps.push(Keycap {
+					scancode: *scancode,
+					half_width,
+					legend: Text::create(
+						model_parent,
+						Transform::from_position([center, y, LEGEND_Z]),
+						"",
+						TextStyle {
+							character_height: LEGEND_HEIGHT,
+							text_align: Alignment::Center.into(),
+							..Default::default()
+						},
+					)?,
+					pad: Lines::create(
+						model_parent,
+						Transform::from_position([center, y, PAD_Z]),
+						&pad_points(half_width, PAD_OFF),
+						false,
+					)?,
+					shown: None,
+				});
+			}
+		}
+		Ok(Keycaps(keycaps))
+	}
+
+	/// Label each key with what it types in `state`, lighting up the keys in `held` and the modifier
+	/// keys whose modifier is on.
+	pub fn show(&mut self, state: Option<&State>, held: &FxHashSet<u32>, lit: Rgba<f32>) {
+		for keycap in &mut self.0 {
+			let legend = state
+				.map(|state| legend(state, keycap.scancode))
+				.unwrap_or_default();
+			let modifier_on = state.is_some_and(|state| {
+				MODIFIER_KEYS.iter().any(|(key, name)| {
+					*key == keycap.scancode
+						&& state.mod_name_is_active(*name, xkb::STATE_MODS_EFFECTIVE)
+				})
+			});
+			let color = if held.contains(&keycap.scancode) || modifier_on {
+				lit
+			} else {
+				PAD_OFF
+			};
+			if keycap.shown.as_ref().is_none_or(|(old, _)| *old != legend) {
+				if let Err(e) = keycap.legend.set_text(&legend) {
+					eprintln!("Couldn't label a keycap: {e}");
+				}
+			}
+			if keycap.shown.as_ref().is_none_or(|(_, old)| *old != color) {
+				if let Err(e) = keycap
+					.pad
+					.update_points(&pad_points(keycap.half_width, color))
+				{
+					eprintln!("Couldn't light up a keycap: {e}");
+				}
+			}
+			keycap.shown = Some((legend, color));
+		}
+	}
+}
+
+/// The character a key types, or a short name for it if it doesn't type one.
+fn legend(state: &State, scancode: u32) -> String {
+	let keysym = state.key_get_one_sym(scancode + EVDEV_OFFSET);
+	match char::from_u32(xkb::keysym_to_utf32(keysym)) {
+		Some(c) if !c.is_control() && !c.is_whitespace() => c.to_string(),
+		_ => {
+			let name = xkb::keysym_get_name(keysym);
+			let name = name
+				.strip_suffix("_L")
+				.or_else(|| name.strip_suffix("_R"))
+				.unwrap_or(&name);
+			SHORT_NAMES
+				.iter()
+				.find(|(long, _)| *long == name)
+				.map_or(name, |(_, short)| short)
+				.to_string()
+		}
+	}
+}
+
+/// A flat line across the key, behind its legend.
+fn pad_points(half_width: f32, color: Rgba<f32>) -> [LinePoint; 2] {
+	[-half_width, half_width].map(|x| LinePoint {
+		point: Vector3::from([x, 0.0, 0.0]),
+		thickness: ROW_PITCH - KEY_GAP,
+		color,
+	})
+}
@@ -0,0 +1,260 @@
+//! Working out which keys produce a character, and patching keymaps for characters no key produces.
+
+use crate::binding::EVDEV_OFFSET;
+use std::ops::{Deref, DerefMut};
+use xkbcommon::xkb::{self, Keycode, Keymap, Keysym, State};
+
+/// Modifier keysyms tried, in every combination, to reach the higher levels of a key.
+const LEVEL_MODIFIERS: [Keysym; 3] = [
+	xkb::KEY_Shift_L,
+	xkb::KEY_ISO_Level3_Shift,
+	xkb::KEY_ISO_Level5_Shift,
+];
+
+/// An xkb keymap or state owned by the keyboard handler, which the Stardust client moves between
+/// its threads. Only the concrete types the handler keeps can be sent.
+#[derive(Default, Clone)]
+pub(crate) struct Xkb<T>(T);
+impl<T> Xkb<T> {
+	pub(crate) fn new(value: T) -> Self {
+		Xkb(value)
+	}
+}
+// SAFETY: xkbcommon objects aren't thread safe, not even their reference counts, so one can only
+// change threads along with every other reference to it. Every `Xkb` lives in the keyboard handler
+// or the remappers and filter it owns, which are only ever reached through the handler's lock, so
+// one thread at a time. The handler never hands out its keymaps or states, and keymaps only come
+// into it freshly compiled: from keymap text on the Stardust side, or from the crate's own config
+// and control code which keeps no copy, so no reference is ever left behind on another thread.
+unsafe impl Send for Xkb<Option<Keymap>> {}
+unsafe impl Sync for Xkb<Option<Keymap>> {}
+unsafe impl Send for Xkb<Option<State>> {}
+unsafe impl Sync for Xkb<Option<State>> {}
+impl<T> Deref for Xkb<T> {
+	type Target = T;
+	fn deref(&self) -> &T {
+		&self.0
+	}
+}
+impl<T> DerefMut for Xkb<T> {
+	fn deref_mut(&mut self) -> &mut T {
+		&mut self.0
+	}
+}
+
+/// A key along with the modifier keys to hold while tapping it, all as evdev codes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyPress {
+	pub key: u32,
+	pub modifiers: Vec<u32>,
+}
+
+/// The keysym that types `character`, following `xkb_utf32_to_keysym`.
+pub fn keysym_for_char(character: char) -> Keysym {
+	match character {
+		'\n' | '\r' => xkb::KEY_Return,
+		'\t' => xkb::KEY_Tab,
+		'\u{8}' => xkb::KEY_BackSpace,
+		'\u{1b}' => xkb::KEY_Escape,
+		'\u{7f}' => xkb::KEY_Delete,
+		' '..='~' | '\u{a0}'..='\u{ff}' => character as Keysym,
+		_ => 0x0100_0000 | character as Keysym,
+	}
+}
+
+/// The evdev codes of the keys that can shift other keys to a higher level in the first layout.
+pub fn level_modifier_keys(keymap: &Keymap) -> Vec<u32> {
+	LEVEL_MODIFIERS
+		.iter()
+		.filter_map(|modifier| {
+			keycodes(keymap).find(|keycode| {
+				keymap
+					.key_get_syms_by_level(*keycode, 0, 0)
+					.contains(modifier)
+			})
+		})
+		.map(|keycode| keycode - EVDEV_OFFSET)
+		.collect()
+}
+
+/// Find a key in the first layout that types `character`, and the fewest `modifier_keys` that reach its level.
+pub fn find_key_press(keymap: &Keymap, modifier_keys: &[u32], character: char) -> Option<KeyPress> {
+	let keysym = keysym_for_char(character);
+	find_press(keymap, modifier_keys, |sym| {
+		sym == keysym || (!character.is_control() && xkb::keysym_to_utf32(sym) == character as u32)
+	})
+}
+
+/// Find a key in the first layout that types `keysym`, and the fewest `modifier_keys` that reach its level.
+pub fn find_keysym_press(
+	keymap: &Keymap,
+	modifier_keys: &[u32],
+	keysym: Keysym,
+) -> Option<KeyPress> {
+	find_press(keymap, modifier_keys, |sym| sym == keysym)
+}
+
+fn find_press(
+	keymap: &Keymap,
+	modifier_keys: &[u32],
+	matches: impl Fn(Keysym) -> bool,
+) -> Option<KeyPress> {
+	keycodes(keymap).find_map(|keycode| {
+		(0..keymap.num_levels_for_key(keycode, 0))
+			.filter(|level| {
+				keymap
+					.key_get_syms_by_level(keycode, 0, *level)
+					.iter()
+					.any(|sym| matches(*sym))
+			})
+			.find_map(|level| {
+				let modifiers = modifiers_for_level(keymap, modifier_keys, keycode, level)?;
+				Some(KeyPress {
+					key: keycode - EVDEV_OFFSET,
+					modifiers,
+				})
+			})
+	})
+}
+
+/// Simulate holding each combination of `modifier_keys`, smallest first, until `keycode` lands on `level`.
+fn modifiers_for_level(
+	keymap: &Keymap,
+	modifier_keys: &[u32],
+	keycode: Keycode,
+	level: u32,
+) -> Option<Vec<u32>> {
+	let mut combinations: Vec<Vec<u32>> = (0..1_u32 << modifier_keys.len())
+		.map(|bits| {
+			modifier_keys
+				.iter()
+				.enumerate()
+				.filter(|(i, _)| bits & (1 << i) != 0)
+				.map(|(_, key)| *key)
+				.collect()
+		})
+		.collect();
+	combinations.sort_by_key(Vec::len);
+	combinations.into_iter().find(|modifiers| {
+		let mut state = State::new(keymap);
+		for modifier in modifiers {
+			state.update_key(modifier + EVDEV_OFFSET, xkb::KeyDirection::Down);
+		}
+		state.key_get_level(keycode, 0) == level
+	})
+}
+
+/// Copy `keymap` with each of `keysyms` put on its own key that had no symbols, for typing characters
+/// the layout doesn't have. Returns the new keymap and the evdev codes the keysyms ended up on, which
+/// is fewer than asked for if the keymap runs out of unused keys.
+pub fn with_extra_keysyms(keymap: &Keymap, keysyms: &[Keysym]) -> Option<(Keymap, Vec<u32>)> {
+	let unused: Vec<(Keycode, String)> = keycodes(keymap)
+		.filter(|keycode| keymap.num_layouts_for_key(*keycode) == 0)
+		.filter_map(|keycode| Some((keycode, keymap.key_get_name(keycode)?.to_string())))
+		.take(keysyms.len())
+		.collect();
+
+	let mut text = keymap.get_as_string(xkb::KEYMAP_FORMAT_TEXT_V1);
+	let symbols_start = text.find("xkb_symbols")?;
+	let insert_at = symbols_start + text[symbols_start..].find('\n')? + 1;
+	let keys: String = unused
+		.iter()
+		.zip(keysyms)
+		.map(|((_, name), keysym)| {
+			format!(
+				"\tkey <{name}> {{ [ {} ] }};\n",
+				xkb::keysym_get_name(*keysym)
+			)
+		})
+		.collect();
+	text.insert_str(insert_at, &keys);
+
+	let patched = Keymap::new_from_string(
+		&xkb::Context::new(xkb::CONTEXT_NO_FLAGS),
+		text,
+		xkb::KEYMAP_FORMAT_TEXT_V1,
+		xkb::KEYMAP_COMPILE_NO_FLAGS,
+	)?;
+	let keys = unused
+		.into_iter()
+		.map(|(keycode, _)| keycode - EVDEV_OFFSET)
+		.collect();
+	Some((patched, keys))
+}
+
+/// Every keycode in the keymap that has an evdev code.
+fn keycodes(keymap: &Keymap) -> impl Iterator<Item = Keycode> {
+	keymap.min_keycode().max(EVDEV_OFFSET)..=keymap.max_keycode()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+	use super::*;
+	use crate::config::KeymapConfig;
+	use input_event_codes::{KEY_A, KEY_ENTER, KEY_LEFTSHIFT};
+
+	/// The US layout, for tests that need a keymap without an X server.
+	pub(crate) fn us_keymap() -> Keymap {
+		KeymapConfig {
+			layout: "us".into(),
+			..Default::default()
+		}
+		.compile()
+		.unwrap()
+	}
+	fn press(character: char) -> Option<KeyPress> {
+		let keymap = us_keymap();
+		find_key_press(&keymap, &level_modifier_keys(&keymap), character)
+	}
+
+	#[test]
+	fn capitals_need_shift() {
+		let shift = vec![KEY_LEFTSHIFT!()];
+		assert_eq!(
+			press('a'),
+			Some(KeyPress {
+				key: KEY_A!(),
+				modifiers: Vec::new()
+			})
+		);
+		assert_eq!(
+			press('A'),
+			Some(KeyPress {
+				key: KEY_A!(),
+				modifiers: shift
+			})
+		);
+	}
+
+	#[test]
+	fn newlines_press_return() {
+		assert_eq!(keysym_for_char('\n'), xkb::KEY_Return);
+		assert_eq!(
+			press('\n'),
+			Some(KeyPress {
+				key: KEY_ENTER!(),
+				modifiers: Vec::new()
+			})
+		);
+	}
+
+	#[test]
+	fn missing_characters_go_on_spare_keys() {
+		let keymap = us_keymap();
+		assert_eq!(press('☃'), None);
+		let keysym = keysym_for_char('☃');
+		let (patched, keys) = with_extra_keysyms(&keymap, &[keysym]).unwrap();
+		assert_eq!(keys.len(), 1);
+		assert_eq!(
+			patched.key_get_syms_by_level(keys[0] + EVDEV_OFFSET, 0, 0),
+			[keysym]
+		);
+		assert_eq!(
+			find_key_press(&patched, &level_modifier_keys(&patched), '☃'),
+			Some(KeyPress {
+				key: keys[0],
+				modifiers: Vec::new()
+			})
+		);
+	}
+}
@@ -0,0 +1,123 @@
+//! How long input takes from reaching manifold to being sent to the receivers, kept as histograms
+//! per kind of input so they can be read over the control socket.
+
+use serde::{Deserialize, Serialize};
+use std::{
+	sync::atomic::{AtomicU64, Ordering},
+	time::{Duration, Instant},
+};
+
+/// Upper bounds of the buckets in microseconds, doubling from 64µs to about a second. Anything
+/// slower goes in one last bucket.
+const BUCKETS: usize = 15;
+const FIRST_BUCKET_MICROS: u64 = 64;
+
+pub static LATENCY: Latency = Latency {
+	key: Histogram::new(),
+	motion: Histogram::new(),
+	button: Histogram::new(),
+	scroll: Histogram::new(),
+};
+
+pub struct Latency {
+	pub key: Histogram,
+	/// From the oldest of the coalesced motion events.
+	pub motion: Histogram,
+	pub button: Histogram,
+	pub scroll: Histogram,
+}
+impl Latency {
+	pub fn snapshot(&self) -> LatencySnapshot {
+		LatencySnapshot {
+			key: self.key.snapshot(),
+			motion: self.motion.snapshot(),
+			button: self.button.snapshot(),
+			scroll: self.scroll.snapshot(),
+		}
+	}
+	pub fn reset(&self) {
+		for histogram in [&self.key, &self.motion, &self.button, &self.scroll] {
+			histogram.reset();
+		}
+	}
+}
+
+pub struct Histogram {
+	buckets: [AtomicU64; BUCKETS + 1],
+	total_micros: AtomicU64,
+	max_micros: AtomicU64,
+}
+impl Histogram {
+	const fn new() -> Self {
+		Histogram {
+			buckets: [const { AtomicU64::new(0) }; BUCKETS + 1],
+			total_micros: AtomicU64::new(0),
+			max_micros: AtomicU64::new(0),
+		}
+	}
+
+	/// Count input that arrived at `received` and was sent just now.
+	pub fn record_since(&self, received: Instant) {
+		self.record(received.elapsed());
+	}
+	pub fn record(&self, latency: Duration) {
+		let micros = latency.as_micros().min(u64::MAX as u128) as u64;
+		let bucket = (0..BUCKETS)
+			.find(|i| micros <= FIRST_BUCKET_MICROS << i)
+			.unwrap_or(BUCKETS);
+		self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+		self.total_micros.fetch_add(micros, Ordering::Relaxed);
+		self.max_micros.fetch_max(micros, Ordering::Relaxed);
+	}
+
+	fn reset(&self) {
+		for bucket in &self.buckets {
+			bucket.store(0, Ordering::Relaxed);
+		}
+		self.total_micros.store(0, Ordering::Relaxed);
+		self.max_micros.store(0, Ordering::Relaxed);
+	}
+
+	fn snapshot(&self) -> HistogramSnapshot {
+		let buckets: Vec<Bucket> = self
+			.buckets
+			.iter()
+			.enumerate()
+			.map(|(i, count)| Bucket {
+				le_micros: (i < BUCKETS).then(|| FIRST_BUCKET_MICROS << i),
+				count: count.load(Ordering::Relaxed),
+			})
+			.collect();
+		let count = buckets.iter().map(|bucket| bucket.count).sum();
+		HistogramSnapshot {
+			count,
+			mean_micros: self.total_micros.load(Ordering::Relaxed) / count.max(1),
+			max_micros: self.max_micros.load(Ordering::Relaxed),
+			buckets,
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct LatencySnapshot {
+	pub key: HistogramSnapshot,
+	pub motion: HistogramSnapshot,
+	pub button: HistogramSnapshot,
+	pub scroll: HistogramSnapshot,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct HistogramSnapshot {
+	pub count: u64,
+	pub mean_micros: u64,
+	pub max_micros: u64,
+	pub buckets: Vec<Bucket>,
+}
+
+/// How many events took at most `le_micros`, and more than the bucket before's. The last bucket has
+/// no upper bound.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Bucket {
+	pub le_micros: Option<u64>,
+	pub count: u64,
+}
@@ -0,0 +1,75 @@
+//! Caps, Num and Scroll Lock as the receivers should see them, shown as lights on the keyboard model.
+
+use color::{rgba, Rgba};
+use color_eyre::eyre::Result;
+use mint::Vector3;
+use stardust_xr_fusion::{
+	core::values::Transform,
+	drawable::{LinePoint, Lines},
+	spatial::Spatial,
+};
+use xkbcommon::xkb::{self, State};
+
+/// Where the lights sit on the keyboard model, left to right, in the model's space.
+const LED_POSITIONS: [[f32; 3]; 3] = [
+	[0.015, 0.012, 0.0025],
+	[0.018, 0.012, 0.0025],
+	[0.021, 0.012, 0.0025],
+];
+const LED_SIZE: f32 = 0.0015;
+const LED_ON: Rgba<f32> = rgba!(0.3, 1.0, 0.4, 1.0);
+const LED_OFF: Rgba<f32> = rgba!(0.15, 0.15, 0.15, 1.0);
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LockLeds {
+	pub caps_lock: bool,
+	pub num_lock: bool,
+	pub scroll_lock: bool,
+}
+impl LockLeds {
+	pub fn from_state(state: &State) -> Self {
+		LockLeds {
+			caps_lock: state.led_name_is_active(xkb::LED_NAME_CAPS),
+			num_lock: state.led_name_is_active(xkb::LED_NAME_NUM),
+			scroll_lock: state.led_name_is_active(xkb::LED_NAME_SCROLL),
+		}
+	}
+}
+
+/// The three lights, in the same order as the keyboard's.
+pub struct LedIndicators([Lines; 3]);
+impl LedIndicators {
+	pub fn create(model_parent: &Spatial) -> Result<Self> {
+		let [num_lock, caps_lock, scroll_lock] = LED_POSITIONS.map(|position| {
+			Lines::create(
+				model_parent,
+				Transform::from_position(position),
+				&led_points(false),
+				false,
+			)
+		});
+		Ok(LedIndicators([num_lock?, caps_lock?, scroll_lock?]))
+	}
+
+	pub fn show(&self, leds: LockLeds) {
+		for (lines, on) in self
+			.0
+			.iter()
+			.zip([leds.num_lock, leds.caps_lock, leds.scroll_lock])
+		{
+			if let Err(e) = lines.update_points(&led_points(on)) {
+				eprintln!("Couldn't light up a lock key indicator: {e}");
+			}
+		}
+	}
+}
+
+/// A dot of a line, since lines are the only thing that can be drawn without a model.
+fn led_points(on: bool) -> [LinePoint; 2] {
+	let color = if on { LED_ON } else { LED_OFF };
+	[-LED_SIZE / 2.0, LED_SIZE / 2.0].map(|x| LinePoint {
+		point: Vector3::from([x, 0.0, 0.0]),
+		thickness: LED_SIZE,
+		color,
+	})
+}
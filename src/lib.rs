@@ -0,0 +1,627 @@
+//! Take input from your 2D desktop and shove it into 3D!
+//!
+//! The [`Manifold`] root spawns a keyboard and a mouse [`Emitter`] that Stardust pulse receivers
+//! can connect to. Other clients can embed those emitters directly and drive them through
+//! [`KeyboardHandler::send_key`] and [`MouseHandler::send_event`], or run the whole thing with
+//! the desktop input window through [`run`].
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use manifest_dir_macros::directory_relative_path;
+use parking_lot::Mutex;
+use stardust_xr_fusion::{client::Client, core::messenger::MessengerError};
+use std::{
+	fmt,
+	future::Future,
+	panic::{self, PanicHookInfo},
+	path::PathBuf,
+	sync::Arc,
+	thread,
+	time::Duration,
+};
+use tokio::{
+	runtime::Handle,
+	sync::{oneshot, watch, Notify},
+	task::JoinHandle,
+};
+use winit::{
+	event_loop::{EventLoopBuilder, EventLoopProxy},
+	platform::{wayland::EventLoopBuilderExtWayland, x11::EventLoopBuilderExtX11},
+};
+
+pub(crate) mod accessibility;
+pub(crate) mod binding;
+pub(crate) mod commands;
+pub(crate) mod config;
+pub(crate) mod control;
+pub(crate) mod dwell;
+pub(crate) mod emitter;
+pub(crate) mod evdev;
+pub(crate) mod input;
+pub(crate) mod input_window;
+pub(crate) mod keyboard;
+pub(crate) mod keyboard_grab;
+pub(crate) mod keycaps;
+pub(crate) mod keymap;
+pub(crate) mod latency;
+pub(crate) mod leds;
+pub(crate) mod manifold;
+pub(crate) mod motion;
+pub(crate) mod mouse;
+pub(crate) mod mouse_emulation;
+pub(crate) mod mouse_feedback;
+pub(crate) mod mouse_keys;
+pub(crate) mod remap;
+pub(crate) mod script;
+
+use crate::input_window::{InputWindow, InputWindowEvent};
+pub use crate::{
+	binding::{KeyBinding, Modifiers},
+	config::{
+		AccessibilityConfig, Config, DwellConfig, EmitterConfig, GrabConfig, GrabTrigger, KeyRemap,
+		KeymapConfig, MacroConfig, MouseEmulationConfig, MouseKeysConfig, RemapConfig, RemapRules,
+		RepeatConfig, WindowConfig,
+	},
+	dwell::DwellMode,
+	emitter::{ConnectionEvent, Emittable, Emitter, Placement, ReceiverNames},
+	input::InputEvent,
+	keyboard::{Keyboard, KeyboardHandler},
+	leds::LockLeds,
+	manifold::{Manifold, Placements},
+	mouse::{Mouse, MouseHandler, MouseStatus},
+	remap::KeyRepeat,
+	script::ScriptStep,
+};
+
+/// Directory holding the `manifold` namespaced resources (the emitter models).
+/// Pass it to [`Client::set_base_prefixes`] before creating any emitters.
+pub const RESOURCE_DIR: &str = directory_relative_path!("res");
+
+/// How long to give pulse receivers to show up before scripted input is sent.
+const RECEIVER_DISCOVERY_TIME: Duration = Duration::from_millis(500);
+/// How long to wait before reconnecting to the Stardust server, doubling after every failed
+/// attempt up to the max.
+const RECONNECT_DELAY: Duration = Duration::from_millis(250);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+/// Where input comes from.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Backend {
+	/// An X11 window that confines the cursor.
+	#[default]
+	X11,
+	/// A Wayland window that locks the pointer.
+	Wayland,
+	/// Read `/dev/input` devices directly, no window.
+	Evdev,
+}
+
+#[derive(
+	Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Deserialize, serde::Serialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum EmitterKind {
+	Keyboard,
+	Mouse,
+}
+impl fmt::Display for EmitterKind {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			EmitterKind::Keyboard => f.write_str("keyboard"),
+			EmitterKind::Mouse => f.write_str("mouse"),
+		}
+	}
+}
+
+/// How to start manifold, usually filled in from the command line.
+#[derive(Debug, Default, Clone)]
+pub struct Options {
+	/// Config file to use instead of the one in `$XDG_CONFIG_HOME`.
+	pub config_path: Option<PathBuf>,
+	/// Emitters to spawn, overriding the config's `enabled` settings.
+	pub emitters: Option<Vec<EmitterKind>>,
+	/// Keymap names that override the config's, empty names are left alone.
+	pub keymap: KeymapConfig,
+	pub backend: Backend,
+	/// Devices for the evdev backend, every readable one if empty.
+	pub devices: Vec<PathBuf>,
+	/// Where to put the control socket instead of `$XDG_RUNTIME_DIR/manifold.sock`.
+	pub control_socket: Option<PathBuf>,
+}
+impl Options {
+	pub fn config_path(&self) -> Option<PathBuf> {
+		self.config_path.clone().or_else(Config::path)
+	}
+
+	/// Load the config and apply the overrides from these options on top.
+	pub fn load_config(&self) -> Result<Config> {
+		let mut config = match self.config_path() {
+			Some(path) => Config::load_from(&path)?,
+			None => Config::default(),
+		};
+		self.apply(&mut config);
+		Ok(config)
+	}
+
+	fn apply(&self, config: &mut Config) {
+		if let Some(emitters) = &self.emitters {
+			config.keyboard.enabled = emitters.contains(&EmitterKind::Keyboard);
+			config.mouse.enabled = emitters.contains(&EmitterKind::Mouse);
+		}
+		// a layout from the command line wins over the list to cycle through
+		if !self.keymap.layout.is_empty() || !self.keymap.variant.is_empty() {
+			config.keymap.layouts.clear();
+		}
+		let overrides = [
+			(&mut config.keymap.rules, &self.keymap.rules),
+			(&mut config.keymap.model, &self.keymap.model),
+			(&mut config.keymap.layout, &self.keymap.layout),
+			(&mut config.keymap.variant, &self.keymap.variant),
+			(&mut config.keymap.options, &self.keymap.options),
+		];
+		for (name, name_override) in overrides {
+			if !name_override.is_empty() {
+				name.clone_from(name_override);
+			}
+		}
+	}
+}
+
+type StardustEventLoop = JoinHandle<Result<(), MessengerError>>;
+async fn connect(
+	config: &Config,
+	placements: &Placements,
+) -> Result<(Arc<Client>, StardustEventLoop, Arc<Mutex<Manifold>>)> {
+	let (client, stardust_event_loop) = Client::connect_with_async_loop().await?;
+	client.set_base_prefixes(&[RESOURCE_DIR]);
+	let manifold = Manifold::new(&client, config.clone(), placements.clone())?;
+	let manifold = client.wrap_root(manifold)?;
+	Ok((client, stardust_event_loop, manifold))
+}
+
+fn spawn_control_socket(
+	options: &Options,
+	manifold: Arc<Mutex<Manifold>>,
+	window: Option<EventLoopProxy<InputWindowEvent>>,
+) -> JoinHandle<()> {
+	let path = options
+		.control_socket
+		.clone()
+		.unwrap_or_else(control::default_path);
+	tokio::task::spawn(async move {
+		if let Err(e) = control::serve(path, manifold, window).await {
+			eprintln!("Control socket stopped: {e:?}");
+		}
+	})
+}
+
+/// Keep the input window told about the lock LEDs of the focused receiver, following the keyboard
+/// across reloads.
+fn spawn_led_mirror(
+	manifold: Arc<Mutex<Manifold>>,
+	proxy: EventLoopProxy<InputWindowEvent>,
+) -> JoinHandle<()> {
+	spawn_mirror(
+		manifold,
+		proxy,
+		|manifold| Some(manifold.keyboard()?.lock().watch_leds()),
+		InputWindowEvent::SetLeds,
+	)
+}
+
+/// Keep the input window told about mouse keys and dwell clicking, following the mouse across
+/// reloads.
+fn spawn_mouse_status_mirror(
+	manifold: Arc<Mutex<Manifold>>,
+	proxy: EventLoopProxy<InputWindowEvent>,
+) -> JoinHandle<()> {
+	spawn_mirror(
+		manifold,
+		proxy,
+		|manifold| Some(manifold.mouse()?.lock().watch_status()),
+		InputWindowEvent::MouseStatus,
+	)
+}
+
+/// Send every value of an emitter's watch to the input window, watching it again whenever the
+/// emitter is rebuilt and stopping once it's gone.
+fn spawn_mirror<T: Copy + Send + Sync + 'static>(
+	manifold: Arc<Mutex<Manifold>>,
+	proxy: EventLoopProxy<InputWindowEvent>,
+	watch: fn(&Manifold) -> Option<watch::Receiver<T>>,
+	event: fn(T) -> InputWindowEvent,
+) -> JoinHandle<()> {
+	tokio::task::spawn(async move {
+		loop {
+			let Some(mut values) = watch(&manifold.lock()) else {
+				return;
+			};
+			loop {
+				let current = *values.borrow_and_update();
+				if proxy.send_event(event(current)).is_err() {
+					return;
+				}
+				// closes when the emitter is rebuilt
+				if values.changed().await.is_err() {
+					break;
+				}
+			}
+		}
+	})
+}
+
+/// Connect to the Stardust server, spawn the emitters and forward input from the backend until either
+/// side quits. Losing the server doesn't quit: the input window stays open, or the devices keep being
+/// read, while manifold reconnects, and everything is rebuilt once the server's back.
+pub async fn run(options: Options) -> Result<()> {
+	let mut config = options.load_config()?;
+	let placements = Placements::default();
+	let (client, stardust_event_loop, manifold) = connect(&config, &placements).await?;
+
+	let (current_manifold, current_manifold_rx) = watch::channel(None);
+	let commands = commands::spawn(current_manifold_rx);
+	let quit = Arc::new(Notify::new());
+	tokio::task::spawn({
+		let quit = quit.clone();
+		async move {
+			if tokio::signal::ctrl_c().await.is_ok() {
+				quit.notify_one();
+			}
+		}
+	});
+	let input_window = match options.backend {
+		Backend::Evdev => {
+			let devices = if options.devices.is_empty() {
+				evdev::devices()
+			} else {
+				options.devices.clone()
+			};
+			evdev::spawn(&devices, commands)?;
+			None
+		}
+		Backend::X11 | Backend::Wayland => {
+			Some(InputWindowThread::spawn(&options, &config, commands, quit.clone()).await?)
+		}
+	};
+	let proxy = input_window
+		.as_ref()
+		.map(|input_window| input_window.proxy.clone());
+	let _panic_hook = proxy.clone().map(release_grab_on_panic);
+
+	let mut connection = Some((client, stardust_event_loop, manifold));
+	loop {
+		let (client, mut stardust_event_loop, manifold) = match connection.take() {
+			Some(connection) => connection,
+			None => match reconnect(&options, &mut config, &placements, &quit).await {
+				Some(connection) => connection,
+				None => break,
+			},
+		};
+		current_manifold.send_replace(Some(manifold.clone()));
+		if proxy.is_none() {
+			set_config_keymap(&manifold.lock());
+		}
+		let tasks = spawn_connection_tasks(&options, &manifold, proxy.clone());
+		if let Some(proxy) = &proxy {
+			let _ = proxy.send_event(InputWindowEvent::Reload(Box::new(config.clone())));
+			let _ = proxy.send_event(InputWindowEvent::Connected(true));
+		}
+
+		let result = tokio::select! {
+			result = &mut stardust_event_loop => Some(result),
+			_ = quit.notified() => None,
+		};
+		for task in tasks {
+			task.abort();
+		}
+		current_manifold.send_replace(None);
+		// pick up where the config reloads got to
+		config = manifold.lock().config().clone();
+		let Some(result) = result else {
+			client.stop_loop();
+			let _ = stardust_event_loop.await;
+			break;
+		};
+		match result {
+			Ok(Ok(())) => eprintln!("The Stardust server went away, reconnecting"),
+			Ok(Err(e)) => eprintln!("Lost the Stardust server, reconnecting: {e}"),
+			Err(e) => eprintln!("Lost the Stardust server, reconnecting: {e}"),
+		}
+		if let Some(proxy) = &proxy {
+			let _ = proxy.send_event(InputWindowEvent::Connected(false));
+		}
+	}
+
+	match input_window {
+		Some(input_window) => input_window.stop(),
+		None => Ok(()),
+	}
+}
+
+/// The thread the input window's event loop runs on.
+struct InputWindowThread {
+	thread: thread::JoinHandle<Result<()>>,
+	stop: oneshot::Sender<()>,
+	proxy: EventLoopProxy<InputWindowEvent>,
+}
+impl InputWindowThread {
+	async fn spawn(
+		options: &Options,
+		config: &Config,
+		commands: commands::CommandSender,
+		quit: Arc<Notify>,
+	) -> Result<Self> {
+		let tokio_handle = Handle::current();
+		let (stop, mut stop_rx) = oneshot::channel::<()>();
+		let (proxy_tx, proxy_rx) = oneshot::channel();
+		let thread = thread::Builder::new().name("winit".to_owned()).spawn({
+			let config = config.clone();
+			let config_path = options.config_path();
+			let backend = options.backend;
+			move || -> Result<()> {
+				let _tokio_guard = tokio_handle.enter();
+				let mut event_loop_builder = EventLoopBuilder::with_user_event();
+				if backend == Backend::Wayland {
+					EventLoopBuilderExtWayland::with_any_thread(&mut event_loop_builder, true)
+						.with_wayland();
+				} else {
+					EventLoopBuilderExtX11::with_any_thread(&mut event_loop_builder, true)
+						.with_x11();
+				}
+				let event_loop = event_loop_builder.build();
+				let _ = proxy_tx.send(event_loop.create_proxy());
+				let mut input_window = match InputWindow::new(
+					&event_loop,
+					quit.clone(),
+					commands,
+					config,
+					config_path,
+				) {
+					Ok(input_window) => input_window,
+					Err(e) => {
+						quit.notify_one();
+						return Err(e);
+					}
+				};
+
+				event_loop.run(move |event, _, control_flow| {
+					match stop_rx.try_recv() {
+						Ok(_) => {
+							control_flow.set_exit();
+							return;
+						}
+						Err(ref e) if *e == oneshot::error::TryRecvError::Closed => {
+							control_flow.set_exit();
+							return;
+						}
+						_ => (),
+					}
+
+					input_window.handle_event(event);
+					match input_window.next_deadline() {
+						Some(deadline) => control_flow.set_wait_until(deadline),
+						None => control_flow.set_wait(),
+					}
+				});
+			}
+		})?;
+		Ok(InputWindowThread {
+			thread,
+			stop,
+			proxy: proxy_rx.await.wrap_err("The input window thread died")?,
+		})
+	}
+
+	/// Close the window and wait for its thread, returning the error it stopped with if any.
+	fn stop(self) -> Result<()> {
+		let _ = self.stop.send(());
+		// wake the event loop up so it sees the stop
+		let _ = self.proxy.send_event(InputWindowEvent::SetGrab(false));
+		self.thread
+			.join()
+			.map_err(|_| eyre!("The input window panicked"))?
+	}
+}
+
+type PanicHook = dyn Fn(&PanicHookInfo<'_>) + Sync + Send + 'static;
+
+/// Let go of the cursor and keyboard whenever another thread panics, so the desktop doesn't stay
+/// stuck in the input window, and show the panic there. The panic hook that was there before is
+/// still called first, and put back when the returned guard is dropped. The input window's own
+/// thread lets go as it unwinds instead, by dropping the window.
+fn release_grab_on_panic(proxy: EventLoopProxy<InputWindowEvent>) -> RestorePanicHook {
+	let proxy = Mutex::new(proxy);
+	let previous: Arc<PanicHook> = panic::take_hook().into();
+	panic::set_hook(Box::new({
+		let previous = previous.clone();
+		move |info| {
+			previous(info);
+			let message = info.payload_as_str().unwrap_or("unknown panic");
+			let error = match info.location() {
+				Some(location) => format!("panicked at {location}: {message}"),
+				None => format!("panicked: {message}"),
+			};
+			let proxy = proxy.lock();
+			let _ = proxy.send_event(InputWindowEvent::SetGrab(false));
+			let _ = proxy.send_event(InputWindowEvent::Error(error));
+		}
+	}));
+	RestorePanicHook(previous)
+}
+
+/// Puts back the panic hook from before [`release_grab_on_panic`] when dropped.
+struct RestorePanicHook(Arc<PanicHook>);
+impl Drop for RestorePanicHook {
+	fn drop(&mut self) {
+		// the hook can't be changed while panicking, and the process is going down anyway
+		if !thread::panicking() {
+			let previous = self.0.clone();
+			panic::set_hook(Box::new(move |info| previous(info)));
+		}
+	}
+}
+
+/// Keep trying to connect again, waiting longer after every failed attempt, until it works or the
+/// input window is closed. The config is read again first in case it changed in the meantime, and
+/// the emitters are put back where they were left.
+async fn reconnect(
+	options: &Options,
+	config: &mut Config,
+	placements: &Placements,
+	quit: &Notify,
+) -> Option<(Arc<Client>, StardustEventLoop, Arc<Mutex<Manifold>>)> {
+	let mut delay = RECONNECT_DELAY;
+	loop {
+		tokio::select! {
+			_ = tokio::time::sleep(delay) => (),
+			_ = quit.notified() => return None,
+		}
+		match options.load_config() {
+			Ok(new_config) => *config = new_config,
+			Err(e) => eprintln!("Keeping the last config: {e:?}"),
+		}
+		let connection = tokio::select! {
+			connection = connect(config, placements) => connection,
+			_ = quit.notified() => return None,
+		};
+		match connection {
+			Ok(connection) => return Some(connection),
+			Err(e) => {
+				delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+				eprintln!(
+					"Couldn't reconnect to the Stardust server, trying again in {delay:?}: {e}"
+				);
+			}
+		}
+	}
+}
+
+/// Start everything that works on one connection's manifold: the control socket, reloading the
+/// config when it changes and, if there's an input window, keeping it up to date.
+fn spawn_connection_tasks(
+	options: &Options,
+	manifold: &Arc<Mutex<Manifold>>,
+	proxy: Option<EventLoopProxy<InputWindowEvent>>,
+) -> Vec<JoinHandle<()>> {
+	let mut tasks = vec![spawn_control_socket(
+		options,
+		manifold.clone(),
+		proxy.clone(),
+	)];
+	if let Some(proxy) = &proxy {
+		tasks.push(spawn_led_mirror(manifold.clone(), proxy.clone()));
+		tasks.push(spawn_mouse_status_mirror(manifold.clone(), proxy.clone()));
+	}
+	if let Some(path) = options.config_path() {
+		let manifold = manifold.clone();
+		let options = options.clone();
+		tasks.push(tokio::task::spawn(Config::watch(
+			path,
+			move |mut config| {
+				options.apply(&mut config);
+				let reloaded = manifold.lock().reload(config.clone());
+				// the input window picks the keymap when there is one
+				match &proxy {
+					Some(proxy) => {
+						let _ = proxy.send_event(InputWindowEvent::Reload(Box::new(config)));
+					}
+					None => set_config_keymap(&manifold.lock()),
+				}
+				if let Err(e) = reloaded {
+					eprintln!("{e:?}");
+					if let Some(proxy) = &proxy {
+						let _ = proxy.send_event(InputWindowEvent::Error(format!("{e:#}")));
+					}
+				}
+			},
+		)));
+	}
+	tasks
+}
+
+/// Give the keyboard the keymap the config names, for when there's no input window to choose it.
+fn set_config_keymap(manifold: &Manifold) {
+	let Some(keyboard) = manifold.keyboard() else {
+		return;
+	};
+	match manifold.config().keymap.compile() {
+		Ok(keymap) => keyboard.lock().set_keymap(keymap),
+		Err(e) => eprintln!("Couldn't compile the keymap: {e}"),
+	}
+}
+
+/// Spawn the emitters without any input backend, give receivers a moment to connect, then run `f` and disconnect.
+async fn headless<F, Fut>(options: &Options, f: F) -> Result<()>
+where
+	F: FnOnce(Option<Keyboard>, Option<Mouse>) -> Fut,
+	Fut: Future<Output = Result<()>>,
+{
+	let config = options.load_config()?;
+	let (client, stardust_event_loop, manifold) = connect(&config, &Placements::default()).await?;
+	let keyboard = manifold.lock().keyboard();
+	let mouse = manifold.lock().mouse();
+	if let Some(keyboard) = &keyboard {
+		keyboard.lock().set_keymap(config.keymap.compile()?);
+	}
+
+	tokio::time::sleep(RECEIVER_DISCOVERY_TIME).await;
+	let result = f(keyboard, mouse).await;
+	// give the messenger a chance to flush everything that was just sent
+	tokio::time::sleep(Duration::from_millis(100)).await;
+	client.stop_loop();
+	stardust_event_loop.await??;
+	result
+}
+
+/// Play an input script, one JSON [`InputEvent`] or `{"delay": seconds}` per line, into whatever
+/// receivers are connected.
+pub async fn replay(options: &Options, path: PathBuf) -> Result<()> {
+	let steps = script::load(&path)?;
+	headless(options, |keyboard, mouse| async move {
+		script::play(&steps, keyboard.as_ref(), mouse.as_ref()).await;
+		Ok(())
+	})
+	.await
+}
+
+/// Type `text` into whatever keyboard receivers are connected.
+pub async fn type_text(options: &Options, text: String) -> Result<()> {
+	headless(options, |keyboard, _| async move {
+		let keyboard = keyboard.ok_or_else(|| eyre!("The keyboard emitter is disabled"))?;
+		let mut keyboard = keyboard.lock();
+		let missing = keyboard.type_text(&text);
+		if !missing.is_empty() {
+			if keyboard.has_keymap() {
+				eprintln!("Ran out of spare keys for {missing:?}, skipped them");
+			} else {
+				eprintln!("No keymap to find the keys for {missing:?} in, skipped them");
+			}
+		}
+		Ok(())
+	})
+	.await
+}
+
+/// Print the UIDs of the receivers each emitter can see.
+pub async fn list_receivers(options: &Options) -> Result<()> {
+	headless(options, |keyboard, mouse| async move {
+		if let Some(keyboard) = keyboard {
+			for uid in keyboard.lock().connected_receivers() {
+				println!("keyboard {uid}");
+			}
+		}
+		if let Some(mouse) = mouse {
+			for uid in mouse.lock().connected_receivers() {
+				println!("mouse {uid}");
+			}
+		}
+		Ok(())
+	})
+	.await
+}
+
+/// Send a JSON request to a running manifold's control socket, the default one if `socket` is
+/// `None`, and print the responses.
+pub async fn send_control_request(socket: Option<PathBuf>, request: &str) -> Result<()> {
+	let socket = socket.unwrap_or_else(control::default_path);
+	control::send(socket, request).await
+}
@@ -1,63 +1,129 @@
+use clap::{Args, Parser, Subcommand};
 use color_eyre::eyre::Result;
-use input_window::InputWindow;
-use manifest_dir_macros::directory_relative_path;
-use manifold::Manifold;
-use stardust_xr_fusion::client::Client;
-use std::thread;
-use tokio::{runtime::Handle, sync::oneshot};
-use winit::{event_loop::EventLoopBuilder, platform::x11::EventLoopBuilderExtX11};
+use stardust_xr_manifold::{Backend, EmitterKind, KeymapConfig, Options};
+use std::path::PathBuf;
 
-pub mod emitter;
-pub mod input_window;
-pub mod keyboard;
-pub mod manifold;
-pub mod mouse;
-
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> Result<()> {
-	color_eyre::install()?;
-	let (client, stardust_event_loop) = Client::connect_with_async_loop().await?;
-	client.set_base_prefixes(&[directory_relative_path!("res")]);
+/// Take input from your 2D desktop and shove it into 3D!
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+	#[command(subcommand)]
+	command: Option<Command>,
+}
 
-	let tokio_handle = Handle::current();
-	let manifold = client.wrap_root(Manifold::new(&client))?;
-	let (winit_stop_tx, mut winit_stop_rx) = oneshot::channel::<()>();
-	let winit_thread = thread::Builder::new().name("winit".to_owned()).spawn({
-		let client = client.clone();
-		let keyboard = manifold.lock().keyboard();
-		let mouse = manifold.lock().mouse();
-		move || -> Result<()> {
-			let _tokio_guard = tokio_handle.enter();
-			let event_loop = EventLoopBuilder::new()
-				.with_any_thread(true)
-				.with_x11()
-				.build();
-			let mut input_window = InputWindow::new(&event_loop, client, keyboard, mouse)?;
+#[derive(Subcommand)]
+enum Command {
+	/// Forward input from the desktop to Stardust (the default)
+	Run(RunArgs),
+	/// Replay a recorded input script into the connected receivers
+	Replay {
+		file: PathBuf,
+		#[command(flatten)]
+		common: CommonArgs,
+	},
+	/// Type text into the connected keyboard receivers
+	Type {
+		text: String,
+		#[command(flatten)]
+		common: CommonArgs,
+	},
+	/// List the receivers each emitter can see
+	ListReceivers {
+		#[command(flatten)]
+		common: CommonArgs,
+	},
+	/// Send a JSON request to a running manifold's control socket and print the response
+	Control {
+		/// Such as '{"request": "grab"}'
+		request: String,
+		/// Control socket to use instead of $XDG_RUNTIME_DIR/manifold.sock
+		#[arg(long)]
+		socket: Option<PathBuf>,
+	},
+}
 
-			event_loop.run(move |event, _, control_flow| {
-				match winit_stop_rx.try_recv() {
-					Ok(_) => {
-						control_flow.set_exit();
-						return;
-					}
-					Err(ref e) if *e == oneshot::error::TryRecvError::Closed => {
-						return;
-					}
-					_ => (),
-				}
+#[derive(Args)]
+struct RunArgs {
+	#[command(flatten)]
+	common: CommonArgs,
+	/// Where to take input from
+	#[arg(long, value_enum, default_value_t)]
+	backend: Backend,
+	/// Input device for the evdev backend, can be repeated (default: every readable device)
+	#[arg(long = "device")]
+	devices: Vec<PathBuf>,
+	/// Control socket to listen on instead of $XDG_RUNTIME_DIR/manifold.sock
+	#[arg(long)]
+	socket: Option<PathBuf>,
+}
 
-				input_window.handle_event(event);
-				control_flow.set_wait();
-			});
+#[derive(Args)]
+struct CommonArgs {
+	/// Config file to use instead of $XDG_CONFIG_HOME/manifold/config.toml
+	#[arg(long)]
+	config: Option<PathBuf>,
+	/// Emitters to spawn, overriding the config
+	#[arg(long, value_enum, value_delimiter = ',')]
+	emitters: Option<Vec<EmitterKind>>,
+	/// xkb rules for the keymap
+	#[arg(long)]
+	rules: Option<String>,
+	/// xkb model for the keymap
+	#[arg(long)]
+	model: Option<String>,
+	/// xkb layout for the keymap, such as "us" or "us,de"
+	#[arg(long)]
+	layout: Option<String>,
+	/// xkb variant for the keymap
+	#[arg(long)]
+	variant: Option<String>,
+	/// xkb options for the keymap, such as "caps:escape"
+	#[arg(long)]
+	options: Option<String>,
+}
+impl CommonArgs {
+	fn into_options(self) -> Options {
+		Options {
+			config_path: self.config,
+			emitters: self.emitters,
+			keymap: KeymapConfig {
+				rules: self.rules.unwrap_or_default(),
+				model: self.model.unwrap_or_default(),
+				layout: self.layout.unwrap_or_default(),
+				variant: self.variant.unwrap_or_default(),
+				options: self.options.unwrap_or_default(),
+				..Default::default()
+			},
+			..Default::default()
 		}
-	})?;
-
-	let result = stardust_event_loop.await?;
+	}
+}
 
-	winit_stop_tx
-		.send(())
-		.expect("Failed to send stop signal to winit thread");
-	winit_thread.join().expect("Couldn't rejoin winit thread")?;
-	result?;
-	Ok(())
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<()> {
+	color_eyre::install()?;
+	match Cli::parse().command {
+		None => stardust_xr_manifold::run(Options::default()).await,
+		Some(Command::Run(args)) => {
+			let options = Options {
+				backend: args.backend,
+				devices: args.devices,
+				control_socket: args.socket,
+				..args.common.into_options()
+			};
+			stardust_xr_manifold::run(options).await
+		}
+		Some(Command::Replay { file, common }) => {
+			stardust_xr_manifold::replay(&common.into_options(), file).await
+		}
+		Some(Command::Type { text, common }) => {
+			stardust_xr_manifold::type_text(&common.into_options(), text).await
+		}
+		Some(Command::ListReceivers { common }) => {
+			stardust_xr_manifold::list_receivers(&common.into_options()).await
+		}
+		Some(Command::Control { request, socket }) => {
+			stardust_xr_manifold::send_control_request(socket, &request).await
+		}
+	}
 }
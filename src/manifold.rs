@@ -1,26 +1,194 @@
-use crate::{emitter::Emitter, keyboard::Keyboard, mouse::Mouse};
-use stardust_xr_fusion::client::{Client, FrameInfo, RootHandler};
+use crate::{
+	config::{Config, EmitterConfig},
+	emitter::{Emittable, Emitter, Placement, ReceiverNames},
+	keyboard::Keyboard,
+	mouse::Mouse,
+};
+use color_eyre::eyre::{Result, WrapErr};
+use stardust_xr_fusion::{
+	client::{Client, FrameInfo, RootHandler},
+	node::NodeType,
+	spatial::Spatial,
+};
+
+/// Where the emitters were left and what their receivers were named. Pass the same ones to every
+/// [`Manifold`] so the emitters come back where they were after reconnecting.
+#[derive(Debug, Default, Clone)]
+pub struct Placements {
+	pub keyboard: Placement,
+	pub mouse: Placement,
+	pub keyboard_names: ReceiverNames,
+	pub mouse_names: ReceiverNames,
+}
 
 pub struct Manifold {
-	keyboard: Emitter<Keyboard>,
-	mouse: Emitter<Mouse>,
+	root: Spatial,
+	config: Config,
+	placements: Placements,
+	keyboard: Option<Emitter<Keyboard>>,
+	mouse: Option<Emitter<Mouse>>,
 }
 impl Manifold {
-	pub fn new(client: &Client) -> Self {
-		let keyboard = Emitter::new(client.get_root(), |grabbable| Keyboard::create(grabbable));
-		let mouse = Emitter::new(client.get_root(), |grabbable| Mouse::create(grabbable));
-		Manifold { keyboard, mouse }
+	pub fn new(client: &Client, config: Config, placements: Placements) -> Result<Self> {
+		config.validate()?;
+		let root = client.get_root().alias();
+		let keyboard = Self::spawn(
+			&root,
+			&config.keyboard,
+			&placements.keyboard,
+			Keyboard::create,
+		)
+		.wrap_err("Couldn't create the keyboard")?;
+		if let Some(keyboard) = &keyboard {
+			configure_keyboard(&keyboard.contained, &config, &placements.keyboard_names);
+		}
+		let mouse = Self::spawn(&root, &config.mouse, &placements.mouse, Mouse::create)
+			.wrap_err("Couldn't create the mouse")?;
+		if let Some(mouse) = &mouse {
+			configure_mouse(&mouse.contained, &config, &placements.mouse_names);
+		}
+		Ok(Manifold {
+			root,
+			config,
+			placements,
+			keyboard,
+			mouse,
+		})
+	}
+	fn spawn<E: Emittable>(
+		root: &Spatial,
+		config: &EmitterConfig,
+		placement: &Placement,
+		create: fn(&Spatial, &EmitterConfig) -> Result<E>,
+	) -> Result<Option<Emitter<E>>> {
+		config
+			.enabled
+			.then(|| {
+				Emitter::new(root, config, placement.clone(), |parent| {
+					create(parent, config)
+				})
+			})
+			.transpose()
 	}
-	pub fn keyboard(&self) -> Keyboard {
-		self.keyboard.contained.clone()
+
+	pub fn config(&self) -> &Config {
+		&self.config
+	}
+	pub fn keyboard(&self) -> Option<Keyboard> {
+		self.keyboard.as_ref().map(|e| e.contained.clone())
 	}
-	pub fn mouse(&self) -> Mouse {
-		self.mouse.contained.clone()
+	pub fn mouse(&self) -> Option<Mouse> {
+		self.mouse.as_ref().map(|e| e.contained.clone())
 	}
+
+	/// Apply a new config, recreating the emitters whose shape changed. An invalid config isn't
+	/// applied at all, while an emitter that can't be recreated is left out and the first such error
+	/// is returned once the rest is applied.
+	pub fn reload(&mut self, config: Config) -> Result<()> {
+		config.validate()?;
+		let mut result = Ok(());
+		if self.config.keyboard.needs_rebuild(&config.keyboard) {
+			self.keyboard = None;
+			match Self::spawn(
+				&self.root,
+				&config.keyboard,
+				&self.placements.keyboard,
+				Keyboard::create,
+			) {
+				Ok(keyboard) => self.keyboard = keyboard,
+				Err(e) => result = Err(e.wrap_err("Couldn't recreate the keyboard")),
+			}
+			if let Some(keyboard) = &self.keyboard {
+				configure_keyboard(
+					&keyboard.contained,
+					&config,
+					&self.placements.keyboard_names,
+				);
+			}
+		} else if let Some(keyboard) = &mut self.keyboard {
+			keyboard.contained.configure(&config.keyboard);
+			if config.keymap != self.config.keymap {
+				keyboard.contained.lock().set_keymap_config(&config.keymap);
+			}
+			if config.remap != self.config.remap {
+				keyboard.contained.lock().set_remap(config.remap.clone());
+			}
+			if config.repeat != self.config.repeat {
+				keyboard
+					.contained
+					.lock()
+					.set_repeat(config.repeat.key_repeat());
+			}
+			if config.accessibility != self.config.accessibility {
+				keyboard
+					.contained
+					.lock()
+					.set_accessibility(&config.accessibility);
+			}
+		}
+		if self.config.mouse.needs_rebuild(&config.mouse) {
+			self.mouse = None;
+			match Self::spawn(
+				&self.root,
+				&config.mouse,
+				&self.placements.mouse,
+				Mouse::create,
+			) {
+				Ok(mouse) => self.mouse = mouse,
+				Err(e) => result = result.and(Err(e.wrap_err("Couldn't recreate the mouse"))),
+			}
+			if let Some(mouse) = &self.mouse {
+				configure_mouse(&mouse.contained, &config, &self.placements.mouse_names);
+			}
+		} else if let Some(mouse) = &mut self.mouse {
+			mouse.contained.configure(&config.mouse);
+			if config.motion_rate != self.config.motion_rate {
+				mouse.contained.set_motion_rate(config.motion_rate);
+			}
+			if config.mouse_keys != self.config.mouse_keys {
+				mouse.contained.lock().set_mouse_keys(&config.mouse_keys);
+			}
+			if config.dwell != self.config.dwell {
+				mouse.contained.lock().set_dwell(&config.dwell);
+			}
+			if config.mouse_emulation != self.config.mouse_emulation {
+				mouse
+					.contained
+					.lock()
+					.set_emulation(&config.mouse_emulation);
+			}
+		}
+		self.config = config;
+		result
+	}
+}
+/// Apply everything in the config that a freshly created keyboard doesn't have yet.
+fn configure_keyboard(keyboard: &Keyboard, config: &Config, names: &ReceiverNames) {
+	let mut keyboard = keyboard.lock();
+	keyboard.set_names(names.clone());
+	keyboard.set_keymap_config(&config.keymap);
+	keyboard.set_remap(config.remap.clone());
+	keyboard.set_repeat(config.repeat.key_repeat());
+	keyboard.set_accessibility(&config.accessibility);
 }
+
+/// Apply everything in the config that a freshly created mouse doesn't have yet.
+fn configure_mouse(mouse: &Mouse, config: &Config, names: &ReceiverNames) {
+	mouse.set_motion_rate(config.motion_rate);
+	let mut mouse = mouse.lock();
+	mouse.set_names(names.clone());
+	mouse.set_mouse_keys(&config.mouse_keys);
+	mouse.set_dwell(&config.dwell);
+	mouse.set_emulation(&config.mouse_emulation);
+}
+
 impl RootHandler for Manifold {
 	fn frame(&mut self, info: FrameInfo) {
-		self.mouse.frame(info);
-		self.keyboard.frame(info);
+		if let Some(mouse) = &mut self.mouse {
+			mouse.frame(info);
+		}
+		if let Some(keyboard) = &mut self.keyboard {
+			keyboard.frame(info);
+		}
 	}
 }
@@ -0,0 +1,72 @@
+//! Pointer motion waiting to be sent, summed up so a fast mouse doesn't send a pulse per event.
+
+use std::time::{Duration, Instant};
+
+pub struct MotionQueue {
+	delta: [f32; 2],
+	/// When the oldest motion still waiting came in.
+	since: Option<Instant>,
+	/// How often motion goes out between Stardust frames, if it isn't just once a frame.
+	interval: Option<Duration>,
+	last_sent: Instant,
+}
+impl MotionQueue {
+	pub fn new(rate: Option<f64>) -> Self {
+		MotionQueue {
+			delta: [0.0; 2],
+			since: None,
+			interval: rate.map(|rate| Duration::from_secs_f64(1.0 / rate)),
+			last_sent: Instant::now(),
+		}
+	}
+
+	pub fn set_rate(&mut self, rate: Option<f64>) {
+		self.interval = rate.map(|rate| Duration::from_secs_f64(1.0 / rate));
+	}
+
+	/// Add motion that came in at `received`, returning true if it's time to send it without
+	/// waiting for the next frame.
+	pub fn push(&mut self, delta: [f32; 2], received: Instant) -> bool {
+		self.delta[0] += delta[0];
+		self.delta[1] += delta[1];
+		self.since.get_or_insert(received);
+		self.interval
+			.is_some_and(|interval| self.last_sent.elapsed() >= interval)
+	}
+
+	/// Everything that's waiting, with when the oldest of it came in.
+	pub fn take(&mut self) -> Option<([f32; 2], Instant)> {
+		let since = self.since.take()?;
+		self.last_sent = Instant::now();
+		Some((std::mem::take(&mut self.delta), since))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn sums_motion_until_taken() {
+		let mut queue = MotionQueue::new(None);
+		assert_eq!(queue.take(), None);
+		let first = Instant::now();
+		assert!(!queue.push([1.0, 2.0], first));
+		assert!(!queue.push([3.0, -1.0], first + Duration::from_millis(5)));
+		assert_eq!(queue.take(), Some(([4.0, 1.0], first)));
+		assert_eq!(queue.take(), None);
+	}
+
+	#[test]
+	fn sends_early_at_the_motion_rate() {
+		let mut queue = MotionQueue::new(Some(10.0));
+		queue.last_sent -= Duration::from_millis(100);
+		assert!(queue.push([1.0, 0.0], Instant::now()));
+		queue.take();
+		// just sent, so the next waits
+		assert!(!queue.push([1.0, 0.0], Instant::now()));
+		queue.set_rate(None);
+		queue.last_sent -= Duration::from_secs(1);
+		assert!(!queue.push([1.0, 0.0], Instant::now()));
+	}
+}
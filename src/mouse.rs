@@ -1,92 +1,412 @@
-use crate::emitter::Emittable;
+use crate::{
+	config::{DwellConfig, EmitterConfig, MouseEmulationConfig, MouseKeysConfig},
+	dwell::{self, Dwell, DwellMode},
+	emitter::{
+		receiver_number, ConnectionEvent, Emittable, ReceiverLine, ReceiverLines, ReceiverNames,
+	},
+	input::InputEvent,
+	latency::LATENCY,
+	motion::MotionQueue,
+	mouse_emulation::MouseEmulation,
+	mouse_feedback::MouseFeedback,
+	mouse_keys::MouseKeys,
+};
 use color::{rgba, Rgba};
-use mint::{Vector2, Vector3};
-use parking_lot::MutexGuard;
+use color_eyre::eyre::Result;
+use mint::Vector2;
+use parking_lot::{Mutex, MutexGuard};
 use rustc_hash::FxHashMap;
 use stardust_xr_fusion::{
 	client::FrameInfo,
 	core::values::Transform,
 	data::{NewReceiverInfo, PulseReceiver, PulseSender, PulseSenderHandler},
-	drawable::{LinePoint, Lines, ResourceID},
+	drawable::ResourceID,
 	fields::UnknownField,
 	node::NodeType,
 	spatial::Spatial,
 	HandlerWrapper,
 };
 use stardust_xr_molecules::mouse::{MouseEvent, MOUSE_MASK};
-use std::sync::Arc;
-
-static MOUSE_COLOR: Rgba<f32> = rgba!(0.141, 0.886, 0.521, 1.0);
+use std::{
+	collections::BTreeMap,
+	sync::Arc,
+	time::{Duration, Instant},
+};
+use tokio::sync::{broadcast, watch};
 
 #[derive(Clone)]
-pub struct Mouse(Arc<HandlerWrapper<PulseSender, MouseHandler>>);
+pub struct Mouse {
+	handler: Arc<HandlerWrapper<PulseSender, MouseHandler>>,
+	/// Kept outside the handler so queueing motion doesn't wait on its lock.
+	motion: Arc<Mutex<MotionQueue>>,
+}
 impl Mouse {
-	pub fn create(spatial_parent: &Spatial) -> Self {
+	pub fn create(spatial_parent: &Spatial, config: &EmitterConfig) -> Result<Self> {
 		let pulse_sender = PulseSender::create(
 			spatial_parent,
-			Transform::from_position(Self::EMIT_POINT),
+			Transform::from_position(config.emit_point::<Self>()),
 			&MOUSE_MASK,
-		)
-		.unwrap();
-		let keyboard_handler = MouseHandler::new(pulse_sender.alias());
-		Mouse(Arc::new(pulse_sender.wrap(keyboard_handler).unwrap()))
+		)?;
+		let mouse_handler = MouseHandler::new(
+			pulse_sender.alias(),
+			MouseFeedback::create(spatial_parent)?,
+			config.color::<Self>(),
+			config.line_thickness,
+		);
+		Ok(Mouse {
+			handler: Arc::new(pulse_sender.wrap(mouse_handler)?),
+			motion: Arc::new(Mutex::new(MotionQueue::new(None))),
+		})
+	}
+	pub fn lock(&self) -> MutexGuard<'_, MouseHandler> {
+		self.handler.lock_wrapped()
+	}
+	/// Lock the handler, sending any motion still queued first so it stays in order with the input
+	/// sent next.
+	pub fn lock_flushed(&self) -> MutexGuard<'_, MouseHandler> {
+		let mut handler = self.lock();
+		if let Some((delta, since)) = self.motion.lock().take() {
+			handler.send_input(&InputEvent::Motion { delta });
+			LATENCY.motion.record_since(since);
+		}
+		handler
+	}
+	/// Queue motion that came in at `received`, to be summed up with the rest of the motion until
+	/// the next frame or until it's due going by the motion rate.
+	pub fn queue_motion(&self, delta: [f32; 2], received: Instant) {
+		let due = self.motion.lock().push(delta, received);
+		if due {
+			drop(self.lock_flushed());
+		}
 	}
-	pub fn lock(&self) -> MutexGuard<MouseHandler> {
-		self.0.lock_wrapped()
+	/// Also send queued motion this many times a second between frames, or only once a frame if `None`.
+	pub fn set_motion_rate(&self, rate: Option<f64>) {
+		self.motion.lock().set_rate(rate);
 	}
 }
 impl Emittable for Mouse {
 	const SIZE: [f32; 3] = [0.018, 0.027379, 0.004];
 	const EMIT_POINT: [f32; 3] = [0.0, 0.017667, 0.0];
+	const COLOR: Rgba<f32> = rgba!(0.141, 0.886, 0.521, 1.0);
+	const MODEL_PART: &'static str = "Mouse";
 
 	fn model_resource() -> ResourceID {
 		ResourceID::new_namespaced("manifold", "mouse")
 	}
-	fn update(&mut self, info: FrameInfo) {
-		self.lock().frame(info);
+	fn update(&mut self, info: FrameInfo, moving: bool) {
+		self.lock_flushed().frame(info, moving);
+	}
+	fn tint(&self) -> Option<Rgba<f32>> {
+		let mouse = self.lock();
+		mouse.mouse_keys_enabled().then_some(mouse.line_color)
+	}
+	fn configure(&mut self, config: &EmitterConfig) {
+		self.lock()
+			.set_line_style(config.color::<Self>(), config.line_thickness);
+	}
+}
+
+/// What the input window shows about the mouse.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct MouseStatus {
+	pub mouse_keys: bool,
+	pub dwell_mode: DwellMode,
+	/// When the pointer came to rest and how long it has to stay there, if it's waiting to dwell.
+	pub dwell_wait: Option<(Instant, Duration)>,
+}
+impl MouseStatus {
+	/// How far along the wait for the next dwell click is, from 0 to 1, if it's waiting.
+	pub fn dwell_progress(&self, now: Instant) -> Option<f32> {
+		Some(dwell::wait_progress(self.dwell_wait?, now))
 	}
 }
 
 pub struct MouseHandler {
 	pulse_sender: PulseSender,
 	receivers_info: FxHashMap<String, MouseReceiverInfo>,
+	connection_events: broadcast::Sender<ConnectionEvent>,
+	focus: Option<String>,
+	names: ReceiverNames,
+	lines: ReceiverLines,
+	mouse_keys: MouseKeys,
+	dwell: Dwell,
+	emulation: MouseEmulation,
+	feedback: MouseFeedback,
+	status: watch::Sender<MouseStatus>,
+	line_color: Rgba<f32>,
+	line_thickness: f32,
 }
 impl MouseHandler {
-	fn new(pulse_sender: PulseSender) -> Self {
+	fn new(
+		pulse_sender: PulseSender,
+		feedback: MouseFeedback,
+		line_color: Rgba<f32>,
+		line_thickness: f32,
+	) -> Self {
 		MouseHandler {
 			pulse_sender,
 			receivers_info: FxHashMap::default(),
+			connection_events: broadcast::channel(16).0,
+			focus: None,
+			names: ReceiverNames::default(),
+			lines: ReceiverLines::default(),
+			mouse_keys: MouseKeys::new(&MouseKeysConfig::default()),
+			dwell: Dwell::new(&DwellConfig::default()),
+			emulation: MouseEmulation::default(),
+			feedback,
+			status: watch::channel(MouseStatus::default()).0,
+			line_color,
+			line_thickness,
+		}
+	}
+	pub fn frame(&mut self, info: FrameInfo, moving: bool) {
+		let focus = self.focus.as_deref();
+		self.lines.update(
+			&self.pulse_sender,
+			self.receivers_info.iter_mut().filter_map(|(uid, info)| {
+				Some((info.line.as_mut()?, focus.map(|focus| focus == uid)))
+			}),
+			self.line_color,
+			self.line_thickness,
+			info.delta as f32,
+			moving,
+		);
+		let now = Instant::now();
+		if let Some(motion) = self.mouse_keys.tick(now) {
+			self.emit(&motion);
+		}
+		for click in self.dwell.tick(now) {
+			self.emit(&click);
 		}
+		for event in self.emulation.tick(now) {
+			self.emit(&event);
+		}
+		self.update_status();
+		let connected = self.receivers_info.values().any(|info| info.connected());
+		self.feedback
+			.frame(info.delta as f32, connected, self.line_color);
+	}
+
+	pub fn set_line_style(&mut self, color: Rgba<f32>, thickness: f32) {
+		self.line_color = color;
+		self.line_thickness = thickness;
 	}
-	pub fn frame(&mut self, _info: FrameInfo) {
-		for receiver_info in self.receivers_info.values_mut() {
-			receiver_info.update_sender(&self.pulse_sender);
+	/// Label the line to a connected receiver with this name instead of its number, and whichever
+	/// receiver gets its number after it. Returns false if no such receiver is connected.
+	pub fn set_receiver_name(&mut self, uid: &str, name: &str) -> bool {
+		let Some(info) = self.receivers_info.get_mut(uid) else {
+			return false;
+		};
+		let Some(line) = &mut info.line else {
+			return false;
+		};
+		self.names.set(info.number, name);
+		line.set_name(name);
+		true
+	}
+	/// Take the names given to receivers from an earlier handler.
+	pub fn set_names(&mut self, names: ReceiverNames) {
+		for info in self.receivers_info.values_mut() {
+			if let Some(line) = &mut info.line {
+				line.set_name(&names.label(info.number));
+			}
 		}
+		self.names = names;
+	}
+
+	/// UIDs of the receivers currently connected to this mouse.
+	pub fn connected_receivers(&self) -> Vec<String> {
+		self.receivers_info
+			.iter()
+			.filter(|(_, info)| info.connected())
+			.map(|(uid, _)| uid.clone())
+			.collect()
+	}
+	/// What the lines to the connected receivers are labelled, by UID.
+	pub fn receiver_names(&self) -> BTreeMap<String, String> {
+		self.receivers_info
+			.iter()
+			.filter_map(|(uid, info)| Some((uid.clone(), info.line.as_ref()?.name().to_string())))
+			.collect()
+	}
+	/// Get notified whenever a receiver connects to or disconnects from this mouse.
+	pub fn subscribe(&self) -> broadcast::Receiver<ConnectionEvent> {
+		self.connection_events.subscribe()
+	}
 
-		// let receivers = self.pulse_sender.receivers();
-		// let receivers: Vec<&PulseReceiver> = self
-		// 	.receivers_info
-		// 	.iter()
-		// 	.filter(|(_, info)| info.connected() && !info.sent_keymap)
-		// 	.filter_map(|(uid, _)| receivers.get(uid).map(|(rx, _)| rx))
-		// 	.collect();
-		// if !receivers.is_empty() {
-		// 	let event = KeyboardEvent::new(, None, None);
-		// 	event.send_event(&self.pulse_sender, &receivers);
-		// 	for receiver in self.receivers_info.values_mut() {
-		// 		receiver.sent_keymap = true;
-		// 	}
-		// }
+	/// The receiver input is limited to, if any.
+	pub fn focus(&self) -> Option<&str> {
+		self.focus.as_deref()
+	}
+	/// Only send input to the receiver with this UID, or to every connected receiver if `None`.
+	/// Returns false and keeps the old focus if no such receiver is connected.
+	pub fn set_focus(&mut self, uid: Option<String>) -> bool {
+		if let Some(uid) = &uid {
+			if !self
+				.receivers_info
+				.get(uid)
+				.is_some_and(|info| info.connected())
+			{
+				return false;
+			}
+		}
+		self.focus = uid;
+		true
 	}
 
+	/// Apply the mouse keys settings, keeping it on or off if only the speeds changed.
+	pub fn set_mouse_keys(&mut self, config: &MouseKeysConfig) {
+		let enabled = self.mouse_keys.enabled();
+		for event in self.mouse_keys.set_enabled(false) {
+			self.emit(&event);
+		}
+		self.mouse_keys = MouseKeys::new(config);
+		self.mouse_keys.set_enabled(enabled || config.enabled);
+	}
+	pub fn mouse_keys_enabled(&self) -> bool {
+		self.mouse_keys.enabled()
+	}
+	/// Turn mouse keys on or off, letting go of any button it was dragging with.
+	pub fn set_mouse_keys_enabled(&mut self, enabled: bool) {
+		for event in self.mouse_keys.set_enabled(enabled) {
+			self.emit(&event);
+		}
+	}
+	/// Give a key to mouse keys, returning false if it isn't a mouse key and belongs to the keyboard.
+	pub fn mouse_key(&mut self, key: u32, pressed: bool) -> bool {
+		let Some(events) = self.mouse_keys.key(key, pressed, Instant::now()) else {
+			return false;
+		};
+		for event in events {
+			self.emit(&event);
+		}
+		true
+	}
+
+	/// Apply the dwell click settings, letting go of the button if it was dragging.
+	pub fn set_dwell(&mut self, config: &DwellConfig) {
+		if let Some(release) = self.dwell.release() {
+			self.emit(&release);
+		}
+		self.dwell = Dwell::new(config);
+	}
+	pub fn dwell_mode(&self) -> DwellMode {
+		self.dwell.mode()
+	}
+	pub fn set_dwell_mode(&mut self, mode: DwellMode) {
+		if let Some(release) = self.dwell.set_mode(mode) {
+			self.emit(&release);
+		}
+	}
+
+	/// Get notified whenever mouse keys is turned on or off or the dwell clicking changes.
+	pub fn watch_status(&self) -> watch::Receiver<MouseStatus> {
+		self.status.subscribe()
+	}
+	fn update_status(&self) {
+		let status = MouseStatus {
+			mouse_keys: self.mouse_keys.enabled(),
+			dwell_mode: self.dwell.mode(),
+			dwell_wait: self.dwell.wait(),
+		};
+		self.status.send_if_modified(|old| {
+			let changed = *old != status;
+			*old = status;
+			changed
+		});
+	}
+
+	/// Apply the scroll and middle click emulation settings.
+	pub fn set_emulation(&mut self, config: &MouseEmulationConfig) {
+		if let Some(release) = self.emulation.release_all() {
+			self.emit(&release);
+		}
+		self.emulation = MouseEmulation::new(config);
+	}
+
+	/// Send the mouse kinds of [`InputEvent`], keys are left out.
+	pub fn send_input(&mut self, event: &InputEvent) {
+		match event {
+			InputEvent::Key { .. } => (),
+			InputEvent::Motion { delta } => {
+				self.send_event(Some(Vector2::from(*delta)), None, None, None, None)
+			}
+			InputEvent::Button { button, pressed } => {
+				let buttons = Some(vec![*button]);
+				let (buttons_up, buttons_down) = if *pressed {
+					(None, buttons)
+				} else {
+					(buttons, None)
+				};
+				self.send_event(None, None, None, buttons_up, buttons_down);
+			}
+			InputEvent::Scroll { distance, steps } => self.send_event(
+				None,
+				distance.map(Vector2::from),
+				steps.map(Vector2::from),
+				None,
+				None,
+			),
+		}
+	}
+
+	/// Send mouse input to the receivers, through the scroll and middle click emulation.
 	pub fn send_event(
-		&self,
+		&mut self,
 		delta: Option<Vector2<f32>>,
 		scroll_distance: Option<Vector2<f32>>,
 		scroll_steps: Option<Vector2<f32>>,
 		buttons_up: Option<Vec<u32>>,
 		buttons_down: Option<Vec<u32>>,
 	) {
+		let now = Instant::now();
+		let mut events = Vec::new();
+		if let Some(delta) = delta {
+			events.extend(self.emulation.motion(delta.into()));
+		}
+		for button in buttons_up.into_iter().flatten() {
+			events.extend(self.emulation.button(button, false, now));
+		}
+		for button in buttons_down.into_iter().flatten() {
+			events.extend(self.emulation.button(button, true, now));
+		}
+		if scroll_distance.is_some() || scroll_steps.is_some() {
+			events.push(InputEvent::Scroll {
+				distance: scroll_distance.map(Into::into),
+				steps: scroll_steps.map(Into::into),
+			});
+		}
+		for event in events {
+			self.emit(&event);
+		}
+	}
+
+	/// Send input straight to the receivers, as long as it isn't a key.
+	fn emit(&mut self, event: &InputEvent) {
+		let mut buttons = None;
+		let (mut delta, mut scroll_distance, mut scroll_steps) = (None, None, None);
+		match event {
+			InputEvent::Key { .. } => return,
+			InputEvent::Motion { delta: motion } => {
+				self.dwell.motion(*motion, Instant::now());
+				delta = Some(Vector2::from(*motion));
+			}
+			InputEvent::Button { button, pressed } => {
+				self.dwell.cancel();
+				self.feedback.button(*button, *pressed);
+				buttons = Some((vec![*button], *pressed));
+			}
+			InputEvent::Scroll { distance, steps } => {
+				self.feedback.scroll(*distance, *steps);
+				scroll_distance = distance.map(Vector2::from);
+				scroll_steps = steps.map(Vector2::from);
+			}
+		}
+		let (buttons_up, buttons_down) = match buttons {
+			Some((buttons, true)) => (None, Some(buttons)),
+			Some((buttons, false)) => (Some(buttons), None),
+			None => (None, None),
+		};
 		let event = MouseEvent::new(
 			delta,
 			scroll_distance,
@@ -94,12 +414,22 @@ impl MouseHandler {
 			buttons_up,
 			buttons_down,
 		);
-		let receivers = self.pulse_sender.receivers();
-		let receivers: Vec<&PulseReceiver> = self
+		let focus = self.focus.as_deref();
+		let targets: Vec<&str> = self
 			.receivers_info
-			.iter()
-			.filter(|(_, info)| info.connected())
-			.filter_map(|(uid, _)| receivers.get(uid).map(|(rx, _)| rx))
+			.iter_mut()
+			.filter(|(uid, info)| info.connected() && focus.is_none_or(|focus| focus == *uid))
+			.map(|(uid, info)| {
+				if let Some(line) = &mut info.line {
+					line.pulse();
+				}
+				uid.as_str()
+			})
+			.collect();
+		let receivers = self.pulse_sender.receivers();
+		let receivers: Vec<&PulseReceiver> = targets
+			.into_iter()
+			.filter_map(|uid| receivers.get(uid).map(|(rx, _)| rx))
 			.collect();
 		event.send_event(&self.pulse_sender, &receivers);
 	}
@@ -111,60 +441,50 @@ impl PulseSenderHandler for MouseHandler {
 		receiver: PulseReceiver,
 		_field: UnknownField,
 	) {
-		let mut keyboard_info = MouseReceiverInfo::new(receiver.alias());
-		keyboard_info.connect(); // temporary
-		self.receivers_info.insert(info.uid, keyboard_info);
+		let number = receiver_number(self.receivers_info.values().map(|info| info.number));
+		let mut mouse_info = MouseReceiverInfo::new(number, receiver.alias());
+		// temporary
+		match mouse_info.connect(&self.names.label(number)) {
+			Ok(()) => {
+				let _ = self
+					.connection_events
+					.send(ConnectionEvent::Connected(info.uid.clone()));
+			}
+			Err(e) => eprintln!("Couldn't connect to receiver {}: {e}", info.uid),
+		}
+		self.receivers_info.insert(info.uid, mouse_info);
 	}
 	fn drop_receiver(&mut self, uid: &str) {
-		self.receivers_info.remove(uid);
+		if self.receivers_info.remove(uid).is_some() {
+			if self.focus.as_deref() == Some(uid) {
+				self.focus = None;
+			}
+			let _ = self
+				.connection_events
+				.send(ConnectionEvent::Disconnected(uid.to_string()));
+		}
 	}
 }
-unsafe impl Send for MouseHandler {}
-unsafe impl Sync for MouseHandler {}
 
 struct MouseReceiverInfo {
-	lines: Option<Arc<Lines>>,
+	/// What it's labelled by until it's named, see [`ReceiverNames`].
+	number: usize,
+	line: Option<ReceiverLine>,
 	receiver: PulseReceiver,
 }
 impl MouseReceiverInfo {
-	fn new(receiver: PulseReceiver) -> Self {
+	fn new(number: usize, receiver: PulseReceiver) -> Self {
 		MouseReceiverInfo {
-			lines: None,
+			number,
+			line: None,
 			receiver,
 		}
 	}
 	fn connected(&self) -> bool {
-		self.lines.is_some()
-	}
-	fn connect(&mut self) {
-		self.lines = Some(Arc::new(
-			Lines::create(&self.receiver, Transform::default(), &[], false).unwrap(),
-		));
-	}
-	const LINE_THICKNESS: f32 = 0.005;
-	fn update_sender(&mut self, sender: &PulseSender) {
-		if let Some(lines) = self.lines.clone() {
-			let future = sender.get_position_rotation_scale(&lines).unwrap();
-			tokio::task::spawn(async move {
-				if let Ok((position, _, _)) = future.await {
-					lines
-						.update_points(&[
-							LinePoint {
-								point: Vector3::from([0.0; 3]),
-								thickness: Self::LINE_THICKNESS,
-								color: MOUSE_COLOR,
-							},
-							LinePoint {
-								point: position,
-								thickness: Self::LINE_THICKNESS,
-								color: MOUSE_COLOR,
-							},
-						])
-						.unwrap();
-				}
-			});
-		}
+		self.line.is_some()
+	}
+	fn connect(&mut self, name: &str) -> Result<()> {
+		self.line = Some(ReceiverLine::create(&self.receiver, name)?);
+		Ok(())
 	}
 }
-unsafe impl Send for MouseReceiverInfo {}
-unsafe impl Sync for MouseReceiverInfo {}
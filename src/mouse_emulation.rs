@@ -0,0 +1,255 @@
+//! Scrolling and middle clicks for mice and trackballs that lack a usable wheel or middle button,
+//! configured by [`MouseEmulationConfig`].
+
+use crate::{config::MouseEmulationConfig, input::InputEvent};
+use std::time::{Duration, Instant};
+
+/// Pixels the pointer has to move with the scroll button held before it scrolls, so a slightly
+/// shaky click is still a click.
+const SCROLL_START_DISTANCE: f32 = 3.0;
+
+const LEFT: u32 = input_event_codes::BTN_LEFT!();
+const RIGHT: u32 = input_event_codes::BTN_RIGHT!();
+const MIDDLE: u32 = input_event_codes::BTN_MIDDLE!();
+
+/// The scroll button being held.
+struct ScrollHold {
+	travelled: f32,
+	scrolling: bool,
+}
+
+#[derive(Default)]
+pub struct MouseEmulation {
+	scroll_button: Option<u32>,
+	scroll_speed: f32,
+	middle_chord: Option<Duration>,
+	/// Left or right, waiting to see if the other joins it for a middle click.
+	pending: Option<(u32, Instant)>,
+	/// Buttons of a middle click chord that are still down.
+	chord: Vec<u32>,
+	scroll: Option<ScrollHold>,
+}
+impl MouseEmulation {
+	pub fn new(config: &MouseEmulationConfig) -> Self {
+		MouseEmulation {
+			scroll_button: config.scroll_button,
+			scroll_speed: config.scroll_speed,
+			middle_chord: config.middle_chord.map(Duration::from_secs_f64),
+			..Default::default()
+		}
+	}
+
+	/// Pointer motion, which scrolls instead while the scroll button is held.
+	pub fn motion(&mut self, delta: [f32; 2]) -> Vec<InputEvent> {
+		// moving means dragging, not chording
+		let mut events = self.flush_pending();
+		match &mut self.scroll {
+			None => events.push(InputEvent::Motion { delta }),
+			Some(hold) => {
+				hold.travelled += delta[0].hypot(delta[1]);
+				hold.scrolling |= hold.travelled > SCROLL_START_DISTANCE;
+				if hold.scrolling {
+					events.push(InputEvent::Scroll {
+						distance: Some([
+							delta[0] * self.scroll_speed,
+							delta[1] * self.scroll_speed,
+						]),
+						steps: None,
+					});
+				}
+			}
+		}
+		events
+	}
+
+	pub fn button(&mut self, button: u32, pressed: bool, now: Instant) -> Vec<InputEvent> {
+		let mut events = Vec::new();
+		for (button, pressed) in self.chord_button(button, pressed, now) {
+			self.scroll_button(button, pressed, &mut events);
+		}
+		events
+	}
+
+	/// Press a left or right button that's been waiting too long for the other.
+	pub fn tick(&mut self, now: Instant) -> Vec<InputEvent> {
+		match (self.pending, self.middle_chord) {
+			(Some((_, since)), Some(timeout)) if now >= since + timeout => self.flush_pending(),
+			_ => Vec::new(),
+		}
+	}
+
+	/// Let go of the middle button if a chord is holding it down, and forget everything else, since
+	/// held back presses never reached the receivers.
+	pub fn release_all(&mut self) -> Option<InputEvent> {
+		let middle_down =
+			std::mem::take(&mut self.chord).len() == 2 && self.scroll_button != Some(MIDDLE);
+		self.pending = None;
+		self.scroll = None;
+		middle_down.then_some(InputEvent::Button {
+			button: MIDDLE,
+			pressed: false,
+		})
+	}
+
+	fn flush_pending(&mut self) -> Vec<InputEvent> {
+		let mut events = Vec::new();
+		if let Some((button, _)) = self.pending.take() {
+			self.scroll_button(button, true, &mut events);
+		}
+		events
+	}
+
+	/// Turn left and right pressed together into middle.
+	fn chord_button(&mut self, button: u32, pressed: bool, now: Instant) -> Vec<(u32, bool)> {
+		if self.middle_chord.is_none() || !(button == LEFT || button == RIGHT) {
+			return vec![(button, pressed)];
+		}
+		if !pressed {
+			if let Some(i) = self.chord.iter().position(|b| *b == button) {
+				self.chord.remove(i);
+				// the middle button comes up with the first of the two
+				return if self.chord.len() == 1 {
+					vec![(MIDDLE, false)]
+				} else {
+					Vec::new()
+				};
+			}
+			if self.pending.take_if(|(b, _)| *b == button).is_some() {
+				return vec![(button, true), (button, false)];
+			}
+			return vec![(button, false)];
+		}
+		match self.pending.take() {
+			Some((other, _)) if other != button => {
+				self.chord = vec![other, button];
+				vec![(MIDDLE, true)]
+			}
+			None if self.chord.is_empty() => {
+				self.pending = Some((button, now));
+				Vec::new()
+			}
+			pending => {
+				self.pending = pending;
+				vec![(button, true)]
+			}
+		}
+	}
+
+	/// Hold back the scroll button, clicking it on release only if the pointer didn't scroll.
+	fn scroll_button(&mut self, button: u32, pressed: bool, events: &mut Vec<InputEvent>) {
+		let click = |pressed| InputEvent::Button { button, pressed };
+		if self.scroll_button != Some(button) {
+			events.push(click(pressed));
+		} else if pressed {
+			self.scroll = Some(ScrollHold {
+				travelled: 0.0,
+				scrolling: false,
+			});
+		} else if self.scroll.take().is_some_and(|hold| !hold.scrolling) {
+			events.extend([click(true), click(false)]);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const CHORD: Duration = Duration::from_millis(50);
+
+	fn click(button: u32, pressed: bool) -> InputEvent {
+		InputEvent::Button { button, pressed }
+	}
+
+	fn scroll_emulation() -> MouseEmulation {
+		MouseEmulation::new(&MouseEmulationConfig {
+			scroll_button: Some(MIDDLE),
+			scroll_speed: 2.0,
+			..Default::default()
+		})
+	}
+	fn chord_emulation() -> MouseEmulation {
+		MouseEmulation::new(&MouseEmulationConfig {
+			middle_chord: Some(CHORD.as_secs_f64()),
+			..Default::default()
+		})
+	}
+
+	#[test]
+	fn scroll_button_scrolls_once_moved_far_enough() {
+		let mut emulation = scroll_emulation();
+		let now = Instant::now();
+		assert_eq!(emulation.button(MIDDLE, true, now), []);
+		assert_eq!(emulation.motion([2.0, 0.0]), []);
+		assert_eq!(
+			emulation.motion([0.0, 2.0]),
+			[InputEvent::Scroll {
+				distance: Some([0.0, 4.0]),
+				steps: None
+			}]
+		);
+		assert_eq!(emulation.button(MIDDLE, false, now), []);
+		assert_eq!(
+			emulation.motion([1.0, 0.0]),
+			[InputEvent::Motion { delta: [1.0, 0.0] }]
+		);
+	}
+
+	#[test]
+	fn scroll_button_still_clicks_without_moving() {
+		let mut emulation = scroll_emulation();
+		let now = Instant::now();
+		emulation.button(MIDDLE, true, now);
+		assert_eq!(emulation.motion([1.0, 1.0]), []);
+		assert_eq!(
+			emulation.button(MIDDLE, false, now),
+			[click(MIDDLE, true), click(MIDDLE, false)]
+		);
+		assert_eq!(emulation.button(LEFT, true, now), [click(LEFT, true)]);
+	}
+
+	#[test]
+	fn left_and_right_together_click_middle() {
+		let mut emulation = chord_emulation();
+		let now = Instant::now();
+		assert_eq!(emulation.button(LEFT, true, now), []);
+		assert_eq!(
+			emulation.button(RIGHT, true, now + CHORD / 2),
+			[click(MIDDLE, true)]
+		);
+		assert_eq!(emulation.button(RIGHT, false, now), [click(MIDDLE, false)]);
+		assert_eq!(emulation.button(LEFT, false, now), []);
+	}
+
+	#[test]
+	fn lone_buttons_go_through_late() {
+		let mut emulation = chord_emulation();
+		let now = Instant::now();
+		emulation.button(LEFT, true, now);
+		assert_eq!(emulation.tick(now + CHORD / 2), []);
+		assert_eq!(emulation.tick(now + CHORD), [click(LEFT, true)]);
+		assert_eq!(emulation.button(LEFT, false, now), [click(LEFT, false)]);
+
+		// a quick click, and a drag
+		emulation.button(RIGHT, true, now);
+		assert_eq!(
+			emulation.button(RIGHT, false, now),
+			[click(RIGHT, true), click(RIGHT, false)]
+		);
+		emulation.button(LEFT, true, now);
+		assert_eq!(
+			emulation.motion([1.0, 0.0]),
+			[click(LEFT, true), InputEvent::Motion { delta: [1.0, 0.0] }]
+		);
+	}
+
+	#[test]
+	fn release_all_lets_go_of_a_chord() {
+		let mut emulation = chord_emulation();
+		let now = Instant::now();
+		emulation.button(LEFT, true, now);
+		emulation.button(RIGHT, true, now);
+		assert_eq!(emulation.release_all(), Some(click(MIDDLE, false)));
+		assert_eq!(emulation.release_all(), None);
+	}
+}
@@ -0,0 +1,215 @@
+//! Feedback drawn on the mouse model: the buttons sink in while held, the wheel turns with scrolling
+//! and an outline lights up while a receiver is connected.
+
+use color::{rgba, Rgba};
+use color_eyre::eyre::Result;
+use mint::Vector3;
+use stardust_xr_fusion::{
+	core::values::Transform,
+	drawable::{LinePoint, Lines},
+	spatial::Spatial,
+};
+use std::f32::consts::{PI, TAU};
+
+/// The top of the model, in the model's space.
+const TOP: f32 = 0.0026;
+/// Corners of the outline around the top of the model.
+const OUTLINE: [[f32; 2]; 4] = [
+	[-0.0088, -0.012],
+	[0.0088, -0.012],
+	[0.0088, 0.0135],
+	[-0.0088, 0.0135],
+];
+const OUTLINE_THICKNESS: f32 = 0.0004;
+/// Where the left button sits, the right one is mirrored. Its length runs along y.
+const BUTTON_CENTER: [f32; 2] = [-0.0045, 0.0085];
+const BUTTON_LENGTH: f32 = 0.007;
+const BUTTON_WIDTH: f32 = 0.0065;
+const WHEEL_CENTER: [f32; 2] = [0.0, 0.0085];
+const WHEEL_RADIUS: f32 = 0.0018;
+const WHEEL_THICKNESS: f32 = 0.0006;
+const WHEEL_SEGMENTS: usize = 16;
+/// How far a held button sinks into the model.
+const PRESS_DEPTH: f32 = 0.0008;
+/// How quickly buttons and the wheel catch up with the input, per second.
+const EASING: f32 = 20.0;
+/// How far the wheel turns for one scroll step, or for a pixel of smooth scrolling.
+const STEP_ANGLE: f32 = PI / 12.0;
+const PIXEL_ANGLE: f32 = STEP_ANGLE / 20.0;
+
+const IDLE: Rgba<f32> = rgba!(0.1, 0.1, 0.1, 1.0);
+const WHEEL_IDLE: Rgba<f32> = rgba!(0.35, 0.35, 0.35, 1.0);
+const SPOKE: Rgba<f32> = rgba!(0.9, 0.9, 0.9, 1.0);
+
+/// A button that sinks in while held.
+struct Shell {
+	button: u32,
+	lines: Lines,
+	pressed: bool,
+	depth: f32,
+	/// Depth and color last sent, to only send changes.
+	shown: Option<(f32, Rgba<f32>)>,
+}
+impl Shell {
+	fn create(model_parent: &Spatial, button: u32, center: [f32; 2]) -> Result<Self> {
+		Ok(Shell {
+			button,
+			lines: Lines::create(
+				model_parent,
+				Transform::from_position([center[0], center[1], 0.0]),
+				&[],
+				false,
+			)?,
+			pressed: false,
+			depth: 0.0,
+			shown: None,
+		})
+	}
+}
+
+pub struct MouseFeedback {
+	outline: Lines,
+	outline_color: Option<Rgba<f32>>,
+	left: Shell,
+	right: Shell,
+	/// The middle button, which sinks the wheel.
+	wheel: Shell,
+	spoke: Lines,
+	wheel_angle: f32,
+	wheel_target: f32,
+	shown_angle: Option<(f32, f32)>,
+}
+impl MouseFeedback {
+	pub fn create(model_parent: &Spatial) -> Result<Self> {
+		let [x, y] = BUTTON_CENTER;
+		Ok(MouseFeedback {
+			outline: Lines::create(model_parent, Transform::default(), &[], true)?,
+			outline_color: None,
+			left: Shell::create(model_parent, input_event_codes::BTN_LEFT!(), [x, y])?,
+			right: Shell::create(model_parent, input_event_codes::BTN_RIGHT!(), [-x, y])?,
+			wheel: Shell::create(model_parent, input_event_codes::BTN_MIDDLE!(), WHEEL_CENTER)?,
+			spoke: Lines::create(
+				model_parent,
+				Transform::from_position([WHEEL_CENTER[0], WHEEL_CENTER[1], 0.0]),
+				&[],
+				false,
+			)?,
+			wheel_angle: 0.0,
+			wheel_target: 0.0,
+			shown_angle: None,
+		})
+	}
+
+	pub fn button(&mut self, button: u32, pressed: bool) {
+		for shell in [&mut self.left, &mut self.right, &mut self.wheel] {
+			if shell.button == button {
+				shell.pressed = pressed;
+			}
+		}
+	}
+
+	pub fn scroll(&mut self, distance: Option<[f32; 2]>, steps: Option<[f32; 2]>) {
+		// steps are what the receivers go by when both are there
+		self.wheel_target += match (steps, distance) {
+			(Some([_, steps]), _) => steps * STEP_ANGLE,
+			(None, Some([_, distance])) => distance * PIXEL_ANGLE,
+			(None, None) => 0.0,
+		};
+	}
+
+	/// Move everything along towards the input, `delta` seconds after the last frame.
+	pub fn frame(&mut self, delta: f32, connected: bool, lit: Rgba<f32>) {
+		let ease = (delta * EASING).min(1.0);
+
+		let outline_color = if connected { lit } else { IDLE };
+		if self.outline_color != Some(outline_color) {
+			let points = OUTLINE.map(|[x, y]| LinePoint {
+				point: Vector3::from([x, y, TOP]),
+				thickness: OUTLINE_THICKNESS,
+				color: outline_color,
+			});
+			if let Err(e) = self.outline.update_points(&points) {
+				eprintln!("Couldn't redraw the mouse outline: {e}");
+			}
+			self.outline_color = Some(outline_color);
+		}
+
+		for shell in [&mut self.left, &mut self.right] {
+			let color = if shell.pressed { lit } else { IDLE };
+			if let Some(depth) = ease_shell(shell, ease, color) {
+				let z = TOP - depth;
+				let points = [-BUTTON_LENGTH / 2.0, BUTTON_LENGTH / 2.0].map(|y| LinePoint {
+					point: Vector3::from([0.0, y, z]),
+					thickness: BUTTON_WIDTH,
+					color,
+				});
+				if let Err(e) = shell.lines.update_points(&points) {
+					eprintln!("Couldn't redraw a mouse button: {e}");
+				}
+			}
+		}
+
+		let color = if self.wheel.pressed { lit } else { WHEEL_IDLE };
+		if let Some(depth) = ease_shell(&mut self.wheel, ease, color) {
+			let points: Vec<LinePoint> = (0..WHEEL_SEGMENTS)
+				.map(|i| {
+					let angle = i as f32 / WHEEL_SEGMENTS as f32 * TAU;
+					LinePoint {
+						point: Vector3::from([
+							0.0,
+							WHEEL_RADIUS * angle.cos(),
+							TOP - depth + WHEEL_RADIUS * angle.sin(),
+						]),
+						thickness: WHEEL_THICKNESS,
+						color,
+					}
+				})
+				.collect();
+			if let Err(e) = self.wheel.lines.update_points(&points) {
+				eprintln!("Couldn't redraw the mouse wheel: {e}");
+			}
+		}
+
+		self.wheel_angle = approach(self.wheel_angle, self.wheel_target, ease, 0.001);
+		// keep the angles small so they don't lose precision after a lot of scrolling
+		let turns = (self.wheel_angle / TAU).floor() * TAU;
+		self.wheel_angle -= turns;
+		self.wheel_target -= turns;
+		let angle = (self.wheel_angle, self.wheel.depth);
+		if self.shown_angle != Some(angle) {
+			let (sin, cos) = self.wheel_angle.sin_cos();
+			let z = TOP - self.wheel.depth;
+			let points = [0.0, WHEEL_RADIUS].map(|radius| LinePoint {
+				point: Vector3::from([0.0, radius * cos, z + radius * sin]),
+				thickness: WHEEL_THICKNESS / 2.0,
+				color: SPOKE,
+			});
+			if let Err(e) = self.spoke.update_points(&points) {
+				eprintln!("Couldn't redraw the mouse wheel's spoke: {e}");
+			}
+			self.shown_angle = Some(angle);
+		}
+	}
+}
+
+/// Sink or raise a button towards where it should be, returning its new depth if it needs redrawing.
+fn ease_shell(shell: &mut Shell, ease: f32, color: Rgba<f32>) -> Option<f32> {
+	let target = if shell.pressed { PRESS_DEPTH } else { 0.0 };
+	shell.depth = approach(shell.depth, target, ease, 0.00001);
+	let shown = Some((shell.depth, color));
+	if shell.shown == shown {
+		return None;
+	}
+	shell.shown = shown;
+	Some(shell.depth)
+}
+
+/// Move part of the way from `value` to `target`, snapping to it once it's within `epsilon`.
+fn approach(value: f32, target: f32, ease: f32, epsilon: f32) -> f32 {
+	let value = value + (target - value) * ease;
+	if (target - value).abs() < epsilon {
+		target
+	} else {
+		value
+	}
+}
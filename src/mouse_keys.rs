@@ -0,0 +1,241 @@
+//! Mouse keys: the numpad moving the pointer and pressing buttons, configured by
+//! [`MouseKeysConfig`].
+
+use crate::{config::MouseKeysConfig, input::InputEvent};
+use rustc_hash::FxHashSet;
+use std::time::{Duration, Instant};
+
+/// Numpad keys that move the pointer, and which way.
+const DIRECTIONS: [(u32, [f32; 2]); 8] = [
+	(input_event_codes::KEY_KP7!(), [-1.0, -1.0]),
+	(input_event_codes::KEY_KP8!(), [0.0, -1.0]),
+	(input_event_codes::KEY_KP9!(), [1.0, -1.0]),
+	(input_event_codes::KEY_KP4!(), [-1.0, 0.0]),
+	(input_event_codes::KEY_KP6!(), [1.0, 0.0]),
+	(input_event_codes::KEY_KP1!(), [-1.0, 1.0]),
+	(input_event_codes::KEY_KP2!(), [0.0, 1.0]),
+	(input_event_codes::KEY_KP3!(), [1.0, 1.0]),
+];
+/// Numpad keys that pick the button the others press.
+const BUTTONS: [(u32, u32); 3] = [
+	(
+		input_event_codes::KEY_KPSLASH!(),
+		input_event_codes::BTN_LEFT!(),
+	),
+	(
+		input_event_codes::KEY_KPASTERISK!(),
+		input_event_codes::BTN_MIDDLE!(),
+	),
+	(
+		input_event_codes::KEY_KPMINUS!(),
+		input_event_codes::BTN_RIGHT!(),
+	),
+];
+const CLICK: u32 = input_event_codes::KEY_KP5!();
+const DOUBLE_CLICK: u32 = input_event_codes::KEY_KPPLUS!();
+const DRAG: u32 = input_event_codes::KEY_KP0!();
+const DROP: u32 = input_event_codes::KEY_KPDOT!();
+
+pub struct MouseKeys {
+	enabled: bool,
+	speed: f32,
+	max_speed: f32,
+	acceleration_time: Duration,
+	/// Keys taken while mouse keys was on, so their release is taken too even if it's off by then.
+	held: FxHashSet<u32>,
+	/// When the pointer started moving and when it was last moved.
+	moving: Option<(Instant, Instant)>,
+	button: u32,
+	/// Buttons held down by the drag key until the drop key.
+	dragging: Vec<u32>,
+}
+impl MouseKeys {
+	pub fn new(config: &MouseKeysConfig) -> Self {
+		MouseKeys {
+			enabled: config.enabled,
+			speed: config.speed as f32,
+			max_speed: config.max_speed as f32,
+			acceleration_time: Duration::from_secs_f64(config.acceleration_time),
+			held: FxHashSet::default(),
+			moving: None,
+			button: input_event_codes::BTN_LEFT!(),
+			dragging: Vec::new(),
+		}
+	}
+
+	pub fn enabled(&self) -> bool {
+		self.enabled
+	}
+	/// Turn mouse keys on or off, returning the buttons to let go of if it was dragging.
+	pub fn set_enabled(&mut self, enabled: bool) -> Vec<InputEvent> {
+		self.enabled = enabled;
+		self.moving = None;
+		if enabled {
+			return Vec::new();
+		}
+		self.dragging
+			.drain(..)
+			.map(|button| InputEvent::Button {
+				button,
+				pressed: false,
+			})
+			.collect()
+	}
+
+	/// Take a key if it's one of the mouse keys, returning the mouse input it makes. `None` means
+	/// the key isn't for mouse keys and should go to the keyboard.
+	pub fn key(&mut self, scancode: u32, pressed: bool, now: Instant) -> Option<Vec<InputEvent>> {
+		if !pressed {
+			if !self.held.remove(&scancode) {
+				return None;
+			}
+			if self.direction() == [0.0; 2] {
+				self.moving = None;
+			}
+			return Some(Vec::new());
+		}
+		if !self.enabled || !is_mouse_key(scancode) {
+			return None;
+		}
+		if !self.held.insert(scancode) {
+			// auto-repeat from the desktop
+			return Some(Vec::new());
+		}
+
+		let button = self.button;
+		let click = |pressed| InputEvent::Button { button, pressed };
+		let events = match scancode {
+			CLICK => vec![click(true), click(false)],
+			DOUBLE_CLICK => vec![click(true), click(false), click(true), click(false)],
+			DRAG if !self.dragging.contains(&button) => {
+				self.dragging.push(button);
+				vec![click(true)]
+			}
+			DROP => self
+				.dragging
+				.drain(..)
+				.map(|button| InputEvent::Button {
+					button,
+					pressed: false,
+				})
+				.collect(),
+			_ => {
+				if let Some((_, button)) = BUTTONS.iter().find(|(key, _)| *key == scancode) {
+					self.button = *button;
+				}
+				match DIRECTIONS.iter().find(|(key, _)| *key == scancode) {
+					// a tap nudges the pointer by a pixel, holding it accelerates
+					Some((_, delta)) if self.moving.is_none() => {
+						self.moving = Some((now, now));
+						vec![InputEvent::Motion { delta: *delta }]
+					}
+					_ => Vec::new(),
+				}
+			}
+		};
+		Some(events)
+	}
+
+	/// How far to move the pointer for the directions held since the last tick, if at all.
+	pub fn tick(&mut self, now: Instant) -> Option<InputEvent> {
+		let (since, last) = self.moving.as_mut()?;
+		let held_for = now.saturating_duration_since(*since);
+		let elapsed = now.saturating_duration_since(*last).as_secs_f32();
+		*last = now;
+
+		let acceleration = if self.acceleration_time.is_zero() {
+			1.0
+		} else {
+			(held_for.as_secs_f32() / self.acceleration_time.as_secs_f32()).min(1.0)
+		};
+		let speed = self.speed + (self.max_speed - self.speed) * acceleration;
+		let [x, y] = self.direction();
+		if [x, y] == [0.0; 2] {
+			return None;
+		}
+		Some(InputEvent::Motion {
+			delta: [x * speed * elapsed, y * speed * elapsed],
+		})
+	}
+
+	fn direction(&self) -> [f32; 2] {
+		DIRECTIONS
+			.iter()
+			.filter(|(key, _)| self.held.contains(key))
+			.fold([0.0; 2], |[x, y], (_, [dx, dy])| [x + dx, y + dy])
+	}
+}
+
+fn is_mouse_key(scancode: u32) -> bool {
+	[CLICK, DOUBLE_CLICK, DRAG, DROP].contains(&scancode)
+		|| BUTTONS.iter().any(|(key, _)| *key == scancode)
+		|| DIRECTIONS.iter().any(|(key, _)| *key == scancode)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use input_event_codes::{BTN_LEFT, KEY_A, KEY_KP0, KEY_KP6, KEY_KPDOT};
+
+	fn mouse_keys() -> MouseKeys {
+		MouseKeys::new(&MouseKeysConfig {
+			enabled: true,
+			speed: 100.0,
+			max_speed: 1100.0,
+			acceleration_time: 1.0,
+			..Default::default()
+		})
+	}
+
+	#[test]
+	fn holding_a_direction_accelerates() {
+		let mut mouse_keys = mouse_keys();
+		let start = Instant::now();
+		let at = |seconds| start + Duration::from_secs_f32(seconds);
+		assert_eq!(
+			mouse_keys.key(KEY_KP6!(), true, start),
+			Some(vec![InputEvent::Motion { delta: [1.0, 0.0] }])
+		);
+		assert_eq!(
+			mouse_keys.tick(at(0.5)),
+			Some(InputEvent::Motion {
+				delta: [300.0, 0.0]
+			})
+		);
+		assert_eq!(
+			mouse_keys.tick(at(1.5)),
+			Some(InputEvent::Motion {
+				delta: [1100.0, 0.0]
+			})
+		);
+		assert_eq!(mouse_keys.key(KEY_KP6!(), false, at(1.5)), Some(Vec::new()));
+		assert_eq!(mouse_keys.tick(at(2.0)), None);
+	}
+
+	#[test]
+	fn toggling_off_passes_keys_through_and_drops() {
+		let mut mouse_keys = mouse_keys();
+		let now = Instant::now();
+		let drag = Some(vec![InputEvent::Button {
+			button: BTN_LEFT!(),
+			pressed: true,
+		}]);
+		assert_eq!(mouse_keys.key(KEY_A!(), true, now), None);
+		assert_eq!(mouse_keys.key(KEY_KP0!(), true, now), drag);
+		assert_eq!(
+			mouse_keys.set_enabled(false),
+			[InputEvent::Button {
+				button: BTN_LEFT!(),
+				pressed: false
+			}]
+		);
+		assert!(!mouse_keys.enabled());
+		// the release of a key taken while on is still taken
+		assert_eq!(mouse_keys.key(KEY_KP0!(), false, now), Some(Vec::new()));
+		assert_eq!(mouse_keys.key(KEY_KPDOT!(), true, now), None);
+		assert_eq!(mouse_keys.key(KEY_KPDOT!(), false, now), None);
+
+		assert_eq!(mouse_keys.set_enabled(true), []);
+		assert_eq!(mouse_keys.key(KEY_KP0!(), true, now), drag);
+	}
+}
@@ -0,0 +1,370 @@
+//! The remapping engine behind [`RemapConfig`](crate::config::RemapConfig), sitting between the
+//! input backends and the receivers.
+
+use crate::{
+	binding::{keysym_for_scancode, parse_keysym, EVDEV_OFFSET},
+	config::{KeyRemap, RemapRules},
+	keymap::{self, KeyPress, Xkb},
+};
+use rustc_hash::FxHashMap;
+use std::{
+	collections::BTreeMap,
+	time::{Duration, Instant},
+};
+use xkbcommon::xkb::{Keymap, Keysym};
+
+/// A key going down or up, as an evdev code.
+pub type KeyEvent = (u32, bool);
+
+/// How held keys repeat.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyRepeat {
+	pub delay: Duration,
+	pub interval: Duration,
+}
+
+#[derive(Debug, Clone)]
+enum Action {
+	Key(KeyPress),
+	Layer(usize),
+}
+
+#[derive(Debug, Clone)]
+enum Mapping {
+	Action(Action),
+	TapHold { tap: KeyPress, hold: Action },
+}
+
+/// A tap-hold key that's down but not decided on yet.
+#[derive(Debug)]
+struct Pending {
+	scancode: u32,
+	since: Instant,
+	tap: KeyPress,
+	hold: Action,
+}
+
+/// Remaps keys for one set of receivers. Keys without a mapping pass through unchanged, as does
+/// everything until there's a keymap to look keysyms up in.
+#[derive(Default)]
+pub struct Remapper {
+	keymap: Xkb<Option<Keymap>>,
+	keys: FxHashMap<Keysym, Mapping>,
+	layers: Vec<FxHashMap<Keysym, Mapping>>,
+	tap_timeout: Duration,
+	pending: Option<Pending>,
+	/// What each key that's down is doing, so releasing it undoes exactly that.
+	held: FxHashMap<u32, Action>,
+	active_layers: Vec<usize>,
+	repeat: Option<KeyRepeat>,
+	/// The last key pressed, if it repeats, and when it should next.
+	repeating: Option<(u32, Instant)>,
+}
+impl Remapper {
+	pub(crate) fn new(
+		keymap: &Keymap,
+		rules: &RemapRules,
+		tap_timeout: Duration,
+		repeat: Option<KeyRepeat>,
+	) -> Self {
+		let layer_names: Vec<&String> = rules.layers.keys().collect();
+		let modifier_keys = keymap::level_modifier_keys(keymap);
+		let action = |name: &str| match name.strip_prefix('@') {
+			Some(layer) => layer_names
+				.iter()
+				.position(|name| *name == layer)
+				.map(Action::Layer),
+			None => {
+				let keysym = parse_keysym(name, false)?;
+				keymap::find_keysym_press(keymap, &modifier_keys, keysym).map(Action::Key)
+			}
+		};
+		let mappings = |keys: &BTreeMap<String, KeyRemap>| {
+			keys.iter()
+				.filter_map(|(from, to)| {
+					let mapping = match to {
+						KeyRemap::Key(to) => action(to).map(Mapping::Action),
+						KeyRemap::TapHold { tap, hold } => match (action(tap), action(hold)) {
+							(Some(Action::Key(tap)), Some(hold)) => {
+								Some(Mapping::TapHold { tap, hold })
+							}
+							_ => None,
+						},
+					};
+					if mapping.is_none() {
+						eprintln!("Not remapping {from}, the keymap has no key for {to:?}");
+					}
+					Some((parse_keysym(from, true)?, mapping?))
+				})
+				.collect()
+		};
+
+		Remapper {
+			keymap: Xkb::new(Some(keymap.clone())),
+			keys: mappings(&rules.keys),
+			layers: rules.layers.values().map(mappings).collect(),
+			tap_timeout,
+			repeat,
+			..Default::default()
+		}
+	}
+
+	pub fn set_repeat(&mut self, repeat: Option<KeyRepeat>) {
+		self.repeat = repeat;
+		self.repeating = None;
+	}
+
+	/// Feed a key through the remapping, returning the key events to send in order.
+	pub fn key(&mut self, scancode: u32, pressed: bool, now: Instant) -> Vec<KeyEvent> {
+		let mut events = Vec::new();
+		if pressed {
+			if self
+				.pending
+				.as_ref()
+				.is_some_and(|p| p.scancode == scancode)
+			{
+				return events;
+			}
+			if self.held.contains_key(&scancode) {
+				// auto-repeat from the desktop, we do our own
+				return events;
+			}
+			self.resolve_pending(now, &mut events);
+			match self.lookup(scancode) {
+				Mapping::Action(action) => self.press(scancode, action, now, &mut events),
+				Mapping::TapHold { tap, hold } => {
+					self.pending = Some(Pending {
+						scancode,
+						since: now,
+						tap,
+						hold,
+					})
+				}
+			}
+		} else if let Some(pending) = self.pending.take_if(|p| p.scancode == scancode) {
+			push_press(&pending.tap, true, &mut events);
+			push_press(&pending.tap, false, &mut events);
+		} else if let Some(action) = self.held.remove(&scancode) {
+			self.release(action, &mut events);
+		}
+		events
+	}
+
+	/// Turn a tap-hold key that's been down long enough into a hold, and repeat the held key.
+	pub fn tick(&mut self, now: Instant) -> Vec<KeyEvent> {
+		let mut events = Vec::new();
+		if self
+			.pending
+			.as_ref()
+			.is_some_and(|p| now >= p.since + self.tap_timeout)
+		{
+			self.resolve_pending(now, &mut events);
+		}
+		if let (Some((key, next)), Some(repeat)) = (&mut self.repeating, self.repeat) {
+			if now >= *next {
+				events.push((*key, true));
+				// don't burst to catch up after a stall
+				*next = (*next + repeat.interval).max(now);
+			}
+		}
+		events
+	}
+
+	/// Release everything that's down, before this remapper gets replaced.
+	pub fn release_all(&mut self) -> Vec<KeyEvent> {
+		let mut events = Vec::new();
+		self.pending = None;
+		self.repeating = None;
+		for (_, action) in std::mem::take(&mut self.held) {
+			self.release(action, &mut events);
+		}
+		events
+	}
+
+	fn lookup(&self, scancode: u32) -> Mapping {
+		let passthrough = Mapping::Action(Action::Key(KeyPress {
+			key: scancode,
+			modifiers: Vec::new(),
+		}));
+		let Some(keymap) = &*self.keymap else {
+			return passthrough;
+		};
+		let keysym = keysym_for_scancode(keymap, scancode);
+		self.active_layers
+			.iter()
+			.rev()
+			.find_map(|layer| self.layers[*layer].get(&keysym))
+			.or_else(|| self.keys.get(&keysym))
+			.cloned()
+			.unwrap_or(passthrough)
+	}
+
+	fn resolve_pending(&mut self, now: Instant, events: &mut Vec<KeyEvent>) {
+		if let Some(pending) = self.pending.take() {
+			self.press(pending.scancode, pending.hold, now, events);
+		}
+	}
+
+	fn press(&mut self, scancode: u32, action: Action, now: Instant, events: &mut Vec<KeyEvent>) {
+		match &action {
+			Action::Key(press) => {
+				push_press(press, true, events);
+				if let (Some(keymap), Some(repeat)) = (&*self.keymap, self.repeat) {
+					if keymap.key_repeats(press.key + EVDEV_OFFSET) {
+						self.repeating = Some((press.key, now + repeat.delay));
+					}
+				}
+			}
+			Action::Layer(layer) => self.active_layers.push(*layer),
+		}
+		self.held.insert(scancode, action);
+	}
+
+	fn release(&mut self, action: Action, events: &mut Vec<KeyEvent>) {
+		match action {
+			Action::Key(press) => {
+				if self.repeating.is_some_and(|(key, _)| key == press.key) {
+					self.repeating = None;
+				}
+				push_press(&press, false, events)
+			}
+			Action::Layer(layer) => {
+				if let Some(i) = self.active_layers.iter().rposition(|l| *l == layer) {
+					self.active_layers.remove(i);
+				}
+			}
+		}
+	}
+}
+
+/// Modifiers go down before the key and come up after it.
+fn push_press(press: &KeyPress, pressed: bool, events: &mut Vec<KeyEvent>) {
+	if pressed {
+		events.extend(press.modifiers.iter().map(|modifier| (*modifier, true)));
+		events.push((press.key, true));
+	} else {
+		events.push((press.key, false));
+		events.extend(
+			press
+				.modifiers
+				.iter()
+				.rev()
+				.map(|modifier| (*modifier, false)),
+		);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::keymap::tests::us_keymap;
+	use input_event_codes::{
+		KEY_A, KEY_B, KEY_CAPSLOCK, KEY_ESC, KEY_H, KEY_LEFT, KEY_LEFTCTRL, KEY_SPACE,
+	};
+
+	const TAP_TIMEOUT: Duration = Duration::from_millis(200);
+
+	fn remapper(rules: &str, repeat: Option<KeyRepeat>) -> Remapper {
+		let rules: RemapRules = toml::from_str(rules).unwrap();
+		Remapper::new(&us_keymap(), &rules, TAP_TIMEOUT, repeat)
+	}
+
+	const CAPS_TAP_HOLD: &str = r#"keys.Caps_Lock = { tap = "Escape", hold = "Control_L" }"#;
+
+	#[test]
+	fn remaps_keys_and_passes_the_rest_through() {
+		let mut remapper = remapper(r#"keys.a = "b""#, None);
+		let now = Instant::now();
+		assert_eq!(remapper.key(KEY_A!(), true, now), [(KEY_B!(), true)]);
+		assert_eq!(remapper.key(KEY_A!(), false, now), [(KEY_B!(), false)]);
+		assert_eq!(remapper.key(KEY_H!(), true, now), [(KEY_H!(), true)]);
+		assert_eq!(remapper.key(KEY_H!(), false, now), [(KEY_H!(), false)]);
+	}
+
+	#[test]
+	fn quick_release_taps() {
+		let mut remapper = remapper(CAPS_TAP_HOLD, None);
+		let now = Instant::now();
+		assert_eq!(remapper.key(KEY_CAPSLOCK!(), true, now), []);
+		assert_eq!(remapper.tick(now + TAP_TIMEOUT / 2), []);
+		assert_eq!(
+			remapper.key(KEY_CAPSLOCK!(), false, now + TAP_TIMEOUT / 2),
+			[(KEY_ESC!(), true), (KEY_ESC!(), false)]
+		);
+	}
+
+	#[test]
+	fn holding_past_the_timeout_holds() {
+		let mut remapper = remapper(CAPS_TAP_HOLD, None);
+		let now = Instant::now();
+		remapper.key(KEY_CAPSLOCK!(), true, now);
+		assert_eq!(remapper.tick(now + TAP_TIMEOUT), [(KEY_LEFTCTRL!(), true)]);
+		assert_eq!(
+			remapper.key(KEY_CAPSLOCK!(), false, now + TAP_TIMEOUT * 2),
+			[(KEY_LEFTCTRL!(), false)]
+		);
+	}
+
+	#[test]
+	fn another_key_makes_it_a_hold() {
+		let mut remapper = remapper(CAPS_TAP_HOLD, None);
+		let now = Instant::now();
+		remapper.key(KEY_CAPSLOCK!(), true, now);
+		assert_eq!(
+			remapper.key(KEY_A!(), true, now),
+			[(KEY_LEFTCTRL!(), true), (KEY_A!(), true)]
+		);
+		assert_eq!(remapper.key(KEY_A!(), false, now), [(KEY_A!(), false)]);
+		assert_eq!(
+			remapper.key(KEY_CAPSLOCK!(), false, now),
+			[(KEY_LEFTCTRL!(), false)]
+		);
+	}
+
+	#[test]
+	fn layers_apply_while_held() {
+		let mut remapper = remapper(
+			r#"
+			keys.space = { tap = "space", hold = "@nav" }
+			layers.nav.h = "Left"
+			"#,
+			None,
+		);
+		let now = Instant::now();
+		remapper.key(KEY_SPACE!(), true, now);
+		assert_eq!(remapper.key(KEY_H!(), true, now), [(KEY_LEFT!(), true)]);
+		assert_eq!(remapper.key(KEY_H!(), false, now), [(KEY_LEFT!(), false)]);
+		assert_eq!(remapper.key(KEY_SPACE!(), false, now), []);
+		assert_eq!(remapper.key(KEY_H!(), true, now), [(KEY_H!(), true)]);
+	}
+
+	#[test]
+	fn repeats_the_last_key_pressed() {
+		let repeat = KeyRepeat {
+			delay: Duration::from_millis(500),
+			interval: Duration::from_millis(100),
+		};
+		let mut remapper = remapper(r#"keys.a = "b""#, Some(repeat));
+		let now = Instant::now();
+		remapper.key(KEY_A!(), true, now);
+		// the desktop's own repeat is dropped
+		assert_eq!(remapper.key(KEY_A!(), true, now + repeat.delay / 2), []);
+		assert_eq!(remapper.tick(now + repeat.delay / 2), []);
+		assert_eq!(remapper.tick(now + repeat.delay), [(KEY_B!(), true)]);
+		assert_eq!(remapper.tick(now + repeat.delay), []);
+		let later = now + repeat.delay + repeat.interval;
+		assert_eq!(remapper.tick(later), [(KEY_B!(), true)]);
+		remapper.key(KEY_A!(), false, later);
+		assert_eq!(remapper.tick(later + repeat.interval), []);
+	}
+
+	#[test]
+	fn release_all_lets_go_of_everything() {
+		let mut remapper = remapper(CAPS_TAP_HOLD, None);
+		let now = Instant::now();
+		remapper.key(KEY_A!(), true, now);
+		remapper.key(KEY_CAPSLOCK!(), true, now);
+		assert_eq!(remapper.release_all(), [(KEY_A!(), false)]);
+		assert_eq!(remapper.key(KEY_CAPSLOCK!(), false, now), []);
+	}
+}
@@ -0,0 +1,248 @@
+//! Replayable input scripts, stored as one JSON object per line:
+//!
+//! ```text
+//! {"type": "key", "key": 30, "pressed": true}
+//! {"delay": 0.05}
+//! {"type": "key", "key": 30, "pressed": false}
+//! {"type": "motion", "delta": [10.0, 0.0]}
+//! ```
+//!
+//! Blank lines and lines starting with `#` are ignored.
+
+use crate::{input::InputEvent, keyboard::Keyboard, mouse::Mouse};
+use color_eyre::eyre::{ensure, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use std::{
+	fs,
+	path::Path,
+	time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum ScriptStep {
+	Input(InputEvent),
+	/// Wait this many seconds before the next step.
+	Delay {
+		delay: f64,
+	},
+}
+
+impl ScriptStep {
+	/// Check that a delay is a length of time that can be waited for.
+	pub fn validate(&self) -> Result<()> {
+		if let ScriptStep::Delay { delay } = self {
+			ensure!(
+				Duration::try_from_secs_f64(*delay).is_ok(),
+				"A delay must be a positive number of seconds, got {delay}"
+			);
+		}
+		Ok(())
+	}
+}
+
+pub fn parse(script: &str) -> Result<Vec<ScriptStep>> {
+	script
+		.lines()
+		.enumerate()
+		.filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+		.map(|(i, line)| {
+			let step: ScriptStep = serde_json::from_str(line)
+				.wrap_err_with(|| format!("Invalid step on line {}", i + 1))?;
+			step.validate()
+				.wrap_err_with(|| format!("Invalid step on line {}", i + 1))?;
+			Ok(step)
+		})
+		.collect()
+}
+
+pub fn load(path: &Path) -> Result<Vec<ScriptStep>> {
+	let script =
+		fs::read_to_string(path).wrap_err_with(|| format!("Couldn't read {}", path.display()))?;
+	parse(&script).wrap_err_with(|| format!("Invalid script {}", path.display()))
+}
+
+/// Send every step to the emitters in order, sleeping through the delays.
+pub async fn play(steps: &[ScriptStep], keyboard: Option<&Keyboard>, mouse: Option<&Mouse>) {
+	for step in steps {
+		match step {
+			ScriptStep::Input(event) => event.send(keyboard, mouse),
+			ScriptStep::Delay { delay } => {
+				tokio::time::sleep(Duration::from_secs_f64(delay.max(0.0))).await
+			}
+		}
+	}
+}
+
+/// Collects input as script steps, with delays matching the time between events.
+pub struct Recorder {
+	steps: Vec<ScriptStep>,
+	last: Instant,
+}
+impl Recorder {
+	pub fn new() -> Self {
+		Recorder {
+			steps: Vec::new(),
+			last: Instant::now(),
+		}
+	}
+
+	pub fn record(&mut self, event: InputEvent) {
+		let now = Instant::now();
+		let delay = (now - self.last).as_secs_f64();
+		// millisecond precision is plenty and keeps saved scripts readable
+		let delay = (delay * 1000.0).round() / 1000.0;
+		if !self.steps.is_empty() && delay > 0.0 {
+			self.steps.push(ScriptStep::Delay { delay });
+		}
+		self.steps.push(ScriptStep::Input(event));
+		self.last = now;
+	}
+
+	/// The recorded steps, minus any keys and buttons that went down or came up outside the
+	/// recording so playing it back never leaves anything stuck down.
+	pub fn finish(self) -> Vec<ScriptStep> {
+		let press = |step: &ScriptStep| match step {
+			ScriptStep::Input(InputEvent::Key { key, pressed }) => Some((true, *key, *pressed)),
+			ScriptStep::Input(InputEvent::Button { button, pressed }) => {
+				Some((false, *button, *pressed))
+			}
+			_ => None,
+		};
+		let mut down = Vec::new();
+		let mut balanced = Vec::new();
+		for (i, step) in self.steps.iter().enumerate() {
+			let Some((is_key, code, pressed)) = press(step) else {
+				continue;
+			};
+			if pressed {
+				down.push((is_key, code, i));
+			} else if let Some(j) = down
+				.iter()
+				.rposition(|(k, c, _)| (*k, *c) == (is_key, code))
+			{
+				balanced.push(down.remove(j).2);
+				balanced.push(i);
+			}
+		}
+
+		let mut steps: Vec<ScriptStep> = self
+			.steps
+			.into_iter()
+			.enumerate()
+			.filter(|(i, step)| press(step).is_none() || balanced.contains(i))
+			.map(|(_, step)| step)
+			.collect();
+		while matches!(steps.last(), Some(ScriptStep::Delay { .. })) {
+			steps.pop();
+		}
+		steps
+	}
+}
+impl Default for Recorder {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parses_steps_skipping_comments() {
+		let steps = parse(
+			"# a comment\n{\"type\": \"key\", \"key\": 30, \"pressed\": true}\n\n{\"delay\": 0.05}\n",
+		)
+		.unwrap();
+		assert_eq!(
+			steps,
+			[
+				ScriptStep::Input(InputEvent::Key {
+					key: 30,
+					pressed: true
+				}),
+				ScriptStep::Delay { delay: 0.05 },
+			]
+		);
+	}
+
+	fn key(key: u32, pressed: bool) -> ScriptStep {
+		ScriptStep::Input(InputEvent::Key { key, pressed })
+	}
+	fn button(button: u32, pressed: bool) -> ScriptStep {
+		ScriptStep::Input(InputEvent::Button { button, pressed })
+	}
+	const DELAY: ScriptStep = ScriptStep::Delay { delay: 0.1 };
+
+	fn finish(steps: Vec<ScriptStep>) -> Vec<ScriptStep> {
+		Recorder {
+			steps,
+			last: Instant::now(),
+		}
+		.finish()
+	}
+
+	#[test]
+	fn drops_presses_from_outside_the_recording() {
+		let motion = ScriptStep::Input(InputEvent::Motion { delta: [1.0, 0.0] });
+		let steps = finish(vec![
+			// released the hotkey that started the recording
+			key(29, false),
+			DELAY,
+			key(30, true),
+			motion.clone(),
+			key(30, false),
+			DELAY,
+			// pressed the hotkey that stops it
+			key(29, true),
+			DELAY,
+		]);
+		assert_eq!(steps, [DELAY, key(30, true), motion, key(30, false)]);
+	}
+
+	#[test]
+	fn keys_and_buttons_balance_separately() {
+		let steps = finish(vec![
+			key(272, true),
+			button(272, true),
+			key(272, false),
+			// the release goes with the last press
+			button(272, true),
+			button(272, false),
+		]);
+		assert_eq!(
+			steps,
+			[
+				key(272, true),
+				key(272, false),
+				button(272, true),
+				button(272, false)
+			]
+		);
+	}
+
+	#[test]
+	fn records_delays_between_events() {
+		let mut recorder = Recorder::new();
+		recorder.record(InputEvent::Key {
+			key: 30,
+			pressed: true,
+		});
+		recorder.last -= Duration::from_millis(250);
+		recorder.record(InputEvent::Key {
+			key: 30,
+			pressed: false,
+		});
+		let steps = recorder.finish();
+		assert_eq!(steps.len(), 3);
+		assert!(matches!(steps[1], ScriptStep::Delay { delay } if delay >= 0.25));
+	}
+
+	#[test]
+	fn rejects_delays_that_cant_be_waited_for() {
+		for delay in ["-1.0", "1e30"] {
+			assert!(parse(&format!("{{\"delay\": {delay}}}")).is_err());
+		}
+	}
+}